@@ -0,0 +1,199 @@
+//! Offline checker for append-only files, like `redis-check-aof`
+//!
+//! ```text
+//! aof-check [--fix] <file | manifest | directory>
+//! ```
+//!
+//! Checks a single AOF file, or every file listed by a manifest, given
+//! directly or as the AOF directory holding it. RDB preambles are validated
+//! with [`RDBParser`], and commands are read with the same RESP parser
+//! connections use. For each file, reports how many commands it holds, the
+//! offset up to which it is valid, and the commands this server cannot
+//! parse.
+//!
+//! With `--fix`, a last file that is truncated or corrupted is truncated
+//! after its last valid command, which is what is needed after an unclean
+//! shutdown. Only the last file of a manifest can be fixed this way, as
+//! cutting an earlier one would drop the commands of the files after it.
+//!
+//! Exits with 0 if every file is valid, or was fixed, and 1 otherwise.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process;
+use redis_starter_rust::parser::{RDBParser, RESPOutput, RdbEvent};
+use redis_starter_rust::persistence::aof::{AofReadError, CommandReader};
+use redis_starter_rust::persistence::manifest::Manifest;
+use redis_starter_rust::Command;
+
+const USAGE: &str = "Usage: aof-check [--fix] <file | manifest | directory>";
+
+fn main() {
+    let mut fix = false;
+    let mut path = None;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--fix" => fix = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if arg.starts_with("--") || path.is_some() => {
+                eprintln!("Unexpected argument {}\n{}", arg, USAGE);
+                process::exit(2);
+            }
+            _ => path = Some(PathBuf::from(arg)),
+        }
+    }
+    let path = path.unwrap_or_else(|| {
+        eprintln!("No AOF given\n{}", USAGE);
+        process::exit(2);
+    });
+
+    let result = files(&path).and_then(|files| {
+        let mut valid = true;
+        for (index, file) in files.iter().enumerate() {
+            valid &= check(file, index + 1 == files.len(), fix)?;
+        }
+        Ok(valid)
+    });
+    match result {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1);
+        }
+    }
+}
+
+/// The files to check, in loading order: the file itself, or the base and
+/// incremental files of a manifest
+fn files(path: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let manifest = if path.is_dir() {
+        let manifests = fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter(|file| file.extension().is_some_and(|extension| extension == "manifest"))
+            .collect::<Vec<_>>();
+        match manifests.as_slice() {
+            [manifest] => manifest.clone(),
+            [] => return Err(format!("No manifest found in {}", path.display()).into()),
+            _ => return Err(format!("More than one manifest found in {}", path.display()).into()),
+        }
+    } else if path.extension().is_some_and(|extension| extension == "manifest") {
+        path.to_path_buf()
+    } else {
+        return Ok(vec![path.to_path_buf()]);
+    };
+
+    println!("Checking the multi part AOF of manifest {}", manifest.display());
+    let text = fs::read_to_string(&manifest)
+        .map_err(|e| format!("Cannot read the manifest {}: {}", manifest.display(), e))?;
+    let parsed = Manifest::parse(&text)
+        .map_err(|e| format!("Invalid manifest {}: {}", manifest.display(), e))?;
+    let dir = manifest.parent().unwrap_or(Path::new("."));
+    Ok(parsed.base.iter()
+        .chain(&parsed.incrs)
+        .map(|info| dir.join(&info.name))
+        .collect())
+}
+
+/// Checks one file, and truncates it after its last valid command when
+/// `fix` is set and it is the `last` file. Returns whether the file is
+/// valid, or was fixed.
+fn check(path: &Path, last: bool, fix: bool) -> Result<bool, Box<dyn std::error::Error>> {
+    let file = File::open(path).map_err(|e| format!("Cannot open {}: {}", path.display(), e))?;
+    let size = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    let mut offset = 0;
+    if reader.fill_buf()?.starts_with(b"REDIS") {
+        let mut parser = RDBParser::new(reader);
+        parser.set_input_len(size);
+        let mut keys = 0;
+        for event in parser.by_ref() {
+            match event {
+                Ok(RdbEvent::Entry(_)) => keys += 1,
+                Ok(_) => {}
+                Err(e) => {
+                    println!("RDB preamble of {} is not valid: {}", path.display(), e);
+                    println!("An RDB preamble cannot be fixed, check it with rdb-check");
+                    return Ok(false);
+                }
+            }
+        }
+        offset = parser.offset();
+        println!("RDB preamble of {} is OK: {} keys, up to offset {}", path.display(), keys, offset);
+        reader = parser.into_inner();
+    }
+
+    let mut commands = CommandReader::new(reader, offset);
+    let mut count = 0;
+    let mut unparseable = Vec::new();
+    let problem = loop {
+        match commands.next_command() {
+            Ok(Some((offset, output))) => {
+                count += 1;
+                let name = command_name(&output);
+                if let Err(e) = Command::from_resp(output) {
+                    unparseable.push((offset, name, e));
+                }
+            }
+            Ok(None) => break None,
+            Err(AofReadError::Io(e)) => return Err(format!("Cannot read {}: {}", path.display(), e).into()),
+            Err(e) => break Some(e),
+        }
+    };
+    let ok_up_to = commands.offset();
+
+    println!(
+        "AOF analyzed: filename={}, size={}, ok_up_to={}, commands={}, diff={}",
+        path.display(), size, ok_up_to, count, size - ok_up_to,
+    );
+    for (offset, name, e) in &unparseable {
+        println!("Command {} at offset {} cannot be parsed: {}", name, offset, e);
+    }
+    if !unparseable.is_empty() {
+        println!("{} commands of {} cannot be replayed by this server", unparseable.len(), path.display());
+    }
+
+    let problem = match problem {
+        Some(problem) => problem,
+        None if unparseable.is_empty() => {
+            println!("AOF {} is valid", path.display());
+            return Ok(true);
+        }
+        None => return Ok(false),
+    };
+    println!("{}", problem);
+    if !fix {
+        println!("AOF {} is not valid. Use the --fix option to try fixing it.", path.display());
+        return Ok(false);
+    }
+    if !last {
+        println!("Only the last file of an AOF can be fixed, {} is not.", path.display());
+        return Ok(false);
+    }
+
+    OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_len(ok_up_to))
+        .map_err(|e| format!("Failed to truncate {}: {}", path.display(), e))?;
+    println!("Successfully truncated AOF {} from {} to {} bytes", path.display(), size, ok_up_to);
+    Ok(unparseable.is_empty())
+}
+
+/// Name of a command, for reports
+fn command_name(output: &RESPOutput) -> String {
+    match output {
+        RESPOutput::Array(elements) => match elements.first() {
+            Some(RESPOutput::BulkString(name)) => format!("{:?}", name),
+            _ => "(not a command)".to_string(),
+        },
+        _ => "(not a command)".to_string(),
+    }
+}
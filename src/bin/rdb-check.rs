@@ -0,0 +1,373 @@
+//! Offline checker for RDB files, like `redis-check-rdb`
+//!
+//! ```text
+//! rdb-check [--json] [--top <n>] [--strict-checksum] <file>
+//! ```
+//!
+//! Reads the whole file with [`RDBParser`], which validates the header,
+//! every opcode and value, and the checksum, then reports the keys of each
+//! database by type, their expiries and the largest keys. With `--json`,
+//! every entry is also written to stdout as one JSON object per line, and
+//! the report goes to stderr so the entries can be piped elsewhere.
+//!
+//! Exits with 0 if the file is valid and 1 if it is not.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::process;
+use std::time::SystemTime;
+use redis_starter_rust::parser::stream::Stream;
+use redis_starter_rust::parser::{RDBEntry, RDBParser, RDBValue, RdbEvent};
+
+/// Number of largest keys reported by default
+const DEFAULT_TOP: usize = 10;
+
+const USAGE: &str = "Usage: rdb-check [--json] [--top <n>] [--strict-checksum] <file>";
+
+struct Options {
+    path: String,
+    /// Write every entry to stdout as JSON Lines
+    json: bool,
+    /// Number of largest keys to report
+    top: usize,
+    /// Reject a zero checksum trailer, written when `rdbchecksum` is off
+    strict_checksum: bool,
+}
+
+/// Counts of the keys of one database
+#[derive(Default)]
+struct DbStats {
+    keys: u64,
+    expires: u64,
+    already_expired: u64,
+    /// Keys by type name
+    types: BTreeMap<&'static str, u64>,
+}
+
+/// A key, as listed among the largest ones
+struct KeySize {
+    db: usize,
+    key: Vec<u8>,
+    type_name: &'static str,
+    elements: usize,
+    bytes: usize,
+}
+
+fn main() {
+    let options = parse_args().unwrap_or_else(|error| {
+        eprintln!("{}\n{}", error, USAGE);
+        process::exit(2);
+    });
+    if let Err(error) = check(&options) {
+        eprintln!("--- RDB ERROR DETECTED ---");
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options { path: String::new(), json: false, top: DEFAULT_TOP, strict_checksum: false };
+    let mut path = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => options.json = true,
+            "--strict-checksum" => options.strict_checksum = true,
+            "--top" => {
+                options.top = args.next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or("--top needs a number of keys")?;
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err("Only one file can be checked at a time".to_string()),
+        }
+    }
+    options.path = path.ok_or("No RDB file given")?;
+    Ok(options)
+}
+
+/// Reads the file, writing the report to stdout, or to stderr with `--json`
+fn check(options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+    let file = File::open(&options.path)
+        .map_err(|e| format!("Cannot open {}: {}", options.path, e))?;
+    let size = file.metadata()?.len();
+    let mut parser = RDBParser::new(BufReader::new(file));
+    parser.set_skip_zero_checksum(!options.strict_checksum);
+    parser.set_input_len(size);
+
+    let stdout = io::stdout();
+    let mut entries = BufWriter::new(stdout.lock());
+    let mut report: Box<dyn Write> = if options.json { Box::new(io::stderr()) } else { Box::new(io::stdout()) };
+
+    let now = SystemTime::now();
+    let mut dbs: BTreeMap<usize, DbStats> = BTreeMap::new();
+    let mut largest: Vec<KeySize> = Vec::new();
+    let mut functions = 0;
+    let mut module_aux = 0;
+
+    writeln!(report, "[offset 0] Checking RDB file {}", options.path)?;
+    loop {
+        let offset = parser.offset();
+        let event = match parser.next_event()? {
+            Some(event) => event,
+            None => break,
+        };
+        match event {
+            RdbEvent::Header { version } => {
+                writeln!(report, "[offset {}] RDB version {}", offset, version)?;
+            }
+            RdbEvent::Aux { key, value } => {
+                writeln!(report, "[offset {}] AUX FIELD {} = '{}'", offset, lossy(&key), lossy(&value))?;
+            }
+            RdbEvent::SelectDb { db } => {
+                writeln!(report, "[offset {}] Selecting DB ID {}", offset, db)?;
+            }
+            RdbEvent::Function { .. } => functions += 1,
+            RdbEvent::ModuleAux { .. } => module_aux += 1,
+            RdbEvent::Eof { checksum } => {
+                let checksum = match checksum {
+                    Some(0) if !options.strict_checksum => "checksum disabled when saved, not verified".to_string(),
+                    Some(checksum) => format!("checksum {:016x} OK", checksum),
+                    None => "no checksum in this version".to_string(),
+                };
+                writeln!(report, "[offset {}] EOF, {}", offset, checksum)?;
+            }
+            RdbEvent::Entry(entry) => {
+                let stats = dbs.entry(entry.db).or_default();
+                let type_name = type_name(&entry.value);
+                stats.keys += 1;
+                *stats.types.entry(type_name).or_default() += 1;
+                if let Some(expiry) = entry.expiry {
+                    stats.expires += 1;
+                    if expiry <= now {
+                        stats.already_expired += 1;
+                    }
+                }
+
+                let (elements, bytes) = value_size(&entry.value);
+                largest.push(KeySize { db: entry.db, key: entry.key.clone(), type_name, elements, bytes });
+                if largest.len() > options.top * 2 {
+                    keep_largest(&mut largest, options.top);
+                }
+
+                if options.json {
+                    writeln!(entries, "{}", entry_json(&entry))?;
+                }
+            }
+            RdbEvent::ResizeDb { .. } | RdbEvent::SlotInfo { .. } => {}
+        }
+    }
+    entries.flush()?;
+
+    let total = |field: fn(&DbStats) -> u64| dbs.values().map(field).sum::<u64>();
+    writeln!(report, "\\o/ RDB looks OK! \\o/")?;
+    writeln!(report, "[info] {} keys read", total(|db| db.keys))?;
+    writeln!(report, "[info] {} expires", total(|db| db.expires))?;
+    writeln!(report, "[info] {} already expired", total(|db| db.already_expired))?;
+    if functions > 0 {
+        writeln!(report, "[info] {} function libraries", functions)?;
+    }
+    if module_aux > 0 {
+        writeln!(report, "[info] {} module aux fields", module_aux)?;
+    }
+
+    for (db, stats) in &dbs {
+        writeln!(
+            report,
+            "db{}: keys={} expires={} already_expired={}",
+            db, stats.keys, stats.expires, stats.already_expired,
+        )?;
+        for (type_name, count) in &stats.types {
+            writeln!(report, "  {}: {}", type_name, count)?;
+        }
+    }
+
+    keep_largest(&mut largest, options.top);
+    if !largest.is_empty() {
+        writeln!(report, "Largest keys:")?;
+        for key in &largest {
+            writeln!(
+                report,
+                "  db{} {} {}: {} bytes, {} elements",
+                key.db, key.type_name, json_string(&key.key), key.bytes, key.elements,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Keeps the `top` largest keys, largest first
+fn keep_largest(keys: &mut Vec<KeySize>, top: usize) {
+    keys.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(b.elements.cmp(&a.elements)));
+    keys.truncate(top);
+}
+
+fn type_name(value: &RDBValue) -> &'static str {
+    match value {
+        RDBValue::String(_) => "string",
+        RDBValue::List(_) => "list",
+        RDBValue::Set(_) => "set",
+        RDBValue::SortedSet(_) => "zset",
+        RDBValue::Hash(_) => "hash",
+        RDBValue::Stream(_) => "stream",
+        RDBValue::Module(_) => "module",
+    }
+}
+
+/// Number of elements of a value, and the bytes of data it holds. Scores
+/// and stream IDs count as 8 and 16 bytes, what they take in memory.
+fn value_size(value: &RDBValue) -> (usize, usize) {
+    let sum = |elements: &[Vec<u8>]| elements.iter().map(Vec::len).sum::<usize>();
+    match value {
+        RDBValue::String(s) => (1, s.len()),
+        RDBValue::List(elements) | RDBValue::Set(elements) => (elements.len(), sum(elements)),
+        RDBValue::SortedSet(members) => {
+            (members.len(), members.iter().map(|(member, _)| member.len() + 8).sum())
+        }
+        RDBValue::Hash(fields) => {
+            (fields.len(), fields.iter().map(|(field, value)| field.len() + value.len()).sum())
+        }
+        RDBValue::Stream(stream) => {
+            let bytes = stream.entries.iter()
+                .map(|entry| 16 + entry.fields.iter().map(|(field, value)| field.len() + value.len()).sum::<usize>())
+                .sum();
+            (stream.entries.len(), bytes)
+        }
+        RDBValue::Module(_) => (0, 0),
+    }
+}
+
+fn lossy(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).to_string()
+}
+
+/// Formats an entry as a JSON object:
+/// `{"db":0,"key":"k","type":"string","expires_at":null,"value":"v"}`, plus
+/// `idle` and `freq` when the file has them. `expires_at` is in unix
+/// milliseconds.
+fn entry_json(entry: &RDBEntry) -> String {
+    let expires_at = entry.expiry.map_or("null".to_string(), |expiry| {
+        let millis = match expiry.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(since) => since.as_millis() as i128,
+            Err(before) => -(before.duration().as_millis() as i128),
+        };
+        millis.to_string()
+    });
+    let mut json = format!(
+        "{{\"db\":{},\"key\":{},\"type\":\"{}\",\"expires_at\":{}",
+        entry.db,
+        json_string(&entry.key),
+        type_name(&entry.value),
+        expires_at,
+    );
+    if let Some(idle) = entry.idle {
+        json.push_str(&format!(",\"idle\":{}", idle));
+    }
+    if let Some(freq) = entry.freq {
+        json.push_str(&format!(",\"freq\":{}", freq));
+    }
+    json.push_str(&format!(",\"value\":{}}}", value_json(&entry.value)));
+    json
+}
+
+/// Strings become JSON strings, lists and sets arrays, hashes objects and
+/// sorted sets arrays of `{"member","score"}` objects in file order
+fn value_json(value: &RDBValue) -> String {
+    match value {
+        RDBValue::String(s) => json_string(s),
+        RDBValue::List(elements) | RDBValue::Set(elements) => {
+            json_array(elements.iter().map(|element| json_string(element)))
+        }
+        RDBValue::SortedSet(members) => json_array(members.iter().map(|(member, score)| {
+            format!("{{\"member\":{},\"score\":{}}}", json_string(member), json_number(*score))
+        })),
+        RDBValue::Hash(fields) => json_object(fields),
+        RDBValue::Stream(stream) => stream_json(stream),
+        RDBValue::Module(name) => format!("{{\"module\":{}}}", json_string(name.as_bytes())),
+    }
+}
+
+fn stream_json(stream: &Stream) -> String {
+    let entries = json_array(stream.entries.iter().map(|entry| {
+        format!("{{\"id\":\"{}\",\"fields\":{}}}", entry.id, json_object(&entry.fields))
+    }));
+    let groups = json_array(stream.groups.iter().map(|group| {
+        let pending = json_array(group.pending.iter().map(|pending| {
+            format!(
+                "{{\"id\":\"{}\",\"delivery_time\":{},\"delivery_count\":{}}}",
+                pending.id, pending.delivery_time, pending.delivery_count,
+            )
+        }));
+        let consumers = json_array(group.consumers.iter().map(|consumer| {
+            format!(
+                "{{\"name\":{},\"seen_time\":{},\"active_time\":{},\"pending\":{}}}",
+                json_string(&consumer.name),
+                consumer.seen_time,
+                consumer.active_time,
+                json_array(consumer.pending.iter().map(|id| format!("\"{}\"", id))),
+            )
+        }));
+        format!(
+            "{{\"name\":{},\"last_id\":\"{}\",\"entries_read\":{},\"pending\":{},\"consumers\":{}}}",
+            json_string(&group.name),
+            group.last_id,
+            group.entries_read.map_or("null".to_string(), |read| read.to_string()),
+            pending,
+            consumers,
+        )
+    }));
+    format!(
+        "{{\"length\":{},\"first_id\":\"{}\",\"last_id\":\"{}\",\"max_deleted_id\":\"{}\",\"entries_added\":{},\"entries\":{},\"groups\":{}}}",
+        stream.length, stream.first_id, stream.last_id, stream.max_deleted_id, stream.entries_added, entries, groups,
+    )
+}
+
+fn json_array(items: impl Iterator<Item = String>) -> String {
+    format!("[{}]", items.collect::<Vec<_>>().join(","))
+}
+
+fn json_object(pairs: &[(Vec<u8>, Vec<u8>)]) -> String {
+    let fields = pairs.iter()
+        .map(|(field, value)| format!("{}:{}", json_string(field), json_string(value)))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", fields.join(","))
+}
+
+/// JSON has no infinities, so those are written as strings the way Redis
+/// prints them
+fn json_number(value: f64) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else if value.is_nan() {
+        "\"nan\"".to_string()
+    } else if value > 0.0 {
+        "\"inf\"".to_string()
+    } else {
+        "\"-inf\"".to_string()
+    }
+}
+
+/// Encodes bytes as a JSON string. Bytes that are not valid UTF-8 are
+/// replaced with U+FFFD.
+fn json_string(bytes: &[u8]) -> String {
+    let mut json = String::from("\"");
+    for c in String::from_utf8_lossy(bytes).chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
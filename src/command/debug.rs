@@ -0,0 +1,218 @@
+//! The `DEBUG` command, used by tests and diagnostics tooling
+//!
+//! DEBUG can block clients and replace the whole dataset, so it is refused
+//! unless `enable-debug-command` allows it, as in Redis.
+
+use std::collections::HashMap;
+use std::time::Duration;
+use crate::error::{RedisError, Result};
+use crate::parser::RESPOutput;
+use crate::persistence::dump;
+use crate::persistence::rdb::{self, SaveOptions};
+use crate::server::Server;
+use crate::store::datatype::DataType;
+use crate::store::redis::{SetCondition, SetOptions, Store};
+use super::{bytes_arg, integer_arg, string_arg, Session};
+
+const HELP: &[&str] = &[
+    "DEBUG <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+    "CHANGE-REPL-ID",
+    "    Change the replication IDs of the instance.",
+    "JMAP",
+    "    Show a histogram of the keys by type and encoding, with their serialized size.",
+    "OBJECT <key>",
+    "    Show low level info about the key and its value.",
+    "POPULATE <count> [<prefix>] [<size>]",
+    "    Create <count> string keys named key:<num>, or <prefix>:<num>. Values are",
+    "    value:<num>, padded with zero bytes or cut to <size> bytes when given.",
+    "RELOAD [NOSAVE] [NOFLUSH]",
+    "    Save the RDB file on disk, empty the dataset and load the file back.",
+    "    NOSAVE skips the save, NOFLUSH loads the file over the current keys.",
+    "SET-ACTIVE-EXPIRE <0|1>",
+    "    Turn the active expire cycle off or on.",
+    "SLEEP <seconds>",
+    "    Wait <seconds> before replying, decimals are allowed.",
+    "HELP",
+    "    Print this help.",
+];
+
+/// A subcommand of `DEBUG`
+#[derive(Debug)]
+pub enum DebugCommand {
+    Help,
+    /// Saves the RDB file unless `save` is off, empties every database
+    /// unless `flush` is off, then loads the file back
+    Reload { save: bool, flush: bool },
+    Object(Vec<u8>),
+    Sleep(Duration),
+    SetActiveExpire(bool),
+    Populate { count: u64, prefix: String, size: usize },
+    /// A histogram of the keys, in the style of `jmap -histo`
+    Jmap,
+    ChangeReplId,
+}
+
+impl DebugCommand {
+    pub fn parse(args: &[RESPOutput]) -> Result<Self> {
+        let subcommand = string_arg(args, 0)?.to_uppercase();
+        let unknown = || RedisError::Message(format!(
+            "Unknown subcommand or wrong number of arguments for '{}'. Try DEBUG HELP.",
+            subcommand,
+        ));
+        let arity = |expected: usize| if args.len() == expected { Ok(()) } else { Err(unknown()) };
+
+        match subcommand.as_str() {
+            "HELP" => arity(1).map(|_| DebugCommand::Help),
+            "RELOAD" => {
+                let (mut save, mut flush) = (true, true);
+                for i in 1..args.len() {
+                    match string_arg(args, i)?.to_uppercase().as_str() {
+                        "NOSAVE" => save = false,
+                        "NOFLUSH" => flush = false,
+                        // Loaded keys always replace existing ones
+                        "MERGE" => {}
+                        _ => return Err(RedisError::Syntax),
+                    }
+                }
+                Ok(DebugCommand::Reload { save, flush })
+            }
+            "OBJECT" => {
+                arity(2)?;
+                Ok(DebugCommand::Object(bytes_arg(args, 1)?))
+            }
+            "SLEEP" => {
+                arity(2)?;
+                let seconds = string_arg(args, 1)?
+                    .parse::<f64>()
+                    .ok()
+                    .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                    .ok_or_else(|| RedisError::Message("value is not a valid float".to_string()))?;
+                Ok(DebugCommand::Sleep(seconds))
+            }
+            "SET-ACTIVE-EXPIRE" => {
+                arity(2)?;
+                Ok(DebugCommand::SetActiveExpire(integer_arg(args, 1)? != 0))
+            }
+            "POPULATE" => {
+                if !(2..=4).contains(&args.len()) {
+                    return Err(unknown());
+                }
+                let positive = |index: usize| {
+                    u64::try_from(integer_arg(args, index)?)
+                        .map_err(|_| RedisError::Message("value is out of range, must be positive".to_string()))
+                };
+                let count = positive(1)?;
+                let prefix = if args.len() > 2 { string_arg(args, 2)? } else { "key".to_string() };
+                let size = if args.len() > 3 { positive(3)? as usize } else { 0 };
+                Ok(DebugCommand::Populate { count, prefix, size })
+            }
+            "JMAP" => arity(1).map(|_| DebugCommand::Jmap),
+            "CHANGE-REPL-ID" => arity(1).map(|_| DebugCommand::ChangeReplId),
+            _ => Err(unknown()),
+        }
+    }
+
+    pub async fn execute(&self, store: &Store, session: &mut Session) -> Result<Vec<u8>> {
+        let allowed = match store.get_config("enable-debug-command").await.as_deref() {
+            Some("yes") => true,
+            Some("local") => session.local,
+            _ => false,
+        };
+        if !allowed {
+            return Err(RedisError::Message(
+                "DEBUG command not allowed. If the enable-debug-command option is set to \"local\", \
+                 you can run it from a local connection, otherwise you need to set this option in the \
+                 configuration file, and then restart the server.".to_string(),
+            ));
+        }
+
+        let db = store.db(session.db);
+        match self {
+            DebugCommand::Help => {
+                let lines = HELP.iter().map(|line| RESPOutput::SimpleString(line.to_string())).collect();
+                Ok(RESPOutput::Array(lines).encode())
+            }
+            DebugCommand::Reload { save, flush } => {
+                let options = SaveOptions::from_config(store).await;
+                if *save {
+                    rdb::save(store).await?;
+                }
+                if *flush {
+                    store.flush_all(false).await;
+                }
+                // The cause is already logged by the loader
+                let loaded = Server::init_db(store, &options.dir.join(&options.filename)).await.is_ok();
+                if !loaded {
+                    return Err(RedisError::Message("Error trying to load the RDB dump, check server logs.".to_string()));
+                }
+                store.save_state().mark_loaded(store.changes());
+                Ok(b"+OK\r\n".to_vec())
+            }
+            DebugCommand::Object(key) => {
+                let (value, _) = db.get_with_expiry(key).await?
+                    .ok_or_else(|| RedisError::Message("no such key".to_string()))?;
+                // Values are not shared, reference-counted objects here, and
+                // no LRU clock is kept. Those fields are placeholders that
+                // keep the layout tools parse.
+                Ok(RESPOutput::SimpleString(format!(
+                    "Value at:0x0 refcount:1 encoding:{} serializedlength:{} lru:0 lru_seconds_idle:0",
+                    value.encoding(),
+                    dump::serialized_len(&value),
+                )).encode())
+            }
+            DebugCommand::Sleep(duration) => {
+                // Only this client waits, the others are still served
+                tokio::time::sleep(*duration).await;
+                Ok(b"+OK\r\n".to_vec())
+            }
+            DebugCommand::SetActiveExpire(enabled) => {
+                store.set_active_expire(*enabled);
+                Ok(b"+OK\r\n".to_vec())
+            }
+            DebugCommand::Populate { count, prefix, size } => {
+                // Keys that already exist are left alone
+                let options = SetOptions { condition: Some(SetCondition::NotExists), ..SetOptions::default() };
+                for i in 0..*count {
+                    let mut value = format!("value:{}", i).into_bytes();
+                    if *size > 0 {
+                        value.resize(*size, 0);
+                    }
+                    let key = format!("{}:{}", prefix, i).into_bytes();
+                    db.set_with(&key, DataType::String(value), &options).await?;
+                }
+                Ok(b"+OK\r\n".to_vec())
+            }
+            DebugCommand::Jmap => Ok(RESPOutput::BulkString(jmap(store).await).encode()),
+            DebugCommand::ChangeReplId => {
+                store.replication().change_id();
+                Ok(b"+OK\r\n".to_vec())
+            }
+        }
+    }
+}
+
+/// Counts the keys of every database by type and encoding, with the
+/// serialized size of the keys and their values, largest first, laid out
+/// like `jmap -histo`
+async fn jmap(store: &Store) -> String {
+    let mut classes = HashMap::<String, (usize, usize)>::new();
+    for entries in store.snapshot().await {
+        for (key, value, _) in entries {
+            let class = classes.entry(format!("{} ({})", value.type_name(), value.encoding())).or_default();
+            class.0 += 1;
+            class.1 += key.len() + dump::serialized_len(&value);
+        }
+    }
+    let mut classes = classes.into_iter().collect::<Vec<_>>();
+    classes.sort_by(|a, b| b.1.1.cmp(&a.1.1).then_with(|| a.0.cmp(&b.0)));
+
+    let mut out = String::from(" num     #instances         #bytes  class name\r\n");
+    out.push_str(&format!("{}\r\n", "-".repeat(50)));
+    for (index, (class, (instances, bytes))) in classes.iter().enumerate() {
+        out.push_str(&format!("{:>4}:{:>14}{:>15}  {}\r\n", index + 1, instances, bytes, class));
+    }
+    let instances = classes.iter().map(|(_, (instances, _))| instances).sum::<usize>();
+    let bytes = classes.iter().map(|(_, (_, bytes))| bytes).sum::<usize>();
+    out.push_str(&format!("Total{:>14}{:>15}\r\n", instances, bytes));
+    out
+}
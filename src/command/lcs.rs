@@ -0,0 +1,149 @@
+//! Longest common subsequence used by the `LCS` command
+//!
+//! This is a port of the dynamic programming approach Redis uses in
+//! `lcsCommand`: build the full LCS length table, then walk it backwards from
+//! the end of both strings to recover the subsequence and the ranges of
+//! contiguous matches.
+
+/// A contiguous run of matching bytes, as inclusive byte ranges in both inputs
+#[derive(Debug, PartialEq)]
+pub struct LcsMatch {
+    pub a: (usize, usize),
+    pub b: (usize, usize),
+    pub len: usize,
+}
+
+/// Result of running the LCS algorithm over two strings
+#[derive(Debug)]
+pub struct Lcs {
+    /// The longest common subsequence itself
+    pub sequence: Vec<u8>,
+    /// Matching ranges, from the end of the strings towards the start
+    pub matches: Vec<LcsMatch>,
+}
+
+impl Lcs {
+    /// Computes the LCS of `a` and `b`.
+    ///
+    /// Matches shorter than `min_match_len` are left out of `matches`, but
+    /// still count towards `sequence`.
+    pub fn compute(a: &[u8], b: &[u8], min_match_len: usize) -> Self {
+        let (alen, blen) = (a.len(), b.len());
+        let width = blen + 1;
+        let mut table = vec![0u32; (alen + 1) * (blen + 1)];
+
+        for i in 1..=alen {
+            for j in 1..=blen {
+                table[i * width + j] = if a[i - 1] == b[j - 1] {
+                    table[(i - 1) * width + j - 1] + 1
+                } else {
+                    table[(i - 1) * width + j].max(table[i * width + j - 1])
+                };
+            }
+        }
+
+        let mut idx = table[alen * width + blen] as usize;
+        let mut sequence = vec![0u8; idx];
+        let mut matches = Vec::new();
+
+        // `current` holds the match being extended backwards, if any
+        let mut current: Option<LcsMatch> = None;
+        let (mut i, mut j) = (alen, blen);
+        while i > 0 && j > 0 {
+            let mut emit = false;
+            if a[i - 1] == b[j - 1] {
+                sequence[idx - 1] = a[i - 1];
+                match current.as_mut() {
+                    None => {
+                        current = Some(LcsMatch { a: (i - 1, i - 1), b: (j - 1, j - 1), len: 0 });
+                    }
+                    Some(m) if m.a.0 == i && m.b.0 == j => {
+                        m.a.0 -= 1;
+                        m.b.0 -= 1;
+                    }
+                    Some(_) => emit = true,
+                }
+                // Emit as soon as we reach the start of either string
+                if let Some(m) = &current {
+                    if m.a.0 == 0 || m.b.0 == 0 {
+                        emit = true;
+                    }
+                }
+                idx -= 1;
+                i -= 1;
+                j -= 1;
+            } else {
+                if table[(i - 1) * width + j] > table[i * width + j - 1] {
+                    i -= 1;
+                } else {
+                    j -= 1;
+                }
+                if current.is_some() {
+                    emit = true;
+                }
+            }
+
+            if emit {
+                if let Some(mut m) = current.take() {
+                    m.len = m.a.1 - m.a.0 + 1;
+                    if min_match_len == 0 || m.len >= min_match_len {
+                        matches.push(m);
+                    }
+                }
+            }
+        }
+
+        Lcs { sequence, matches }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lcs_match(a: (usize, usize), b: (usize, usize)) -> LcsMatch {
+        LcsMatch { a, b, len: a.1 - a.0 + 1 }
+    }
+
+    #[test]
+    fn finds_the_sequence_and_its_ranges() {
+        let lcs = Lcs::compute(b"ohmytext", b"mynewtext", 0);
+        assert_eq!(lcs.sequence, b"mytext");
+        assert_eq!(lcs.matches, [lcs_match((4, 7), (5, 8)), lcs_match((2, 3), (0, 1))]);
+    }
+
+    #[test]
+    fn leaves_out_short_matches() {
+        let lcs = Lcs::compute(b"ohmytext", b"mynewtext", 4);
+        assert_eq!(lcs.sequence, b"mytext");
+        assert_eq!(lcs.matches, [lcs_match((4, 7), (5, 8))]);
+
+        let lcs = Lcs::compute(b"ohmytext", b"mynewtext", 5);
+        assert_eq!(lcs.sequence, b"mytext");
+        assert!(lcs.matches.is_empty());
+    }
+
+    #[test]
+    fn emits_matches_at_the_start_of_either_string() {
+        let lcs = Lcs::compute(b"abc", b"xabc", 0);
+        assert_eq!(lcs.matches, [lcs_match((0, 2), (1, 3))]);
+        let lcs = Lcs::compute(b"xxabc", b"abcx", 0);
+        assert_eq!(lcs.matches, [lcs_match((2, 4), (0, 2))]);
+        let lcs = Lcs::compute(b"a-b", b"ab", 0);
+        assert_eq!(lcs.sequence, b"ab");
+        assert_eq!(lcs.matches, [lcs_match((2, 2), (1, 1)), lcs_match((0, 0), (0, 0))]);
+    }
+
+    #[test]
+    fn handles_empty_and_unrelated_strings() {
+        for (a, b) in [(&b""[..], &b"abc"[..]), (b"abc", b""), (b"", b""), (b"abc", b"xyz")] {
+            let lcs = Lcs::compute(a, b, 0);
+            assert!(lcs.sequence.is_empty());
+            assert!(lcs.matches.is_empty());
+        }
+
+        let lcs = Lcs::compute(b"same", b"same", 0);
+        assert_eq!(lcs.sequence, b"same");
+        assert_eq!(lcs.matches, [lcs_match((0, 3), (0, 3))]);
+    }
+}
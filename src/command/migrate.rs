@@ -0,0 +1,135 @@
+//! Client side of the `MIGRATE` command
+//!
+//! Like Redis, keys are sent to the target instance as `RESTORE` commands
+//! carrying their DUMP payload, pipelined after an optional `AUTH` and a
+//! `SELECT` of the destination database. Each step of the exchange is
+//! bounded by the timeout given to MIGRATE.
+
+use std::time::Duration;
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use crate::error::RedisError;
+use crate::parser::{Parser, RESPOutput};
+
+/// Arguments of the `MIGRATE` command
+#[derive(Debug)]
+pub struct MigrateOptions {
+    pub host: String,
+    pub port: u16,
+    /// The key argument, or the keys given with `KEYS`
+    pub keys: Vec<Vec<u8>>,
+    /// Database to restore the keys in on the target instance
+    pub db: i64,
+    pub timeout: Duration,
+    /// Keep the keys on this instance (`COPY`)
+    pub copy: bool,
+    /// Overwrite existing keys on the target instance (`REPLACE`)
+    pub replace: bool,
+    /// Credentials sent with AUTH (`AUTH password` or `AUTH2 username
+    /// password`)
+    pub auth: Option<(Option<String>, String)>,
+}
+
+/// A key to send: its name, its TTL in milliseconds (0 for none) and its
+/// DUMP payload
+pub type Restore = (Vec<u8>, i64, Vec<u8>);
+
+/// What the target instance did with the keys sent to it
+#[derive(Debug, Default)]
+pub struct Migrated {
+    /// Keys the target restored, which MIGRATE deletes unless `COPY` is set
+    pub restored: Vec<Vec<u8>>,
+    /// The first error, replied to the client once restored keys are dealt
+    /// with
+    pub error: Option<RedisError>,
+}
+
+/// Sends `restores` to the target instance. Keys restored before an error
+/// are still reported, as they now exist on the target.
+pub async fn send(options: &MigrateOptions, restores: &[Restore]) -> Migrated {
+    let mut migrated = Migrated::default();
+    let connect = TcpStream::connect((options.host.as_str(), options.port));
+    let mut stream = match timeout(options.timeout, connect).await {
+        Ok(Ok(stream)) => stream,
+        _ => {
+            migrated.error = Some(RedisError::MigrateIo("connecting to the client".to_string()));
+            return migrated;
+        }
+    };
+
+    let mut request = Vec::new();
+    let mut preamble = 1;
+    if let Some((username, password)) = &options.auth {
+        let mut args = vec![b"AUTH".to_vec()];
+        args.extend(username.iter().map(|username| username.clone().into_bytes()));
+        args.push(password.clone().into_bytes());
+        request.extend(encode(args));
+        preamble += 1;
+    }
+    request.extend(encode(vec![b"SELECT".to_vec(), options.db.to_string().into_bytes()]));
+    for (key, ttl, payload) in restores {
+        let mut args = vec![b"RESTORE".to_vec(), key.clone(), ttl.to_string().into_bytes(), payload.clone()];
+        if options.replace {
+            args.push(b"REPLACE".to_vec());
+        }
+        request.extend(encode(args));
+    }
+    if !matches!(timeout(options.timeout, stream.write_all(&request)).await, Ok(Ok(()))) {
+        migrated.error = Some(RedisError::MigrateIo("writing to target instance".to_string()));
+        return migrated;
+    }
+
+    // A failed AUTH or SELECT fails every RESTORE after it
+    let mut buffer = BytesMut::with_capacity(4096);
+    let mut target_error = None;
+    let mut preamble_failed = false;
+    for index in 0..preamble + restores.len() {
+        let reply = match read_reply(&mut stream, &mut buffer, options.timeout).await {
+            Some(reply) => reply,
+            None => {
+                migrated.error = Some(RedisError::MigrateIo("reading to target instance".to_string()));
+                return migrated;
+            }
+        };
+        match reply {
+            RESPOutput::Error(error) => {
+                target_error.get_or_insert(error);
+                preamble_failed |= index < preamble;
+            }
+            _ if index >= preamble && !preamble_failed => {
+                migrated.restored.push(restores[index - preamble].0.clone());
+            }
+            _ => {}
+        }
+    }
+    migrated.error = target_error
+        .map(|error| RedisError::Message(format!("Target instance replied with error: {}", error)));
+    migrated
+}
+
+/// Reads the next reply of the target instance, `None` on a connection
+/// error, a timeout or a reply that cannot be parsed
+async fn read_reply(stream: &mut TcpStream, buffer: &mut BytesMut, limit: Duration) -> Option<RESPOutput> {
+    loop {
+        match Parser::parse(buffer) {
+            Ok((reply, rest)) => {
+                let consumed = buffer.len() - rest.len();
+                buffer.advance(consumed);
+                return Some(reply);
+            }
+            Err(e) if e.is_incomplete() => {}
+            Err(_) => return None,
+        }
+        match timeout(limit, stream.read_buf(buffer)).await {
+            Ok(Ok(size)) if size > 0 => {}
+            _ => return None,
+        }
+    }
+}
+
+/// Encodes a command as a RESP array of bulk strings
+fn encode(args: Vec<Vec<u8>>) -> Vec<u8> {
+    RESPOutput::Array(args.into_iter().map(RESPOutput::BulkBytes).collect()).encode()
+}
@@ -0,0 +1,1631 @@
+use std::time::Duration;
+
+pub mod debug;
+pub mod lcs;
+pub mod migrate;
+
+use crate::error::{RedisError, Result};
+use crate::parser::RESPOutput;
+use crate::persistence::{dump, rdb};
+use crate::replication::ReplicaState;
+use crate::store::datatype::DataType;
+use crate::store::redis::{unix_millis, ExpireCondition, Expiry, SetCondition, SetOptions, Database, Store, MAX_STRING_LENGTH};
+use debug::DebugCommand;
+use lcs::Lcs;
+use migrate::MigrateOptions;
+
+#[derive(Debug)]
+pub enum Command {
+    Ping,
+    Echo(Vec<u8>),
+    Get(Vec<u8>),
+    Set(Vec<u8>, DataType, SetOptions),
+    Config(String, String, Option<DataType>),
+    Append(Vec<u8>, Vec<u8>),
+    Strlen(Vec<u8>),
+    GetRange(Vec<u8>, i64, i64),
+    SetRange(Vec<u8>, usize, Vec<u8>),
+    Lcs(Vec<u8>, Vec<u8>, LcsOptions),
+    MSet(Vec<(Vec<u8>, DataType)>),
+    MSetNx(Vec<(Vec<u8>, DataType)>),
+    MGet(Vec<Vec<u8>>),
+    GetDel(Vec<u8>),
+    GetEx(Vec<u8>, Option<Expiry>),
+    SetNx(Vec<u8>, DataType),
+    Del(Vec<Vec<u8>>),
+    Exists(Vec<Vec<u8>>),
+    Type(Vec<u8>),
+    Rename(Vec<u8>, Vec<u8>),
+    RenameNx(Vec<u8>, Vec<u8>),
+    Copy(Vec<u8>, Vec<u8>, CopyOptions),
+    Touch(Vec<Vec<u8>>),
+    RandomKey,
+    Keys(Vec<u8>),
+    Scan(u64, ScanOptions),
+    /// EXPIRE, PEXPIRE, EXPIREAT and PEXPIREAT, with the deadline resolved
+    /// to unix milliseconds
+    Expire(Vec<u8>, i64, ExpireCondition),
+    Ttl(Vec<u8>),
+    PTtl(Vec<u8>),
+    ExpireTime(Vec<u8>),
+    PExpireTime(Vec<u8>),
+    Persist(Vec<u8>),
+    Dump(Vec<u8>),
+    Restore(Vec<u8>, Vec<u8>, RestoreOptions),
+    Migrate(MigrateOptions),
+    Select(i64),
+    Move(Vec<u8>, i64),
+    SwapDb(i64, i64),
+    /// FLUSHDB, freeing the keys in the background when set (`ASYNC`)
+    FlushDb(bool),
+    /// FLUSHALL, freeing the keys in the background when set (`ASYNC`)
+    FlushAll(bool),
+    DbSize,
+    Save,
+    BgSave,
+    LastSave,
+    BgRewriteAof,
+    Info(Option<String>),
+    Debug(DebugCommand),
+    Replconf(Replconf),
+    /// PSYNC with the replication ID and offset the replica continues from
+    Psync(String, i64),
+    Role,
+}
+
+/// Settings that can only be changed in the configuration file, as they
+/// guard what clients are allowed to do
+const IMMUTABLE_CONFIGS: &[&str] = &["enable-debug-command"];
+
+/// State kept for each client connection
+#[derive(Debug, Default)]
+pub struct Session {
+    /// Database selected with SELECT
+    pub db: usize,
+    /// Whether the client connected over the loopback interface
+    pub local: bool,
+    /// Port a replica accepts clients on, announced with `REPLCONF
+    /// listening-port`
+    pub listening_port: u16,
+    /// Address a replica announced with `REPLCONF ip-address`, instead of
+    /// the one it connected from
+    pub announced_ip: Option<String>,
+}
+
+/// Options accepted by the `COPY` command
+#[derive(Debug, Default)]
+pub struct CopyOptions {
+    /// Destination database (`DB`), defaults to the current one
+    pub db: Option<i64>,
+    /// Overwrite the destination if it exists (`REPLACE`)
+    pub replace: bool,
+}
+
+/// Options accepted by the `RESTORE` command
+#[derive(Debug, Default)]
+pub struct RestoreOptions {
+    /// When the key expires, resolved to unix milliseconds from the TTL
+    /// argument (relative, or absolute with `ABSTTL`), `None` for a TTL of 0
+    pub expiry: Option<i64>,
+    /// Overwrite the key if it exists (`REPLACE`)
+    pub replace: bool,
+}
+
+/// What a replica tells its master with `REPLCONF`
+#[derive(Debug)]
+pub enum Replconf {
+    /// Options of the handshake before PSYNC, `None` when not given
+    Handshake { listening_port: Option<u16>, ip_address: Option<String> },
+    /// The offset of the replication stream the replica processed (`ACK`)
+    Ack(u64),
+    /// Asks a replica for an ACK (`GETACK`)
+    GetAck,
+}
+
+/// Options accepted by the `SCAN` command
+#[derive(Debug)]
+pub struct ScanOptions {
+    /// Only return keys matching this glob (`MATCH`)
+    pub pattern: Option<Vec<u8>>,
+    /// Approximate number of keys to examine (`COUNT`)
+    pub count: usize,
+    /// Only return keys holding this type (`TYPE`)
+    pub type_name: Option<String>,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions { pattern: None, count: 10, type_name: None }
+    }
+}
+
+/// Options accepted by the `LCS` command
+#[derive(Debug, Default)]
+pub struct LcsOptions {
+    /// Reply with the length of the match only (`LEN`)
+    pub len: bool,
+    /// Reply with the matching ranges (`IDX`)
+    pub idx: bool,
+    /// Skip ranges shorter than this (`MINMATCHLEN`)
+    pub min_match_len: usize,
+    /// Include the length of each range (`WITHMATCHLEN`)
+    pub with_match_len: bool,
+}
+
+impl Command {
+    pub fn from_resp(resp: RESPOutput) -> Result<Self> {
+        match resp {
+            RESPOutput::Array(elements) => Self::parse_command(elements),
+            RESPOutput::SimpleString(s) => Ok(Command::Echo(s.into_bytes())),
+            RESPOutput::Error(_) => Err(RedisError::InvalidArguments),
+            RESPOutput::Integer(i) => Ok(Command::Get(i.to_string().into_bytes())),
+            RESPOutput::Double(d) => Ok(Command::Get(d.to_string().into_bytes())),
+            RESPOutput::Boolean(b) => Ok(Command::Get(b.to_string().into_bytes())),
+            RESPOutput::Null => Err(RedisError::InvalidArguments),
+            _ => Err(RedisError::InvalidArguments),
+        }
+    }
+
+    fn parse_command(elements: Vec<RESPOutput>) -> Result<Self> {
+        let (command, args) = elements.split_first()
+            .ok_or(RedisError::InvalidArguments)?;
+
+        match command {
+            RESPOutput::BulkString(cmd) => match cmd.to_uppercase().as_str() {
+                "PING" => Ok(Command::Ping),
+                "ECHO" => Ok(Command::Echo(bytes_arg(args, 0)?)),
+                "GET" => Ok(Command::Get(bytes_arg(args, 0)?)),
+                "SET" => {
+                    let key = bytes_arg(args, 0)?;
+                    let value = value_arg(args, 1)?;
+                    let options = Self::parse_set_options(&args[2..])?;
+                    Ok(Command::Set(key, value, options))
+                }
+                "CONFIG" => {
+                    let subcommand = string_arg(args, 0)?;
+
+                    match subcommand.to_uppercase().as_str() {
+                        "GET" => {
+                            let key = string_arg(args, 1)?;
+                            Ok(Command::Config("GET".to_string(), key, None))
+                        }
+                        "SET" => {
+                            let key = string_arg(args, 1)?;
+                            let value = value_arg(args, 2)?;
+                            Ok(Command::Config("SET".to_string(), key, Some(value)))
+                        }
+                        _ => Err(RedisError::InvalidArguments),
+                    }
+                }
+                "APPEND" => {
+                    check_arity(args, 2)?;
+                    Ok(Command::Append(bytes_arg(args, 0)?, bytes_arg(args, 1)?))
+                }
+                "STRLEN" => {
+                    check_arity(args, 1)?;
+                    Ok(Command::Strlen(bytes_arg(args, 0)?))
+                }
+                "GETRANGE" | "SUBSTR" => {
+                    check_arity(args, 3)?;
+                    Ok(Command::GetRange(bytes_arg(args, 0)?, integer_arg(args, 1)?, integer_arg(args, 2)?))
+                }
+                "SETRANGE" => {
+                    check_arity(args, 3)?;
+                    let offset = usize::try_from(integer_arg(args, 1)?)
+                        .map_err(|_| RedisError::Message("offset is out of range".to_string()))?;
+                    Ok(Command::SetRange(bytes_arg(args, 0)?, offset, bytes_arg(args, 2)?))
+                }
+                "LCS" => {
+                    if args.len() < 2 {
+                        return Err(RedisError::InvalidArguments);
+                    }
+                    let options = Self::parse_lcs_options(&args[2..])?;
+                    Ok(Command::Lcs(bytes_arg(args, 0)?, bytes_arg(args, 1)?, options))
+                }
+                "MSET" | "MSETNX" => {
+                    if args.is_empty() || args.len() % 2 != 0 {
+                        return Err(RedisError::InvalidArguments);
+                    }
+                    let pairs = (0..args.len())
+                        .step_by(2)
+                        .map(|i| Ok((bytes_arg(args, i)?, value_arg(args, i + 1)?)))
+                        .collect::<Result<Vec<_>>>()?;
+                    if cmd.eq_ignore_ascii_case("MSET") {
+                        Ok(Command::MSet(pairs))
+                    } else {
+                        Ok(Command::MSetNx(pairs))
+                    }
+                }
+                "MGET" => {
+                    if args.is_empty() {
+                        return Err(RedisError::InvalidArguments);
+                    }
+                    Ok(Command::MGet(bytes_args(args)?))
+                }
+                "GETDEL" => {
+                    check_arity(args, 1)?;
+                    Ok(Command::GetDel(bytes_arg(args, 0)?))
+                }
+                "GETEX" => {
+                    let key = bytes_arg(args, 0)?;
+                    let expiry = Self::parse_getex_options(&args[1..])?;
+                    Ok(Command::GetEx(key, expiry))
+                }
+                "GETSET" => {
+                    check_arity(args, 2)?;
+                    let options = SetOptions { get: true, ..SetOptions::default() };
+                    Ok(Command::Set(bytes_arg(args, 0)?, value_arg(args, 1)?, options))
+                }
+                "SETNX" => {
+                    check_arity(args, 2)?;
+                    Ok(Command::SetNx(bytes_arg(args, 0)?, value_arg(args, 1)?))
+                }
+                "SETEX" | "PSETEX" => {
+                    check_arity(args, 3)?;
+                    let unit = if cmd.eq_ignore_ascii_case("SETEX") { "EX" } else { "PX" };
+                    let expiry = parse_expire_option(unit, integer_arg(args, 1)?, &cmd.to_lowercase())?;
+                    let options = SetOptions { expiry: Some(expiry), ..SetOptions::default() };
+                    Ok(Command::Set(bytes_arg(args, 0)?, value_arg(args, 2)?, options))
+                }
+                "DEL" | "UNLINK" => {
+                    if args.is_empty() {
+                        return Err(RedisError::InvalidArguments);
+                    }
+                    Ok(Command::Del(bytes_args(args)?))
+                }
+                "EXISTS" | "TOUCH" => {
+                    if args.is_empty() {
+                        return Err(RedisError::InvalidArguments);
+                    }
+                    if cmd.eq_ignore_ascii_case("EXISTS") {
+                        Ok(Command::Exists(bytes_args(args)?))
+                    } else {
+                        Ok(Command::Touch(bytes_args(args)?))
+                    }
+                }
+                "TYPE" => {
+                    check_arity(args, 1)?;
+                    Ok(Command::Type(bytes_arg(args, 0)?))
+                }
+                "RENAME" | "RENAMENX" => {
+                    check_arity(args, 2)?;
+                    let (src, dst) = (bytes_arg(args, 0)?, bytes_arg(args, 1)?);
+                    if cmd.eq_ignore_ascii_case("RENAME") {
+                        Ok(Command::Rename(src, dst))
+                    } else {
+                        Ok(Command::RenameNx(src, dst))
+                    }
+                }
+                "COPY" => {
+                    let (src, dst) = (bytes_arg(args, 0)?, bytes_arg(args, 1)?);
+                    let options = Self::parse_copy_options(&args[2..])?;
+                    Ok(Command::Copy(src, dst, options))
+                }
+                "RANDOMKEY" => {
+                    check_arity(args, 0)?;
+                    Ok(Command::RandomKey)
+                }
+                "KEYS" => {
+                    check_arity(args, 1)?;
+                    Ok(Command::Keys(bytes_arg(args, 0)?))
+                }
+                "SCAN" => {
+                    let cursor = string_arg(args, 0)?
+                        .parse::<u64>()
+                        .map_err(|_| RedisError::Message("invalid cursor".to_string()))?;
+                    let options = Self::parse_scan_options(&args[1..])?;
+                    Ok(Command::Scan(cursor, options))
+                }
+                "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" => {
+                    let key = bytes_arg(args, 0)?;
+                    let when = integer_arg(args, 1)?;
+                    let at = Self::parse_expire_time(&cmd.to_lowercase(), when)?;
+                    let condition = Self::parse_expire_condition(&args[2..])?;
+                    Ok(Command::Expire(key, at, condition))
+                }
+                "TTL" | "PTTL" | "EXPIRETIME" | "PEXPIRETIME" => {
+                    check_arity(args, 1)?;
+                    let key = bytes_arg(args, 0)?;
+                    Ok(match cmd.to_uppercase().as_str() {
+                        "TTL" => Command::Ttl(key),
+                        "PTTL" => Command::PTtl(key),
+                        "EXPIRETIME" => Command::ExpireTime(key),
+                        _ => Command::PExpireTime(key),
+                    })
+                }
+                "PERSIST" => {
+                    check_arity(args, 1)?;
+                    Ok(Command::Persist(bytes_arg(args, 0)?))
+                }
+                "DUMP" => {
+                    check_arity(args, 1)?;
+                    Ok(Command::Dump(bytes_arg(args, 0)?))
+                }
+                "RESTORE" => {
+                    if args.len() < 3 {
+                        return Err(RedisError::InvalidArguments);
+                    }
+                    let options = Self::parse_restore_options(integer_arg(args, 1)?, &args[3..])?;
+                    Ok(Command::Restore(bytes_arg(args, 0)?, bytes_arg(args, 2)?, options))
+                }
+                "MIGRATE" => {
+                    if args.len() < 5 {
+                        return Err(RedisError::InvalidArguments);
+                    }
+                    Ok(Command::Migrate(Self::parse_migrate(args)?))
+                }
+                "SELECT" => {
+                    check_arity(args, 1)?;
+                    Ok(Command::Select(integer_arg(args, 0)?))
+                }
+                "MOVE" => {
+                    check_arity(args, 2)?;
+                    Ok(Command::Move(bytes_arg(args, 0)?, integer_arg(args, 1)?))
+                }
+                "SWAPDB" => {
+                    check_arity(args, 2)?;
+                    let a = integer_arg(args, 0)
+                        .map_err(|_| RedisError::Message("invalid first DB index".to_string()))?;
+                    let b = integer_arg(args, 1)
+                        .map_err(|_| RedisError::Message("invalid second DB index".to_string()))?;
+                    Ok(Command::SwapDb(a, b))
+                }
+                "FLUSHDB" | "FLUSHALL" => {
+                    let lazy = match args.len() {
+                        0 => false,
+                        1 => match string_arg(args, 0)?.to_uppercase().as_str() {
+                            "ASYNC" => true,
+                            "SYNC" => false,
+                            _ => return Err(RedisError::Syntax),
+                        },
+                        _ => return Err(RedisError::Syntax),
+                    };
+                    if cmd.eq_ignore_ascii_case("FLUSHDB") {
+                        Ok(Command::FlushDb(lazy))
+                    } else {
+                        Ok(Command::FlushAll(lazy))
+                    }
+                }
+                "DBSIZE" => {
+                    check_arity(args, 0)?;
+                    Ok(Command::DbSize)
+                }
+                "SAVE" => {
+                    check_arity(args, 0)?;
+                    Ok(Command::Save)
+                }
+                "BGSAVE" => {
+                    check_arity(args, 0)?;
+                    Ok(Command::BgSave)
+                }
+                "BGREWRITEAOF" => {
+                    check_arity(args, 0)?;
+                    Ok(Command::BgRewriteAof)
+                }
+                "LASTSAVE" => {
+                    check_arity(args, 0)?;
+                    Ok(Command::LastSave)
+                }
+                "DEBUG" => Ok(Command::Debug(DebugCommand::parse(args)?)),
+                "INFO" => {
+                    if args.len() > 1 {
+                        return Err(RedisError::Syntax);
+                    }
+                    let section = args.first().map(|_| string_arg(args, 0)).transpose()?;
+                    Ok(Command::Info(section))
+                }
+                "REPLCONF" => Ok(Command::Replconf(Self::parse_replconf(args)?)),
+                "PSYNC" => {
+                    check_arity(args, 2)?;
+                    Ok(Command::Psync(string_arg(args, 0)?, integer_arg(args, 1)?))
+                }
+                "ROLE" => {
+                    check_arity(args, 0)?;
+                    Ok(Command::Role)
+                }
+                _ => Err(RedisError::UnknownCommand),
+            },
+            _ => Err(RedisError::InvalidArguments),
+        }
+    }
+
+    /// Parses the flags following `SET key value`, in any order:
+    /// `[NX | XX] [GET] [EX seconds | PX milliseconds | EXAT unix-time-seconds |
+    /// PXAT unix-time-milliseconds | KEEPTTL]`
+    ///
+    /// Repeating a flag is allowed, the last value wins, but flags that
+    /// conflict with each other are a syntax error.
+    fn parse_set_options(args: &[RESPOutput]) -> Result<SetOptions> {
+        let mut options = SetOptions::default();
+        // Which of EX, PX, EXAT, PXAT or KEEPTTL set `options.expiry`
+        let mut expiry_flag: Option<String> = None;
+        let mut i = 0;
+        while i < args.len() {
+            let flag = string_arg(args, i)?.to_uppercase();
+            match flag.as_str() {
+                "NX" | "XX" => {
+                    let condition = if flag == "NX" { SetCondition::NotExists } else { SetCondition::Exists };
+                    if options.condition.is_some_and(|c| c != condition) {
+                        return Err(RedisError::Syntax);
+                    }
+                    options.condition = Some(condition);
+                }
+                "GET" => options.get = true,
+                "KEEPTTL" | "EX" | "PX" | "EXAT" | "PXAT" => {
+                    if expiry_flag.as_ref().is_some_and(|seen| *seen != flag) {
+                        return Err(RedisError::Syntax);
+                    }
+                    if flag == "KEEPTTL" {
+                        options.expiry = Some(Expiry::Keep);
+                    } else {
+                        if i + 1 >= args.len() {
+                            return Err(RedisError::Syntax);
+                        }
+                        i += 1;
+                        options.expiry = Some(parse_expire_option(&flag, integer_arg(args, i)?, "set")?);
+                    }
+                    expiry_flag = Some(flag);
+                }
+                _ => return Err(RedisError::Syntax),
+            }
+            i += 1;
+        }
+        Ok(options)
+    }
+
+    /// Parses `GETEX key [EX seconds | PX milliseconds | EXAT unix-time-seconds |
+    /// PXAT unix-time-milliseconds | PERSIST]` into the expiry to apply
+    fn parse_getex_options(args: &[RESPOutput]) -> Result<Option<Expiry>> {
+        let mut expiry = Some(Expiry::Keep);
+        let mut seen = false;
+        let mut i = 0;
+        while i < args.len() {
+            let flag = string_arg(args, i)?.to_uppercase();
+            if seen {
+                return Err(RedisError::Syntax);
+            }
+            match flag.as_str() {
+                "PERSIST" => expiry = None,
+                "EX" | "PX" | "EXAT" | "PXAT" => {
+                    if i + 1 >= args.len() {
+                        return Err(RedisError::Syntax);
+                    }
+                    i += 1;
+                    expiry = Some(parse_expire_option(&flag, integer_arg(args, i)?, "getex")?);
+                }
+                _ => return Err(RedisError::Syntax),
+            }
+            seen = true;
+            i += 1;
+        }
+        Ok(expiry)
+    }
+
+    fn parse_copy_options(args: &[RESPOutput]) -> Result<CopyOptions> {
+        let mut options = CopyOptions::default();
+        let mut i = 0;
+        while i < args.len() {
+            match string_arg(args, i)?.to_uppercase().as_str() {
+                "REPLACE" => options.replace = true,
+                "DB" if i + 1 < args.len() => {
+                    i += 1;
+                    options.db = Some(integer_arg(args, i)?);
+                }
+                _ => return Err(RedisError::Syntax),
+            }
+            i += 1;
+        }
+        Ok(options)
+    }
+
+    /// Resolves the time argument of `command` (one of the EXPIRE family, in
+    /// lowercase) to unix milliseconds
+    fn parse_expire_time(command: &str, when: i64) -> Result<i64> {
+        let invalid = || RedisError::Message(format!("invalid expire time in '{}' command", command));
+        let when = match command {
+            "expire" | "expireat" => when.checked_mul(1000).ok_or_else(invalid)?,
+            _ => when,
+        };
+        match command {
+            "expire" | "pexpire" => when.checked_add(unix_millis()).ok_or_else(invalid),
+            _ => Ok(when),
+        }
+    }
+
+    fn parse_expire_condition(args: &[RESPOutput]) -> Result<ExpireCondition> {
+        let mut condition = ExpireCondition::default();
+        for i in 0..args.len() {
+            match string_arg(args, i)?.to_uppercase().as_str() {
+                "NX" => condition.nx = true,
+                "XX" => condition.xx = true,
+                "GT" => condition.gt = true,
+                "LT" => condition.lt = true,
+                flag => return Err(RedisError::Message(format!("Unsupported option {}", flag))),
+            }
+        }
+
+        if condition.nx && (condition.xx || condition.gt || condition.lt) {
+            return Err(RedisError::Message(
+                "NX and XX, GT or LT options at the same time are not compatible".to_string(),
+            ));
+        }
+        if condition.gt && condition.lt {
+            return Err(RedisError::Message(
+                "GT and LT options at the same time are not compatible".to_string(),
+            ));
+        }
+        Ok(condition)
+    }
+
+    /// Parses the flags following `RESTORE key ttl serialized-value`:
+    /// `[REPLACE] [ABSTTL] [IDLETIME seconds] [FREQ frequency]`.
+    ///
+    /// IDLETIME and FREQ are checked like Redis does, then ignored, as keys
+    /// carry no LRU or LFU data here.
+    fn parse_restore_options(ttl: i64, args: &[RESPOutput]) -> Result<RestoreOptions> {
+        let mut options = RestoreOptions::default();
+        let mut absolute = false;
+        let (mut idle, mut freq) = (false, false);
+        let mut i = 0;
+        while i < args.len() {
+            let has_value = i + 1 < args.len();
+            match string_arg(args, i)?.to_uppercase().as_str() {
+                "REPLACE" => options.replace = true,
+                "ABSTTL" => absolute = true,
+                "IDLETIME" if has_value && !freq => {
+                    i += 1;
+                    if integer_arg(args, i)? < 0 {
+                        return Err(RedisError::Message("Invalid IDLETIME value, must be >= 0".to_string()));
+                    }
+                    idle = true;
+                }
+                "FREQ" if has_value && !idle => {
+                    i += 1;
+                    if !(0..=255).contains(&integer_arg(args, i)?) {
+                        return Err(RedisError::Message("Invalid FREQ value, must be >= 0 and <= 255".to_string()));
+                    }
+                    freq = true;
+                }
+                _ => return Err(RedisError::Syntax),
+            }
+            i += 1;
+        }
+
+        let invalid = || RedisError::Message("Invalid TTL value, must be >= 0".to_string());
+        if ttl < 0 {
+            return Err(invalid());
+        }
+        options.expiry = match (ttl, absolute) {
+            (0, _) => None,
+            (at, true) => Some(at),
+            (ttl, false) => Some(unix_millis().checked_add(ttl).ok_or_else(invalid)?),
+        };
+        Ok(options)
+    }
+
+    /// Parses `MIGRATE host port key|"" destination-db timeout [COPY]
+    /// [REPLACE] [AUTH password | AUTH2 username password] [KEYS key ...]`
+    fn parse_migrate(args: &[RESPOutput]) -> Result<MigrateOptions> {
+        let port = u16::try_from(integer_arg(args, 1)?).map_err(|_| RedisError::NotAnInteger)?;
+        // A timeout that is not positive means the default of one second
+        let timeout = match integer_arg(args, 4)? {
+            millis if millis <= 0 => 1000,
+            millis => millis as u64,
+        };
+        let mut options = MigrateOptions {
+            host: string_arg(args, 0)?,
+            port,
+            keys: vec![bytes_arg(args, 2)?],
+            db: integer_arg(args, 3)?,
+            timeout: Duration::from_millis(timeout),
+            copy: false,
+            replace: false,
+            auth: None,
+        };
+
+        let mut i = 5;
+        while i < args.len() {
+            let remaining = args.len() - i - 1;
+            match string_arg(args, i)?.to_uppercase().as_str() {
+                "COPY" => options.copy = true,
+                "REPLACE" => options.replace = true,
+                "AUTH" if remaining >= 1 => {
+                    options.auth = Some((None, string_arg(args, i + 1)?));
+                    i += 1;
+                }
+                "AUTH2" if remaining >= 2 => {
+                    options.auth = Some((Some(string_arg(args, i + 1)?), string_arg(args, i + 2)?));
+                    i += 2;
+                }
+                "KEYS" => {
+                    if !options.keys[0].is_empty() {
+                        return Err(RedisError::Message(
+                            "When using MIGRATE KEYS option, the key argument must be set to the empty string".to_string(),
+                        ));
+                    }
+                    options.keys = bytes_args(&args[i + 1..])?;
+                    break;
+                }
+                _ => return Err(RedisError::Syntax),
+            }
+            i += 1;
+        }
+        Ok(options)
+    }
+
+    /// Parses `REPLCONF <option> <value> [<option> <value> ...]`
+    fn parse_replconf(args: &[RESPOutput]) -> Result<Replconf> {
+        if args.len() % 2 == 1 {
+            return Err(RedisError::Syntax);
+        }
+        let mut listening_port = None;
+        let mut ip_address = None;
+        for i in (0..args.len()).step_by(2) {
+            match string_arg(args, i)?.to_lowercase().as_str() {
+                // ACK may be followed by FACK and the AOF offset, which does
+                // not matter here
+                "ack" => {
+                    let offset = u64::try_from(integer_arg(args, i + 1)?).map_err(|_| RedisError::NotAnInteger)?;
+                    return Ok(Replconf::Ack(offset));
+                }
+                "getack" => return Ok(Replconf::GetAck),
+                "listening-port" => {
+                    let port = u16::try_from(integer_arg(args, i + 1)?).map_err(|_| RedisError::NotAnInteger)?;
+                    listening_port = Some(port);
+                }
+                "ip-address" => ip_address = Some(string_arg(args, i + 1)?),
+                // Every snapshot is sent as a bulk payload, which replicas
+                // accept whatever capabilities they announce
+                "capa" => {}
+                option => return Err(RedisError::Message(format!("Unrecognized REPLCONF option: {}", option))),
+            }
+        }
+        Ok(Replconf::Handshake { listening_port, ip_address })
+    }
+
+    fn parse_scan_options(args: &[RESPOutput]) -> Result<ScanOptions> {
+        let mut options = ScanOptions::default();
+        let mut i = 0;
+        while i < args.len() {
+            let option = string_arg(args, i)?.to_uppercase();
+            if i + 1 >= args.len() {
+                return Err(RedisError::Syntax);
+            }
+            i += 1;
+            match option.as_str() {
+                "MATCH" => options.pattern = Some(bytes_arg(args, i)?),
+                "COUNT" => {
+                    options.count = usize::try_from(integer_arg(args, i)?)
+                        .ok()
+                        .filter(|count| *count >= 1)
+                        .ok_or(RedisError::Syntax)?;
+                }
+                "TYPE" => options.type_name = Some(string_arg(args, i)?),
+                _ => return Err(RedisError::Syntax),
+            }
+            i += 1;
+        }
+        Ok(options)
+    }
+
+    fn parse_lcs_options(args: &[RESPOutput]) -> Result<LcsOptions> {
+        let mut options = LcsOptions::default();
+        let mut i = 0;
+        while i < args.len() {
+            match string_arg(args, i)?.to_uppercase().as_str() {
+                "LEN" => options.len = true,
+                "IDX" => options.idx = true,
+                "WITHMATCHLEN" => options.with_match_len = true,
+                "MINMATCHLEN" => {
+                    i += 1;
+                    if i >= args.len() {
+                        return Err(RedisError::Syntax);
+                    }
+                    // Negative values behave like 0, as in Redis
+                    options.min_match_len = integer_arg(args, i)?.max(0) as usize;
+                }
+                _ => return Err(RedisError::Syntax),
+            }
+            i += 1;
+        }
+
+        if options.len && options.idx {
+            return Err(RedisError::Message(
+                "If you want both the length and indexes, please just use IDX.".to_string(),
+            ));
+        }
+        Ok(options)
+    }
+
+    pub async fn execute(&self, store: &Store, session: &mut Session) -> Result<Vec<u8>> {
+        let db = store.db(session.db);
+        match self {
+            Command::Ping => Ok(b"+PONG\r\n".to_vec()),
+            Command::Echo(s) => Ok(RESPOutput::BulkBytes(s.clone()).encode()),
+            Command::Set(key, value, options) => {
+                let (old, written) = db.set_with(key, value.clone(), options).await?;
+                if options.get {
+                    Ok(bulk_or_null(old).encode())
+                } else if written {
+                    Ok(b"+OK\r\n".to_vec())
+                } else {
+                    Ok(RESPOutput::Null.encode())
+                }
+            }
+            Command::Get(key) => {
+                let value = get_string(db, key).await?;
+                Ok(value.map_or(RESPOutput::Null, RESPOutput::BulkBytes).encode())
+            }
+            Command::Config(cmd, key, value) => {
+                match cmd.to_uppercase().as_str() {
+                    "GET" => {
+                        let value = store.get_config(key).await;
+                        Ok(value.map_or(RESPOutput::Null, RESPOutput::BulkString).encode())
+                    }
+                    "SET" => {
+                        if IMMUTABLE_CONFIGS.contains(&key.as_str()) {
+                            return Err(RedisError::Message(format!(
+                                "CONFIG SET failed (possibly related to argument '{}') - can't set immutable config",
+                                key,
+                            )));
+                        }
+                        match value {
+                            Some(value) => {
+                                store.set_config(key, value.to_string()).await;
+                                Ok(b"+OK\r\n".to_vec())
+                            }
+                            None => Err(RedisError::InvalidArguments)
+                        }
+                    }
+                    _ => Err(RedisError::InvalidArguments)
+                }
+            }
+            Command::Append(key, value) => {
+                let len = db.append(key, value).await?;
+                Ok(RESPOutput::Integer(len as i64).encode())
+            }
+            Command::Strlen(key) => {
+                let len = get_string(db, key).await?.map_or(0, |s| s.len());
+                Ok(RESPOutput::Integer(len as i64).encode())
+            }
+            Command::GetRange(key, start, end) => {
+                let value = get_string(db, key).await?.unwrap_or_default();
+                Ok(RESPOutput::BulkBytes(substring(&value, *start, *end).to_vec()).encode())
+            }
+            Command::SetRange(key, offset, value) => {
+                let len = db.setrange(key, *offset, value).await?;
+                Ok(RESPOutput::Integer(len as i64).encode())
+            }
+            Command::Lcs(key1, key2, options) => {
+                let a = get_string(db, key1).await?.unwrap_or_default();
+                let b = get_string(db, key2).await?.unwrap_or_default();
+                if (a.len() + 1).saturating_mul(b.len() + 1).saturating_mul(4) > MAX_STRING_LENGTH {
+                    return Err(RedisError::Message(
+                        "Insufficient memory, transient memory for LCS exceeds proto-max-bulk-len".to_string(),
+                    ));
+                }
+
+                let lcs = Lcs::compute(&a, &b, options.min_match_len);
+                let reply = if options.idx {
+                    let matches = lcs.matches.iter()
+                        .map(|m| {
+                            let mut range = vec![
+                                RESPOutput::Array(vec![RESPOutput::Integer(m.a.0 as i64), RESPOutput::Integer(m.a.1 as i64)]),
+                                RESPOutput::Array(vec![RESPOutput::Integer(m.b.0 as i64), RESPOutput::Integer(m.b.1 as i64)]),
+                            ];
+                            if options.with_match_len {
+                                range.push(RESPOutput::Integer(m.len as i64));
+                            }
+                            RESPOutput::Array(range)
+                        })
+                        .collect();
+                    RESPOutput::Array(vec![
+                        RESPOutput::BulkString("matches".to_string()),
+                        RESPOutput::Array(matches),
+                        RESPOutput::BulkString("len".to_string()),
+                        RESPOutput::Integer(lcs.sequence.len() as i64),
+                    ])
+                } else if options.len {
+                    RESPOutput::Integer(lcs.sequence.len() as i64)
+                } else {
+                    RESPOutput::BulkBytes(lcs.sequence)
+                };
+                Ok(reply.encode())
+            }
+            Command::MSet(pairs) => {
+                db.mset(pairs).await?;
+                Ok(b"+OK\r\n".to_vec())
+            }
+            Command::MSetNx(pairs) => {
+                let written = db.msetnx(pairs).await?;
+                Ok(RESPOutput::Integer(written as i64).encode())
+            }
+            Command::MGet(keys) => {
+                // Keys holding other types read as nil rather than failing the batch
+                let values = db.mget(keys).await?
+                    .into_iter()
+                    .map(|value| match value {
+                        Some(DataType::String(s)) => RESPOutput::BulkBytes(s),
+                        _ => RESPOutput::Null,
+                    })
+                    .collect();
+                Ok(RESPOutput::Array(values).encode())
+            }
+            Command::GetDel(key) => {
+                let value = db.getdel(key).await?;
+                Ok(bulk_or_null(value).encode())
+            }
+            Command::GetEx(key, expiry) => {
+                let value = db.getex(key, *expiry).await?;
+                Ok(bulk_or_null(value).encode())
+            }
+            Command::SetNx(key, value) => {
+                let options = SetOptions { condition: Some(SetCondition::NotExists), ..SetOptions::default() };
+                let (_, written) = db.set_with(key, value.clone(), &options).await?;
+                Ok(RESPOutput::Integer(written as i64).encode())
+            }
+            Command::Del(keys) => {
+                let removed = db.del(keys).await?;
+                Ok(RESPOutput::Integer(removed as i64).encode())
+            }
+            Command::Exists(keys) | Command::Touch(keys) => {
+                let count = db.exists(keys).await?;
+                Ok(RESPOutput::Integer(count as i64).encode())
+            }
+            Command::Type(key) => {
+                let name = db.key_type(key).await?.unwrap_or("none");
+                Ok(RESPOutput::SimpleString(name.to_string()).encode())
+            }
+            Command::Rename(src, dst) => {
+                db.rename(src, dst, false).await?;
+                Ok(b"+OK\r\n".to_vec())
+            }
+            Command::RenameNx(src, dst) => {
+                let renamed = db.rename(src, dst, true).await?;
+                Ok(RESPOutput::Integer(renamed as i64).encode())
+            }
+            Command::Copy(src, dst, options) => {
+                let dst_db = match options.db {
+                    Some(index) => db_index(store, index)?,
+                    None => session.db,
+                };
+                if src == dst && dst_db == session.db {
+                    return Err(RedisError::Message("source and destination objects are the same".to_string()));
+                }
+                let copied = store.copy(src, session.db, dst, dst_db, options.replace).await?;
+                Ok(RESPOutput::Integer(copied as i64).encode())
+            }
+            Command::RandomKey => {
+                let key = db.random_key().await?;
+                Ok(key.map_or(RESPOutput::Null, RESPOutput::BulkBytes).encode())
+            }
+            Command::Keys(pattern) => {
+                let keys = db.keys(pattern).await?;
+                Ok(RESPOutput::Array(keys.into_iter().map(RESPOutput::BulkBytes).collect()).encode())
+            }
+            Command::Scan(cursor, options) => {
+                let (next, keys) = db.scan(
+                    *cursor,
+                    options.count,
+                    options.pattern.as_deref(),
+                    options.type_name.as_deref(),
+                ).await?;
+                Ok(RESPOutput::Array(vec![
+                    RESPOutput::BulkString(next.to_string()),
+                    RESPOutput::Array(keys.into_iter().map(RESPOutput::BulkBytes).collect()),
+                ]).encode())
+            }
+            Command::Expire(key, at, condition) => {
+                let updated = db.expire(key, *at, *condition).await?;
+                Ok(RESPOutput::Integer(updated as i64).encode())
+            }
+            Command::Ttl(key) | Command::PTtl(key) | Command::ExpireTime(key) | Command::PExpireTime(key) => {
+                let reply = match db.expiry(key).await? {
+                    None => -2,
+                    Some(None) => -1,
+                    Some(Some(at)) => {
+                        let remaining = (at - unix_millis()).max(0);
+                        match self {
+                            Command::Ttl(_) => (remaining + 500) / 1000,
+                            Command::PTtl(_) => remaining,
+                            Command::ExpireTime(_) => at / 1000,
+                            _ => at,
+                        }
+                    }
+                };
+                Ok(RESPOutput::Integer(reply).encode())
+            }
+            Command::Persist(key) => {
+                let removed = db.persist(key).await?;
+                Ok(RESPOutput::Integer(removed as i64).encode())
+            }
+            Command::Dump(key) => {
+                let payload = db.get_with_expiry(key).await?.map(|(value, _)| dump::dump(&value));
+                Ok(payload.map_or(RESPOutput::Null, RESPOutput::BulkBytes).encode())
+            }
+            Command::Restore(key, payload, options) => {
+                let value = dump::restore(payload)?;
+                db.restore(key, value, options.expiry, options.replace).await?;
+                Ok(b"+OK\r\n".to_vec())
+            }
+            Command::Migrate(options) => {
+                // Keys that do not exist, or expire before they are sent,
+                // are skipped
+                let now = unix_millis();
+                let mut restores = Vec::new();
+                for key in &options.keys {
+                    if let Some((value, expiry)) = db.get_with_expiry(key).await? {
+                        let ttl = expiry.map_or(0, |at| (at - now).max(1));
+                        restores.push((key.clone(), ttl, dump::dump(&value)));
+                    }
+                }
+                if restores.is_empty() {
+                    return Ok(b"+NOKEY\r\n".to_vec());
+                }
+
+                // The keys moved are deleted with a DEL, which is what gets
+                // logged to the AOF and streamed to replicas, as Redis
+                // propagates MIGRATE
+                let migrated = migrate::send(options, &restores).await;
+                if !options.copy && !migrated.restored.is_empty() {
+                    let del = Command::Del(migrated.restored);
+                    Box::pin(store.replication().execute(&del, store, session)).await?;
+                }
+                match migrated.error {
+                    Some(error) => Err(error),
+                    None => Ok(b"+OK\r\n".to_vec()),
+                }
+            }
+            Command::Select(index) => {
+                session.db = db_index(store, *index)?;
+                Ok(b"+OK\r\n".to_vec())
+            }
+            Command::Move(key, index) => {
+                let dst = db_index(store, *index)?;
+                if dst == session.db {
+                    return Err(RedisError::Message("source and destination objects are the same".to_string()));
+                }
+                let moved = store.move_key(key, session.db, dst).await?;
+                Ok(RESPOutput::Integer(moved as i64).encode())
+            }
+            Command::SwapDb(a, b) => {
+                let (a, b) = (db_index(store, *a)?, db_index(store, *b)?);
+                store.swap_db(a, b).await;
+                Ok(b"+OK\r\n".to_vec())
+            }
+            Command::FlushDb(lazy) => {
+                db.flush(*lazy).await;
+                Ok(b"+OK\r\n".to_vec())
+            }
+            Command::FlushAll(lazy) => {
+                store.flush_all(*lazy).await;
+                Ok(b"+OK\r\n".to_vec())
+            }
+            Command::DbSize => Ok(RESPOutput::Integer(db.size().await as i64).encode()),
+            Command::Save => {
+                rdb::save(store).await?;
+                Ok(b"+OK\r\n".to_vec())
+            }
+            Command::BgSave => {
+                rdb::bgsave(store).await?;
+                Ok(b"+Background saving started\r\n".to_vec())
+            }
+            Command::LastSave => {
+                let last_save = store.save_state().status().last_save;
+                Ok(RESPOutput::Integer(last_save).encode())
+            }
+            Command::BgRewriteAof => {
+                let aof = store.aof()
+                    .ok_or_else(|| RedisError::Message("Append only file is disabled, set appendonly to yes to use it".to_string()))?;
+                aof.bgrewrite(store).await?;
+                Ok(b"+Background append only file rewriting started\r\n".to_vec())
+            }
+            Command::Info(section) => {
+                let section = section.as_deref().unwrap_or("default").to_lowercase();
+                let all = matches!(section.as_str(), "default" | "all" | "everything");
+                let mut info = String::new();
+                if all || section == "persistence" {
+                    let status = store.save_state().status();
+                    let seconds = |time: Option<Duration>| time.map_or(-1, |time| time.as_secs() as i64);
+                    info.push_str("# Persistence\r\n");
+                    info.push_str("loading:0\r\n");
+                    info.push_str(&format!("rdb_changes_since_last_save:{}\r\n", store.dirty()));
+                    info.push_str(&format!("rdb_bgsave_in_progress:{}\r\n", store.save_state().in_progress() as u8));
+                    info.push_str(&format!("rdb_last_save_time:{}\r\n", status.last_save));
+                    info.push_str(&format!("rdb_last_bgsave_status:{}\r\n", if status.last_save_ok { "ok" } else { "err" }));
+                    info.push_str(&format!("rdb_last_bgsave_time_sec:{}\r\n", seconds(status.last_bgsave_time)));
+                    info.push_str(&format!(
+                        "rdb_current_bgsave_time_sec:{}\r\n",
+                        seconds(status.current_save_start.map(|start| start.elapsed())),
+                    ));
+                    info.push_str(&format!("rdb_saves:{}\r\n", status.saves));
+                    let aof = match store.aof() {
+                        Some(aof) => Some(aof.status().await),
+                        None => None,
+                    };
+                    let ok = |ok: bool| if ok { "ok" } else { "err" };
+                    info.push_str(&format!("aof_enabled:{}\r\n", aof.is_some() as u8));
+                    if let Some(aof) = aof {
+                        info.push_str(&format!("aof_rewrite_in_progress:{}\r\n", aof.current_rewrite_start.is_some() as u8));
+                        info.push_str(&format!("aof_last_rewrite_time_sec:{}\r\n", seconds(aof.last_rewrite_time)));
+                        info.push_str(&format!(
+                            "aof_current_rewrite_time_sec:{}\r\n",
+                            seconds(aof.current_rewrite_start.map(|start| start.elapsed())),
+                        ));
+                        info.push_str(&format!("aof_last_bgrewrite_status:{}\r\n", ok(aof.last_rewrite_ok)));
+                        info.push_str(&format!("aof_last_write_status:{}\r\n", ok(aof.last_write_ok)));
+                        info.push_str(&format!("aof_current_size:{}\r\n", aof.current_size));
+                        info.push_str(&format!("aof_base_size:{}\r\n", aof.base_size));
+                    }
+                }
+                if all || section == "stats" {
+                    if !info.is_empty() {
+                        info.push_str("\r\n");
+                    }
+                    let stats = store.expire_stats().await;
+                    info.push_str("# Stats\r\n");
+                    info.push_str(&format!("expired_keys:{}\r\n", stats.expired_keys));
+                    info.push_str(&format!("expired_stale_perc:{:.2}\r\n", stats.stale_perc * 100.0));
+                    info.push_str(&format!("expired_time_cap_reached_count:{}\r\n", stats.time_cap_reached_count));
+                    info.push_str(&format!("expire_cycle_cpu_milliseconds:{}\r\n", stats.cycle_time.as_millis()));
+                }
+                if all || section == "replication" {
+                    if !info.is_empty() {
+                        info.push_str("\r\n");
+                    }
+                    let ids = store.replication().ids();
+                    let status = store.replication().status().await;
+                    info.push_str("# Replication\r\n");
+                    info.push_str("role:master\r\n");
+                    info.push_str(&format!("connected_slaves:{}\r\n", status.replicas.len()));
+                    for (index, replica) in status.replicas.iter().enumerate() {
+                        info.push_str(&format!(
+                            "slave{}:ip={},port={},state={},offset={},lag={}\r\n",
+                            index,
+                            replica.ip,
+                            replica.port,
+                            replica.state.as_str(),
+                            replica.offset,
+                            replica.lag.as_secs(),
+                        ));
+                    }
+                    info.push_str(&format!("master_replid:{}\r\n", ids.id));
+                    info.push_str(&format!("master_replid2:{}\r\n", ids.id2));
+                    info.push_str(&format!("master_repl_offset:{}\r\n", status.offset));
+                    info.push_str(&format!("second_repl_offset:{}\r\n", ids.second_offset));
+                }
+                if all || section == "keyspace" {
+                    if !info.is_empty() {
+                        info.push_str("\r\n");
+                    }
+                    info.push_str("# Keyspace\r\n");
+                    for index in 0..store.databases() {
+                        let (keys, expires, avg_ttl) = store.db(index).keyspace_info().await;
+                        if keys > 0 {
+                            info.push_str(&format!("db{}:keys={},expires={},avg_ttl={}\r\n", index, keys, expires, avg_ttl));
+                        }
+                    }
+                }
+                Ok(RESPOutput::BulkString(info).encode())
+            }
+            Command::Debug(debug) => debug.execute(store, session).await,
+            Command::Replconf(Replconf::Handshake { listening_port, ip_address }) => {
+                if let Some(port) = listening_port {
+                    session.listening_port = *port;
+                }
+                if let Some(ip) = ip_address {
+                    session.announced_ip = Some(ip.clone());
+                }
+                Ok(b"+OK\r\n".to_vec())
+            }
+            // Only replicas send ACK, and only masters GETACK, neither gets a
+            // reply
+            Command::Replconf(_) => Ok(Vec::new()),
+            // The connection handler turns the connection into a link to the
+            // replica instead
+            Command::Psync(..) => Err(RedisError::Message("PSYNC is only accepted from a client connection".to_string())),
+            Command::Role => {
+                // Like Redis, only replicas done synchronizing are listed
+                let status = store.replication().status().await;
+                let replicas = status.replicas.iter()
+                    .filter(|replica| replica.state == ReplicaState::Online)
+                    .map(|replica| RESPOutput::Array(vec![
+                        RESPOutput::BulkString(replica.ip.clone()),
+                        RESPOutput::BulkString(replica.port.to_string()),
+                        RESPOutput::BulkString(replica.offset.to_string()),
+                    ]))
+                    .collect();
+                Ok(RESPOutput::Array(vec![
+                    RESPOutput::BulkString("master".to_string()),
+                    RESPOutput::Integer(status.offset as i64),
+                    RESPOutput::Array(replicas),
+                ]).encode())
+            }
+        }
+    }
+
+    /// Arguments of the command as logged to the append-only file, or `None`
+    /// for commands that never change the dataset.
+    ///
+    /// Commands are logged in a canonical form that replays the same change:
+    /// relative expiries become absolute ones, so replaying the log later does
+    /// not extend them, and GETDEL and GETEX become the write they performed.
+    /// MIGRATE is not logged itself, it deletes the keys it moved with a DEL
+    /// that is.
+    pub fn aof_args(&self) -> Option<Vec<Vec<u8>>> {
+        let args = |args: &[&[u8]]| args.iter().map(|arg| arg.to_vec()).collect::<Vec<_>>();
+        // Values written by commands are always strings
+        let value_bytes = |value: &DataType| match value {
+            DataType::String(bytes) => bytes.clone(),
+            value => value.to_string().into_bytes(),
+        };
+        let args = match self {
+            Command::Set(key, value, options) => {
+                let mut args = args(&[b"SET", key, &value_bytes(value)]);
+                match options.condition {
+                    Some(SetCondition::NotExists) => args.push("NX".into()),
+                    Some(SetCondition::Exists) => args.push("XX".into()),
+                    None => {}
+                }
+                match options.expiry.map(expiry_at) {
+                    Some(Some(at)) => args.extend(["PXAT".into(), at.to_string().into()]),
+                    Some(None) => args.push("KEEPTTL".into()),
+                    None => {}
+                }
+                args
+            }
+            Command::Append(key, value) => args(&[b"APPEND", key, value]),
+            Command::SetRange(key, offset, value) => args(&[b"SETRANGE", key, offset.to_string().as_bytes(), value]),
+            Command::MSet(pairs) | Command::MSetNx(pairs) => {
+                let name: &[u8] = if matches!(self, Command::MSet(_)) { b"MSET" } else { b"MSETNX" };
+                let mut args = args(&[name]);
+                for (key, value) in pairs {
+                    args.extend([key.clone(), value_bytes(value)]);
+                }
+                args
+            }
+            Command::GetDel(key) => args(&[b"DEL", key]),
+            Command::GetEx(key, expiry) => match expiry.map(expiry_at) {
+                Some(Some(at)) => args(&[b"PEXPIREAT", key, at.to_string().as_bytes()]),
+                // Without options GETEX is a plain read
+                Some(None) => return None,
+                None => args(&[b"PERSIST", key]),
+            },
+            Command::SetNx(key, value) => args(&[b"SETNX", key, &value_bytes(value)]),
+            Command::Del(keys) => {
+                let mut args = args(&[b"DEL"]);
+                args.extend(keys.iter().cloned());
+                args
+            }
+            Command::Rename(src, dst) => args(&[b"RENAME", src, dst]),
+            Command::RenameNx(src, dst) => args(&[b"RENAMENX", src, dst]),
+            Command::Copy(src, dst, options) => {
+                let mut args = args(&[b"COPY", src, dst]);
+                if let Some(db) = options.db {
+                    args.extend(["DB".into(), db.to_string().into()]);
+                }
+                if options.replace {
+                    args.push("REPLACE".into());
+                }
+                args
+            }
+            // A deadline already gone deletes the key, which is logged as
+            // such, as Redis does
+            Command::Expire(key, at, _) if *at <= unix_millis() => args(&[b"DEL", key]),
+            Command::Expire(key, at, condition) => {
+                let mut args = args(&[b"PEXPIREAT", key, at.to_string().as_bytes()]);
+                let flags = [(condition.nx, "NX"), (condition.xx, "XX"), (condition.gt, "GT"), (condition.lt, "LT")];
+                args.extend(flags.iter().filter(|(set, _)| *set).map(|(_, flag)| flag.as_bytes().to_vec()));
+                args
+            }
+            Command::Persist(key) => args(&[b"PERSIST", key]),
+            Command::Restore(key, payload, options) => {
+                let ttl = options.expiry.unwrap_or(0);
+                let mut args = args(&[b"RESTORE", key, ttl.to_string().as_bytes(), payload]);
+                if options.expiry.is_some() {
+                    args.push("ABSTTL".into());
+                }
+                if options.replace {
+                    args.push("REPLACE".into());
+                }
+                args
+            }
+            Command::Move(key, index) => args(&[b"MOVE", key, index.to_string().as_bytes()]),
+            Command::SwapDb(a, b) => args(&[b"SWAPDB", a.to_string().as_bytes(), b.to_string().as_bytes()]),
+            Command::FlushDb(_) => args(&[b"FLUSHDB"]),
+            Command::FlushAll(_) => args(&[b"FLUSHALL"]),
+            _ => return None,
+        };
+        Some(args)
+    }
+}
+
+/// Resolves the deadline a write sets to unix milliseconds, or `None` when
+/// it keeps the current one
+fn expiry_at(expiry: Expiry) -> Option<i64> {
+    match expiry {
+        Expiry::At(at) => Some(at),
+        Expiry::Keep => None,
+    }
+}
+
+/// Converts a single RESP argument into a string, the way commands read
+/// their options and other plain arguments
+fn arg_to_string(arg: &RESPOutput) -> Option<String> {
+    match arg {
+        RESPOutput::BulkString(s) => Some(s.clone()),
+        RESPOutput::SimpleString(s) => Some(s.clone()),
+        RESPOutput::Integer(i) => Some(i.to_string()),
+        RESPOutput::Double(d) => Some(d.to_string()),
+        RESPOutput::Boolean(b) => Some(b.to_string()),
+        RESPOutput::Null => Some("nil".to_string()),
+        _ => None,
+    }
+}
+
+/// Reads an argument as a string. Options and numbers are always text, so
+/// binary data here is invalid.
+fn string_arg(args: &[RESPOutput], index: usize) -> Result<String> {
+    args.get(index)
+        .and_then(arg_to_string)
+        .ok_or(RedisError::InvalidArguments)
+}
+
+/// Reads an argument as raw bytes, the way every command reads keys and
+/// values, which are binary safe
+fn bytes_arg(args: &[RESPOutput], index: usize) -> Result<Vec<u8>> {
+    match args.get(index) {
+        Some(RESPOutput::BulkBytes(bytes)) => Ok(bytes.clone()),
+        Some(arg) => arg_to_string(arg).map(String::into_bytes).ok_or(RedisError::InvalidArguments),
+        None => Err(RedisError::InvalidArguments),
+    }
+}
+
+/// Reads every argument as raw bytes, e.g. the key list of MGET
+fn bytes_args(args: &[RESPOutput]) -> Result<Vec<Vec<u8>>> {
+    (0..args.len()).map(|index| bytes_arg(args, index)).collect()
+}
+
+fn integer_arg(args: &[RESPOutput], index: usize) -> Result<i64> {
+    string_arg(args, index)?
+        .parse::<i64>()
+        .map_err(|_| RedisError::NotAnInteger)
+}
+
+fn value_arg(args: &[RESPOutput], index: usize) -> Result<DataType> {
+    args.get(index)
+        .and_then(|arg| match arg {
+            RESPOutput::BulkString(s) => Some(DataType::from(s.clone())),
+            RESPOutput::BulkBytes(bytes) => Some(DataType::from(bytes.clone())),
+            RESPOutput::SimpleString(s) => Some(DataType::from(s.clone())),
+            RESPOutput::Integer(i) => Some(DataType::from(*i)),
+            RESPOutput::Double(d) => Some(DataType::from(*d)),
+            RESPOutput::Boolean(b) => Some(DataType::from(*b)),
+            RESPOutput::Null => Some(DataType::from("nil")),
+            _ => None,
+        })
+        .ok_or(RedisError::InvalidArguments)
+}
+
+/// Builds the expiry for an `EX`/`PX`/`EXAT`/`PXAT` option and its value.
+/// Relative times are resolved to unix milliseconds right away, so the
+/// command is executed and logged with the same deadline, as EXPIRE is.
+///
+/// Like Redis, non-positive values and values that would overflow a
+/// millisecond unix timestamp are rejected as an invalid expire time.
+fn parse_expire_option(option: &str, value: i64, command: &str) -> Result<Expiry> {
+    let invalid = || RedisError::Message(format!("invalid expire time in '{}' command", command));
+    if value <= 0 {
+        return Err(invalid());
+    }
+
+    let millis = match option {
+        "EX" | "EXAT" => value.checked_mul(1000).ok_or_else(invalid)?,
+        _ => value,
+    };
+    match option {
+        "EX" | "PX" => unix_millis().checked_add(millis).map(Expiry::At).ok_or_else(invalid),
+        _ => Ok(Expiry::At(millis)),
+    }
+}
+
+/// Fails unless exactly `expected` arguments (not counting the command name) were given
+fn check_arity(args: &[RESPOutput], expected: usize) -> Result<()> {
+    if args.len() != expected {
+        return Err(RedisError::InvalidArguments);
+    }
+    Ok(())
+}
+
+/// Replies with a string value, or nil
+fn bulk_or_null(value: Option<DataType>) -> RESPOutput {
+    match value {
+        Some(DataType::String(bytes)) => RESPOutput::BulkBytes(bytes),
+        Some(value) => RESPOutput::BulkString(value.to_string()),
+        None => RESPOutput::Null,
+    }
+}
+
+/// Reads a string value, failing with `WrongType` for any other data type
+async fn get_string(db: &Database, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    match db.get(key).await? {
+        Some(DataType::String(s)) => Ok(Some(s)),
+        Some(_) => Err(RedisError::WrongType),
+        None => Ok(None),
+    }
+}
+
+/// Resolves a database index given by a client, failing if it is negative
+/// or not below the configured number of databases
+fn db_index(store: &Store, index: i64) -> Result<usize> {
+    usize::try_from(index)
+        .ok()
+        .filter(|index| *index < store.databases())
+        .ok_or_else(|| RedisError::Message("DB index is out of range".to_string()))
+}
+
+/// Returns the inclusive byte range `start..=end` of `value`, with negative
+/// offsets counting from the end, following the GETRANGE rules.
+fn substring(value: &[u8], start: i64, end: i64) -> &[u8] {
+    // Checked before the offsets are clamped, or both could land on byte 0
+    if start < 0 && end < 0 && start > end {
+        return &[];
+    }
+    let len = value.len() as i64;
+    let mut start = if start < 0 { len + start } else { start };
+    let mut end = if end < 0 { len + end } else { end };
+    start = start.max(0);
+    end = end.max(0).min(len - 1);
+
+    if len == 0 || start > end {
+        return &[];
+    }
+    &value[start as usize..=end as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(words: &[&str]) -> Vec<RESPOutput> {
+        words.iter().map(|word| RESPOutput::BulkString(word.to_string())).collect()
+    }
+
+    async fn run(store: &Store, words: &[&str]) -> Result<Vec<u8>> {
+        let command = Command::from_resp(RESPOutput::Array(args(words)))?;
+        command.execute(store, &mut Session::default()).await
+    }
+
+    #[test]
+    fn set_options_allow_repeated_flags() {
+        for words in [&["NX", "NX"][..], &["XX", "GET", "XX"], &["GET", "GET"], &["KEEPTTL", "KEEPTTL"]] {
+            assert!(Command::parse_set_options(&args(words)).is_ok(), "{:?}", words);
+        }
+
+        let options = Command::parse_set_options(&args(&["EX", "1000", "EX", "2"])).unwrap();
+        match options.expiry {
+            Some(Expiry::At(at)) => assert!((at - unix_millis() - 2000).abs() < 1000),
+            other => panic!("unexpected {:?}", other),
+        }
+        let options = Command::parse_set_options(&args(&["PXAT", "5", "PXAT", "7"])).unwrap();
+        assert_eq!(options.expiry, Some(Expiry::At(7)));
+    }
+
+    #[test]
+    fn set_options_reject_conflicting_flags() {
+        let conflicts: &[&[&str]] = &[
+            &["NX", "XX"],
+            &["XX", "NX"],
+            &["EX", "1", "PX", "1"],
+            &["PX", "1", "EXAT", "1"],
+            &["EXAT", "1", "PXAT", "1"],
+            &["EX", "1", "KEEPTTL"],
+            &["KEEPTTL", "PXAT", "1"],
+            &["EX"],
+            &["NOPE"],
+        ];
+        for words in conflicts {
+            assert!(matches!(Command::parse_set_options(&args(words)), Err(RedisError::Syntax)), "{:?}", words);
+        }
+
+        for words in [&["EX", "0"][..], &["PX", "-1"]] {
+            match Command::parse_set_options(&args(words)) {
+                Err(RedisError::Message(message)) => assert_eq!(message, "invalid expire time in 'set' command"),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert!(matches!(Command::parse_set_options(&args(&["EX", "ten"])), Err(RedisError::NotAnInteger)));
+    }
+
+    #[test]
+    fn set_options_parse_every_flag() {
+        let options = Command::parse_set_options(&args(&["xx", "get", "keepttl"])).unwrap();
+        assert_eq!(options.condition, Some(SetCondition::Exists));
+        assert!(options.get);
+        assert_eq!(options.expiry, Some(Expiry::Keep));
+
+        let options = Command::parse_set_options(&args(&["EXAT", "10", "NX"])).unwrap();
+        assert_eq!(options.condition, Some(SetCondition::NotExists));
+        assert!(!options.get);
+        assert_eq!(options.expiry, Some(Expiry::At(10_000)));
+    }
+
+    fn expire_error(words: &[&str]) -> String {
+        match Command::parse_expire_condition(&args(words)) {
+            Err(RedisError::Message(message)) => message,
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn expire_rejects_nx_with_other_conditions() {
+        for words in [&["NX", "XX"][..], &["NX", "GT"], &["LT", "NX"]] {
+            assert_eq!(expire_error(words), "NX and XX, GT or LT options at the same time are not compatible");
+        }
+        assert_eq!(expire_error(&["GT", "LT"]), "GT and LT options at the same time are not compatible");
+        assert_eq!(expire_error(&["SOON"]), "Unsupported option SOON");
+
+        let condition = Command::parse_expire_condition(&args(&["xx", "gt"])).unwrap();
+        assert!(condition.xx && condition.gt && !condition.nx && !condition.lt);
+    }
+
+    #[tokio::test]
+    async fn expire_treats_keys_without_ttl_as_infinite() {
+        let store = Store::new(1).await.unwrap();
+        run(&store, &["SET", "k", "v"]).await.unwrap();
+        assert_eq!(run(&store, &["EXPIRE", "k", "100", "GT"]).await.unwrap(), b":0\r\n");
+        assert_eq!(run(&store, &["EXPIRE", "k", "100", "XX"]).await.unwrap(), b":0\r\n");
+        assert_eq!(run(&store, &["TTL", "k"]).await.unwrap(), b":-1\r\n");
+        assert_eq!(run(&store, &["EXPIRE", "k", "100", "LT"]).await.unwrap(), b":1\r\n");
+        assert_eq!(run(&store, &["TTL", "k"]).await.unwrap(), b":100\r\n");
+
+        assert_eq!(run(&store, &["EXPIRE", "k", "50", "NX"]).await.unwrap(), b":0\r\n");
+        assert_eq!(run(&store, &["EXPIRE", "k", "50", "GT"]).await.unwrap(), b":0\r\n");
+        assert_eq!(run(&store, &["EXPIRE", "k", "200", "GT"]).await.unwrap(), b":1\r\n");
+        assert_eq!(run(&store, &["EXPIRE", "k", "300", "LT"]).await.unwrap(), b":0\r\n");
+        assert_eq!(run(&store, &["EXPIRE", "k", "150", "XX", "LT"]).await.unwrap(), b":1\r\n");
+        assert_eq!(run(&store, &["TTL", "k"]).await.unwrap(), b":150\r\n");
+
+        assert_eq!(run(&store, &["PERSIST", "k"]).await.unwrap(), b":1\r\n");
+        assert_eq!(run(&store, &["EXPIRE", "k", "50", "NX"]).await.unwrap(), b":1\r\n");
+        assert_eq!(run(&store, &["EXPIRE", "missing", "50"]).await.unwrap(), b":0\r\n");
+    }
+
+    #[tokio::test]
+    async fn expire_in_the_past_deletes_and_logs_del() {
+        let store = Store::new(1).await.unwrap();
+        let cases: &[&[&str]] = &[
+            &["EXPIRE", "k", "0"],
+            &["PEXPIRE", "k", "-100"],
+            &["EXPIREAT", "k", "1"],
+            &["PEXPIREAT", "k", "0"],
+        ];
+        for words in cases {
+            run(&store, &["SET", "k", "v"]).await.unwrap();
+            let command = Command::from_resp(RESPOutput::Array(args(words))).unwrap();
+            assert_eq!(command.aof_args(), Some(vec![b"DEL".to_vec(), b"k".to_vec()]), "{:?}", words);
+            assert_eq!(command.execute(&store, &mut Session::default()).await.unwrap(), b":1\r\n");
+            assert_eq!(run(&store, &["EXISTS", "k"]).await.unwrap(), b":0\r\n", "{:?}", words);
+        }
+
+        let command = Command::from_resp(RESPOutput::Array(args(&["EXPIRE", "k", "10", "NX"]))).unwrap();
+        let logged = command.aof_args().unwrap();
+        assert_eq!(logged[0], b"PEXPIREAT");
+        assert_eq!(logged[3], b"NX");
+    }
+
+    #[test]
+    fn substring_follows_getrange_rules() {
+        let value = b"This is a string";
+        let cases: &[(i64, i64, &[u8])] = &[
+            (0, 3, b"This"),
+            (-3, -1, b"ing"),
+            (0, -1, b"This is a string"),
+            (10, 100, b"string"),
+            (-100, 3, b"This"),
+            (0, -100, b"T"),
+            (5, 3, b""),
+            (-1, -5, b""),
+            // Both negative with start after end is empty, not clamped to byte 0
+            (-100, -200, b""),
+            (100, 200, b""),
+            (i64::MIN, i64::MAX, b"This is a string"),
+        ];
+        for &(start, end, expected) in cases {
+            assert_eq!(substring(value, start, end), expected, "{} {}", start, end);
+        }
+        assert_eq!(substring(b"", 0, -1), b"");
+        assert_eq!(substring(b"", -1, 0), b"");
+    }
+
+    #[tokio::test]
+    async fn setrange_pads_with_zero_bytes() {
+        let store = Store::new(1).await.unwrap();
+        assert_eq!(run(&store, &["SETRANGE", "k", "5", "ab"]).await.unwrap(), b":7\r\n");
+        assert_eq!(run(&store, &["GET", "k"]).await.unwrap(), b"$7\r\n\0\0\0\0\0ab\r\n");
+        assert_eq!(run(&store, &["SETRANGE", "k", "1", "xyz"]).await.unwrap(), b":7\r\n");
+        assert_eq!(run(&store, &["GET", "k"]).await.unwrap(), b"$7\r\n\0xyz\0ab\r\n");
+        assert_eq!(run(&store, &["GETRANGE", "k", "-2", "-1"]).await.unwrap(), b"$2\r\nab\r\n");
+
+        // An empty value changes nothing, and does not create the key
+        assert_eq!(run(&store, &["SETRANGE", "k", "100", ""]).await.unwrap(), b":7\r\n");
+        assert_eq!(run(&store, &["SETRANGE", "missing", "100", ""]).await.unwrap(), b":0\r\n");
+        assert_eq!(run(&store, &["EXISTS", "missing"]).await.unwrap(), b":0\r\n");
+        assert_eq!(run(&store, &["GETRANGE", "missing", "0", "-1"]).await.unwrap(), b"$0\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn setrange_rejects_bad_offsets() {
+        let store = Store::new(1).await.unwrap();
+        match run(&store, &["SETRANGE", "k", "-1", "a"]).await {
+            Err(RedisError::Message(message)) => assert_eq!(message, "offset is out of range"),
+            other => panic!("unexpected {:?}", other),
+        }
+        let offset = MAX_STRING_LENGTH.to_string();
+        match run(&store, &["SETRANGE", "k", &offset, "a"]).await {
+            Err(RedisError::Message(message)) => assert_eq!(message, "string exceeds maximum allowed size (proto-max-bulk-len)"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(run(&store, &["EXISTS", "k"]).await.unwrap(), b":0\r\n");
+
+        run(&store, &["SETRANGE", "k", &(MAX_STRING_LENGTH - 1).to_string(), ""]).await.unwrap();
+        assert_eq!(run(&store, &["EXISTS", "k"]).await.unwrap(), b":0\r\n");
+    }
+
+    #[tokio::test]
+    async fn lcs_replies_with_indexes() {
+        let store = Store::new(1).await.unwrap();
+        run(&store, &["MSET", "key1", "ohmytext", "key2", "mynewtext"]).await.unwrap();
+        assert_eq!(run(&store, &["LCS", "key1", "key2"]).await.unwrap(), b"$6\r\nmytext\r\n");
+        assert_eq!(run(&store, &["LCS", "key1", "key2", "LEN"]).await.unwrap(), b":6\r\n");
+
+        let range = |a: (i64, i64), b: (i64, i64), len: Option<i64>| {
+            let mut range = vec![
+                RESPOutput::Array(vec![RESPOutput::Integer(a.0), RESPOutput::Integer(a.1)]),
+                RESPOutput::Array(vec![RESPOutput::Integer(b.0), RESPOutput::Integer(b.1)]),
+            ];
+            range.extend(len.map(RESPOutput::Integer));
+            RESPOutput::Array(range)
+        };
+        let reply = |matches: Vec<RESPOutput>| RESPOutput::Array(vec![
+            RESPOutput::BulkString("matches".to_string()),
+            RESPOutput::Array(matches),
+            RESPOutput::BulkString("len".to_string()),
+            RESPOutput::Integer(6),
+        ]).encode();
+
+        assert_eq!(
+            run(&store, &["LCS", "key1", "key2", "IDX"]).await.unwrap(),
+            reply(vec![range((4, 7), (5, 8), None), range((2, 3), (0, 1), None)]),
+        );
+        assert_eq!(
+            run(&store, &["LCS", "key1", "key2", "IDX", "MINMATCHLEN", "4", "WITHMATCHLEN"]).await.unwrap(),
+            reply(vec![range((4, 7), (5, 8), Some(4))]),
+        );
+        assert_eq!(
+            run(&store, &["LCS", "key1", "key2", "IDX", "WITHMATCHLEN", "MINMATCHLEN", "-3"]).await.unwrap(),
+            reply(vec![range((4, 7), (5, 8), Some(4)), range((2, 3), (0, 1), Some(2))]),
+        );
+
+        match run(&store, &["LCS", "key1", "key2", "IDX", "LEN"]).await {
+            Err(RedisError::Message(message)) => assert_eq!(message, "If you want both the length and indexes, please just use IDX."),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(run(&store, &["LCS", "key1", "key2", "MINMATCHLEN"]).await, Err(RedisError::Syntax)));
+        assert_eq!(run(&store, &["LCS", "key1", "missing"]).await.unwrap(), b"$0\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn set_nx_px_works_as_a_lock() {
+        let store = Store::new(1).await.unwrap();
+        assert_eq!(run(&store, &["SET", "lock", "a", "NX", "PX", "100"]).await.unwrap(), b"+OK\r\n");
+        assert_eq!(run(&store, &["SET", "lock", "b", "NX", "PX", "100"]).await.unwrap(), b"$-1\r\n");
+        assert_eq!(run(&store, &["GET", "lock"]).await.unwrap(), b"$1\r\na\r\n");
+        let expiry = store.db(0).expiry(b"lock").await.unwrap().flatten().unwrap();
+        assert!(expiry > unix_millis() && expiry <= unix_millis() + 100);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(run(&store, &["SET", "lock", "b", "NX", "PX", "100"]).await.unwrap(), b"+OK\r\n");
+        assert_eq!(run(&store, &["GET", "lock"]).await.unwrap(), b"$1\r\nb\r\n");
+    }
+}
@@ -1,5 +1,5 @@
 use config::{Config, File};
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 
 #[derive(Debug, Deserialize)]
 pub struct ServerConfig {
@@ -7,17 +7,166 @@ pub struct ServerConfig {
     pub port: u16
 }
 
+/// A `save <seconds> <changes>` rule: snapshot once at least `changes`
+/// writes happened and `seconds` passed since the last save
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SavePoint {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+/// Whether to save a snapshot when shutting down on Ctrl-C
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShutdownMode {
+    /// Save only if any save point is configured
+    Default,
+    /// Always save
+    Save,
+    /// Never save
+    NoSave,
+}
+
+/// When the append-only file is fsynced to disk
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AppendFsync {
+    /// After every write, before replying to the client
+    Always,
+    /// Once per second, in the background
+    EverySec,
+    /// Never, the operating system flushes when it sees fit
+    No,
+}
+
+impl AppendFsync {
+    /// The policy as written in the configuration, for CONFIG GET
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AppendFsync::Always => "always",
+            AppendFsync::EverySec => "everysec",
+            AppendFsync::No => "no",
+        }
+    }
+}
+
+/// Whether clients may run the DEBUG command
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EnableDebugCommand {
+    No,
+    Yes,
+    /// Only from connections over the loopback interface
+    Local,
+}
+
+impl EnableDebugCommand {
+    /// The setting as written in the configuration, for CONFIG GET
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EnableDebugCommand::No => "no",
+            EnableDebugCommand::Yes => "yes",
+            EnableDebugCommand::Local => "local",
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct AppConfig {
     pub server: ServerConfig,
     pub dir: String,
     pub dbfilename: String,
+    /// How many times per second background tasks such as the active expire
+    /// cycle run, between 1 and 500
+    pub hz: u32,
+    /// Number of logical databases clients can SELECT
+    pub databases: usize,
+    /// Automatic save rules, written as in redis.conf: `"3600 1 300 100"`
+    /// holds two rules. An empty string disables automatic saves.
+    #[serde(deserialize_with = "deserialize_save_points")]
+    pub save: Vec<SavePoint>,
+    pub shutdown_on_sigint: ShutdownMode,
+    /// LZF-compress long strings in RDB snapshots
+    pub rdbcompression: bool,
+    /// Log every write to the append-only file, and load the dataset from
+    /// it instead of the RDB file on startup
+    pub appendonly: bool,
+    pub appendfsync: AppendFsync,
+    /// Base name of the append-only files and their manifest
+    pub appendfilename: String,
+    /// Directory in `dir` holding the append-only files
+    pub appenddirname: String,
+    /// Write the base file of an AOF rewrite in RDB format rather than as
+    /// commands
+    pub aof_use_rdb_preamble: bool,
+    /// Rewrite the AOF once it grew by this percentage since the last
+    /// rewrite, 0 to never rewrite automatically
+    pub auto_aof_rewrite_percentage: u64,
+    /// Size in bytes the AOF must reach before it is rewritten automatically
+    pub auto_aof_rewrite_min_size: u64,
+    /// Load an AOF whose last command was cut short, dropping that command,
+    /// instead of refusing to start
+    pub aof_load_truncated: bool,
+    /// Allow the DEBUG command, which can block the server or drop the
+    /// dataset. Off by default, like in Redis.
+    pub enable_debug_command: EnableDebugCommand,
+    /// Seconds between the PINGs sent to replicas
+    pub repl_ping_replica_period: u64,
+    /// Seconds a replica may go without acknowledging the stream before it
+    /// is disconnected
+    pub repl_timeout: u64,
+}
+
+/// Formats save points the way they are configured, for CONFIG GET
+pub fn format_save_points(points: &[SavePoint]) -> String {
+    points.iter()
+        .map(|point| format!("{} {}", point.seconds, point.changes))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Parses save points written as `format_save_points` formats them,
+/// `<seconds> <changes>` pairs, or returns `None` if `value` is not valid
+pub fn parse_save_points(value: &str) -> Option<Vec<SavePoint>> {
+    let numbers = value.split_whitespace()
+        .map(|n| n.parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+    if numbers.len() % 2 != 0 {
+        return None;
+    }
+
+    Some(numbers.chunks(2)
+        .map(|pair| SavePoint { seconds: pair[0], changes: pair[1] })
+        .collect())
+}
+
+fn deserialize_save_points<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<SavePoint>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_save_points(&value)
+        .ok_or_else(|| serde::de::Error::custom(format!("invalid save parameters '{}'", value)))
 }
 
 pub fn load_config() -> Result<AppConfig, config::ConfigError> {
     let config: AppConfig = Config::builder()
         .set_default("dir", "data")?
         .set_default("dbfilename", "dump.db")?
+        .set_default("hz", 10)?
+        .set_default("databases", 16)?
+        .set_default("save", "3600 1 300 100 60 10000")?
+        .set_default("shutdown_on_sigint", "default")?
+        .set_default("rdbcompression", true)?
+        .set_default("appendonly", false)?
+        .set_default("appendfsync", "everysec")?
+        .set_default("appendfilename", "appendonly.aof")?
+        .set_default("appenddirname", "appendonlydir")?
+        .set_default("aof_use_rdb_preamble", true)?
+        .set_default("auto_aof_rewrite_percentage", 100)?
+        .set_default("auto_aof_rewrite_min_size", 64 * 1024 * 1024)?
+        .set_default("aof_load_truncated", true)?
+        .set_default("enable_debug_command", "no")?
+        .set_default("repl_ping_replica_period", 10)?
+        .set_default("repl_timeout", 60)?
         .add_source(File::with_name("config.toml"))
         .build()?
         .try_deserialize()?;
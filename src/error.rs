@@ -6,15 +6,55 @@ use crate::parser::ParserError;
 pub enum RedisError {
     #[error("IO error: {0}")]
     Io(#[from] io::Error),
-    
+
     #[error("Parser error: {0}")]
     Parser(#[from] ParserError),
 
     #[error("Unknown command")]
     UnknownCommand,
-    
+
     #[error("Invalid command arguments")]
     InvalidArguments,
+
+    #[error("syntax error")]
+    Syntax,
+
+    #[error("value is not an integer or out of range")]
+    NotAnInteger,
+
+    #[error("Operation against a key holding the wrong kind of value")]
+    WrongType,
+
+    #[error("Errors writing to the AOF file: {0}")]
+    AofWrite(String),
+
+    #[error("Target key name already exists.")]
+    BusyKey,
+
+    /// MIGRATE could not talk to the target instance
+    #[error("error or timeout {0}")]
+    MigrateIo(String),
+
+    #[error("{0}")]
+    Message(String),
+}
+
+impl RedisError {
+    /// Encodes the error as a RESP error reply for the client.
+    ///
+    /// Type errors use the `WRONGTYPE` prefix like Redis does, writes
+    /// refused because the AOF cannot be written `MISCONF`, RESTORE over an
+    /// existing key `BUSYKEY` and MIGRATE connection failures `IOERR`.
+    /// Everything else is reported as a generic `ERR`.
+    pub fn to_resp(&self) -> String {
+        match self {
+            RedisError::WrongType => format!("-WRONGTYPE {}\r\n", self),
+            RedisError::AofWrite(_) => format!("-MISCONF {}\r\n", self),
+            RedisError::BusyKey => format!("-BUSYKEY {}\r\n", self),
+            RedisError::MigrateIo(_) => format!("-IOERR {}\r\n", self),
+            _ => format!("-ERR {}\r\n", self),
+        }
+    }
 }
 
-pub type Result<T> = std::result::Result<T, RedisError>; 
\ No newline at end of file
+pub type Result<T> = std::result::Result<T, RedisError>;
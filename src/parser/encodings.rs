@@ -0,0 +1,366 @@
+//! Decoders for the compact encodings Redis embeds in RDB strings
+//!
+//! Small collections are not saved element by element. Instead a single
+//! string holds the serialized in-memory structure Redis used for them:
+//!
+//! - zipmap: hashes, before RDB 4
+//! - ziplist: lists, hashes and sorted sets, up to RDB 9
+//! - intset: sets made only of integers
+//! - listpack: lists, sets, hashes and sorted sets, from RDB 10
+//!
+//! Integers stored natively by ziplists and listpacks are returned as their
+//! decimal string, the way Redis hands them out. The decoders check every
+//! bound and return `None` on corrupt input instead of panicking, including
+//! sizes and counts in headers that disagree with the contents and bytes
+//! left after the end.
+
+/// Reads little pieces off the front of a blob, failing at its end
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Cursor { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.take(N)?.try_into().ok()
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos == self.data.len()
+    }
+}
+
+/// Whether a header's element count agrees with the elements read. Counts
+/// too large for the 16-bit field are stored as `u16::MAX`, meaning unknown.
+fn count_matches(count: u16, len: usize) -> bool {
+    count == u16::MAX || count as usize == len
+}
+
+/// Decodes a ziplist into its elements
+///
+/// Layout: `<zlbytes:u32><zltail:u32><zllen:u16><entry>...<0xFF>`, where each
+/// entry is `<prevlen><encoding><data>`.
+pub fn ziplist(blob: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut cursor = Cursor::new(blob);
+    let total = u32::from_le_bytes(cursor.array()?);
+    cursor.take(4)?;
+    let count = u16::from_le_bytes(cursor.array()?);
+    if total as usize != blob.len() {
+        return None;
+    }
+
+    let mut elements = Vec::new();
+    loop {
+        // Length of the previous entry, or the end marker
+        match cursor.u8()? {
+            0xFF => break,
+            0xFE => {
+                cursor.take(4)?;
+            }
+            _ => {}
+        }
+
+        let encoding = cursor.u8()?;
+        let element = match encoding >> 6 {
+            0 => cursor.take((encoding & 0x3F) as usize)?.to_vec(),
+            1 => {
+                let len = (((encoding & 0x3F) as usize) << 8) | cursor.u8()? as usize;
+                cursor.take(len)?.to_vec()
+            }
+            2 => {
+                let len = u32::from_be_bytes(cursor.array()?) as usize;
+                cursor.take(len)?.to_vec()
+            }
+            _ => {
+                let value = match encoding {
+                    0xC0 => i16::from_le_bytes(cursor.array()?) as i64,
+                    0xD0 => i32::from_le_bytes(cursor.array()?) as i64,
+                    0xE0 => i64::from_le_bytes(cursor.array()?),
+                    0xF0 => int24(cursor.array()?),
+                    0xFE => cursor.u8()? as i8 as i64,
+                    // 4-bit immediate, offset by one
+                    0xF1..=0xFD => (encoding & 0x0F) as i64 - 1,
+                    _ => return None,
+                };
+                value.to_string().into_bytes()
+            }
+        };
+        elements.push(element);
+    }
+    if !cursor.at_end() || !count_matches(count, elements.len()) {
+        return None;
+    }
+    Some(elements)
+}
+
+/// Decodes a listpack into its elements
+///
+/// Layout: `<total-bytes:u32><num-elements:u16><entry>...<0xFF>`, where each
+/// entry is `<encoding><data><backlen>`.
+pub fn listpack(blob: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut cursor = Cursor::new(blob);
+    let total = u32::from_le_bytes(cursor.array()?);
+    let count = u16::from_le_bytes(cursor.array()?);
+    if total as usize != blob.len() {
+        return None;
+    }
+
+    let mut elements = Vec::new();
+    loop {
+        let start = cursor.pos;
+        let encoding = cursor.u8()?;
+        if encoding == 0xFF {
+            break;
+        }
+
+        let element = if encoding & 0x80 == 0 {
+            // 7-bit unsigned integer
+            (encoding & 0x7F).to_string().into_bytes()
+        } else if encoding & 0xC0 == 0x80 {
+            cursor.take((encoding & 0x3F) as usize)?.to_vec()
+        } else if encoding & 0xE0 == 0xC0 {
+            // 13-bit signed integer
+            let value = (((encoding & 0x1F) as i64) << 8) | cursor.u8()? as i64;
+            let value = if value >= 1 << 12 { value - (1 << 13) } else { value };
+            value.to_string().into_bytes()
+        } else if encoding & 0xF0 == 0xE0 {
+            let len = (((encoding & 0x0F) as usize) << 8) | cursor.u8()? as usize;
+            cursor.take(len)?.to_vec()
+        } else if encoding == 0xF0 {
+            let len = u32::from_le_bytes(cursor.array()?) as usize;
+            cursor.take(len)?.to_vec()
+        } else {
+            let value = match encoding {
+                0xF1 => i16::from_le_bytes(cursor.array()?) as i64,
+                0xF2 => int24(cursor.array()?),
+                0xF3 => i32::from_le_bytes(cursor.array()?) as i64,
+                0xF4 => i64::from_le_bytes(cursor.array()?),
+                _ => return None,
+            };
+            value.to_string().into_bytes()
+        };
+
+        // Skip the back length, whose size follows from the entry's size
+        cursor.take(backlen_size(cursor.pos - start))?;
+        elements.push(element);
+    }
+    if !cursor.at_end() || !count_matches(count, elements.len()) {
+        return None;
+    }
+    Some(elements)
+}
+
+/// Decodes an intset into its members
+///
+/// Layout: `<encoding:u32><length:u32><contents>`, where the encoding is the
+/// byte width (2, 4 or 8) of every little-endian integer in the contents.
+pub fn intset(blob: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut cursor = Cursor::new(blob);
+    let width = u32::from_le_bytes(cursor.array()?);
+    let len = u32::from_le_bytes(cursor.array()?);
+
+    let mut members = Vec::new();
+    for _ in 0..len {
+        let value = match width {
+            2 => i16::from_le_bytes(cursor.array()?) as i64,
+            4 => i32::from_le_bytes(cursor.array()?) as i64,
+            8 => i64::from_le_bytes(cursor.array()?),
+            _ => return None,
+        };
+        members.push(value.to_string().into_bytes());
+    }
+    if !cursor.at_end() {
+        return None;
+    }
+    Some(members)
+}
+
+/// Decodes a zipmap into its field/value pairs
+///
+/// Layout: `<zmlen:u8><len>field<len><free>value...<0xFF>`, where `free`
+/// counts unused bytes padding the value.
+pub fn zipmap(blob: &[u8]) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut cursor = Cursor::new(blob);
+    cursor.u8()?;
+
+    let mut pairs = Vec::new();
+    while let Some(len) = zipmap_len(&mut cursor)? {
+        let field = cursor.take(len)?.to_vec();
+        let len = zipmap_len(&mut cursor)??;
+        let free = cursor.u8()? as usize;
+        let value = cursor.take(len)?.to_vec();
+        cursor.take(free)?;
+        pairs.push((field, value));
+    }
+    if !cursor.at_end() {
+        return None;
+    }
+    Some(pairs)
+}
+
+/// Reads a zipmap length. The inner `None` is the end marker.
+fn zipmap_len(cursor: &mut Cursor) -> Option<Option<usize>> {
+    match cursor.u8()? {
+        0xFF => Some(None),
+        0xFE => Some(Some(u32::from_le_bytes(cursor.array()?) as usize)),
+        len => Some(Some(len as usize)),
+    }
+}
+
+/// Groups a flat list of elements into pairs, as hashes and sorted sets are
+/// stored. Fails on an odd number of elements.
+pub fn pairs(elements: Vec<Vec<u8>>) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut iter = elements.into_iter();
+    let mut pairs = Vec::new();
+    while let Some(first) = iter.next() {
+        pairs.push((first, iter.next()?));
+    }
+    Some(pairs)
+}
+
+/// Sign-extends a little-endian 24-bit integer
+fn int24(bytes: [u8; 3]) -> i64 {
+    (bytes[0] as i64) | ((bytes[1] as i64) << 8) | ((bytes[2] as i8 as i64) << 16)
+}
+
+/// Size of the back length stored after a listpack entry of `len` bytes
+fn backlen_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `RPUSH list a b 1`, as a ziplist
+    const ZIPLIST: &[u8] = b"\x13\x00\x00\x00\x10\x00\x00\x00\x03\x00\x00\x01a\x03\x01b\x03\xf2\xff";
+    /// `RPUSH list 12 -2 1000 100000 3000000000`, one integer encoding each
+    const ZIPLIST_INTEGERS: &[u8] = b"\x23\x00\x00\x00\x18\x00\x00\x00\x05\x00\
+        \x00\xfd\x02\xfe\xfe\x03\xc0\xe8\x03\x04\xf0\xa0\x86\x01\
+        \x05\xe0\x00\x5e\xd0\xb2\x00\x00\x00\x00\xff";
+    /// `RPUSH list a 1 -1`, as a listpack
+    const LISTPACK: &[u8] = b"\x0f\x00\x00\x00\x03\x00\x81a\x02\x01\x01\xdf\xff\x02\xff";
+    /// `SADD set 1 2 3`
+    const INTSET: &[u8] = b"\x02\x00\x00\x00\x03\x00\x00\x00\x01\x00\x02\x00\x03\x00";
+    /// `SADD set 1 65536 -3`, widened to 32 bits
+    const INTSET_32: &[u8] = b"\x04\x00\x00\x00\x03\x00\x00\x00\xfd\xff\xff\xff\x01\x00\x00\x00\x00\x00\x01\x00";
+    /// The zipmap of the RDB format documentation
+    const ZIPMAP: &[u8] = b"\x02\x06MKD1G6\x01\x002\x05YNNXK\x04\x00F7TI\xff";
+
+    fn strings(elements: &[&str]) -> Vec<Vec<u8>> {
+        elements.iter().map(|element| element.as_bytes().to_vec()).collect()
+    }
+
+    /// Every prefix and every single-byte change of `blob` must be handled
+    /// without panicking, and prefixes and trailing bytes must be rejected
+    fn check_damaged<T>(blob: &[u8], decode: fn(&[u8]) -> Option<T>) {
+        for len in 0..blob.len() {
+            assert!(decode(&blob[..len]).is_none(), "prefix of {} bytes", len);
+        }
+        let mut overlong = blob.to_vec();
+        overlong.push(0);
+        assert!(decode(&overlong).is_none());
+        for i in 0..blob.len() {
+            for byte in 0..=255 {
+                let mut damaged = blob.to_vec();
+                damaged[i] = byte;
+                decode(&damaged);
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_ziplists() {
+        assert_eq!(ziplist(ZIPLIST), Some(strings(&["a", "b", "1"])));
+        assert_eq!(ziplist(ZIPLIST_INTEGERS), Some(strings(&["12", "-2", "1000", "100000", "3000000000"])));
+    }
+
+    #[test]
+    fn rejects_damaged_ziplists() {
+        check_damaged(ZIPLIST, ziplist);
+        check_damaged(ZIPLIST_INTEGERS, ziplist);
+
+        // Bytes after the end marker, even when counted in the header
+        let mut overlong = ZIPLIST.to_vec();
+        overlong[0] += 1;
+        overlong.push(0);
+        assert_eq!(ziplist(&overlong), None);
+        // More elements than the header says
+        let mut miscounted = ZIPLIST.to_vec();
+        miscounted[8] = 2;
+        assert_eq!(ziplist(&miscounted), None);
+        miscounted[8..10].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert_eq!(ziplist(&miscounted), Some(strings(&["a", "b", "1"])));
+    }
+
+    #[test]
+    fn decodes_listpacks() {
+        assert_eq!(listpack(LISTPACK), Some(strings(&["a", "1", "-1"])));
+    }
+
+    #[test]
+    fn rejects_damaged_listpacks() {
+        check_damaged(LISTPACK, listpack);
+
+        let mut overlong = LISTPACK.to_vec();
+        overlong[0] += 1;
+        overlong.push(0);
+        assert_eq!(listpack(&overlong), None);
+        let mut miscounted = LISTPACK.to_vec();
+        miscounted[4] = 4;
+        assert_eq!(listpack(&miscounted), None);
+    }
+
+    #[test]
+    fn decodes_intsets() {
+        assert_eq!(intset(INTSET), Some(strings(&["1", "2", "3"])));
+        assert_eq!(intset(INTSET_32), Some(strings(&["-3", "1", "65536"])));
+    }
+
+    #[test]
+    fn rejects_damaged_intsets() {
+        check_damaged(INTSET, intset);
+        check_damaged(INTSET_32, intset);
+        // An encoding that is not a width
+        let mut bad_width = INTSET.to_vec();
+        bad_width[0] = 3;
+        assert_eq!(intset(&bad_width), None);
+    }
+
+    #[test]
+    fn decodes_zipmaps() {
+        let pairs = vec![(b"MKD1G6".to_vec(), b"2".to_vec()), (b"YNNXK".to_vec(), b"F7TI".to_vec())];
+        assert_eq!(zipmap(ZIPMAP), Some(pairs));
+    }
+
+    #[test]
+    fn rejects_damaged_zipmaps() {
+        check_damaged(ZIPMAP, zipmap);
+    }
+
+    #[test]
+    fn pairs_elements() {
+        assert_eq!(pairs(strings(&["f", "v"])), Some(vec![(b"f".to_vec(), b"v".to_vec())]));
+        assert_eq!(pairs(strings(&["f", "v", "g"])), None);
+    }
+}
@@ -2,12 +2,19 @@ use std::fmt;
 use std::time::Duration;
 
 pub mod rdb;
-pub use rdb::{RDBParser, RDBError, RDBValue, RDBEntry};
+pub mod rdb_async;
+pub mod encodings;
+pub mod stream;
+pub use rdb::{RDBParser, RDBError, RDBValue, RDBEntry, RdbEvent};
 
 #[derive(Debug)]
 pub enum RESPOutput {
     Array(Vec<RESPOutput>),
     BulkString(String),
+    /// A bulk string kept as raw bytes. The parser only uses it for bulk
+    /// strings that are not valid UTF-8, replies use it for keys and values,
+    /// which are binary safe.
+    BulkBytes(Vec<u8>),
     SimpleString(String),
     Error(String),
     Integer(i64),
@@ -16,6 +23,37 @@ pub enum RESPOutput {
     Null,
 }
 
+impl RESPOutput {
+    /// Serializes the value back into its RESP wire format
+    ///
+    /// `Null` is written as a RESP2 null bulk string (`$-1`), which is what
+    /// clients expect for missing keys.
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            RESPOutput::Array(elements) => {
+                let mut out = format!("*{}\r\n", elements.len()).into_bytes();
+                for element in elements {
+                    out.extend(element.encode());
+                }
+                out
+            }
+            RESPOutput::BulkString(s) => format!("${}\r\n{}\r\n", s.len(), s).into_bytes(),
+            RESPOutput::BulkBytes(bytes) => {
+                let mut out = format!("${}\r\n", bytes.len()).into_bytes();
+                out.extend_from_slice(bytes);
+                out.extend_from_slice(b"\r\n");
+                out
+            }
+            RESPOutput::SimpleString(s) => format!("+{}\r\n", s).into_bytes(),
+            RESPOutput::Error(s) => format!("-{}\r\n", s).into_bytes(),
+            RESPOutput::Integer(i) => format!(":{}\r\n", i).into_bytes(),
+            RESPOutput::Double(d) => format!(",{}\r\n", d).into_bytes(),
+            RESPOutput::Boolean(b) => format!("#{}\r\n", if *b { "t" } else { "f" }).into_bytes(),
+            RESPOutput::Null => b"$-1\r\n".to_vec(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum RESPCommand {
     Ping,
@@ -32,6 +70,14 @@ pub enum ParserError {
     InvalidInput,
 }
 
+impl ParserError {
+    /// Whether the input ended before a complete value was read, meaning
+    /// more bytes may still turn it into a valid value
+    pub fn is_incomplete(&self) -> bool {
+        matches!(self, ParserError::IncompleteInput | ParserError::CRLFNotFound)
+    }
+}
+
 // Implement Display for ParserError
 impl fmt::Display for ParserError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -49,16 +95,31 @@ impl std::error::Error for ParserError {}
 
 pub type ParserCRLFResult<'a> = Result<(&'a [u8], &'a [u8]), ParserError>;
 
+/// Largest bulk string accepted, Redis' default `proto-max-bulk-len`
+const PROTO_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+/// Largest number of elements accepted in an array, as in Redis
+const PROTO_MAX_MULTIBULK_LEN: u32 = i32::MAX as u32;
+/// Longest line waited for before giving up on finding its CRLF, such as
+/// the header of a bulk string, as in Redis
+const PROTO_INLINE_MAX_SIZE: usize = 64 * 1024;
+
 pub type ParserResult<'a> = Result<(RESPOutput, &'a [u8]), ParserError>;
 pub struct Parser {}
 
 impl Parser {
-    pub fn parse(input: &[u8]) -> ParserResult {
+    pub fn parse(input: &[u8]) -> ParserResult<'_> {
         // If input is empty, return an error
-        if input.len() == 0 || input[0] == 0 {
+        if input.is_empty() || input[0] == 0 {
             return Err(ParserError::IncompleteInput);
         }
 
+        // Stop waiting for the end of a line that is already too long, such
+        // as a header that never ends
+        let line = &input[..input.len().min(PROTO_INLINE_MAX_SIZE)];
+        if input.len() > PROTO_INLINE_MAX_SIZE && !line.windows(2).any(|window| window == b"\r\n") {
+            return Err(ParserError::InvalidInput);
+        }
+
         let symbol_lossy = String::from_utf8_lossy(&input[0..1]);
         let symbol = symbol_lossy.as_ref();
         let payload = &input[1..];
@@ -86,8 +147,8 @@ impl Parser {
 
         let (num_elements, remaining) = parsed.unwrap();
         let num_elements: u32 = match String::from(String::from_utf8_lossy(num_elements)).parse() {
-            Ok(num) => num,
-            Err(_) => {
+            Ok(num) if num <= PROTO_MAX_MULTIBULK_LEN => num,
+            _ => {
                 return Err(ParserError::InvalidInput);
             }
         };
@@ -97,11 +158,9 @@ impl Parser {
         let mut remaining = remaining;
 
         for _ in 0..num_elements {
-            let parsed = Parser::parse(remaining);
-            if parsed.is_err() {
-                return Err(ParserError::InvalidInput);
-            }
-            let (result, rem) = parsed.unwrap();
+            // Keep the element's error so callers can tell a command that is
+            // still arriving apart from a malformed one
+            let (result, rem) = Parser::parse(remaining)?;
             resp_result.push(result);
             remaining = rem;
         }
@@ -118,26 +177,38 @@ impl Parser {
             return Err(ParserError::CRLFNotFound);
         }
         let (length, rem) = parsed.unwrap();
-        let length: u32 = match String::from_utf8_lossy(length).parse() {
+        let length: i64 = match String::from_utf8_lossy(length).parse() {
             Ok(num) => num,
             Err(_) => {
                 return Err(ParserError::InvalidInput);
             }
         };
 
-        let parsed = Parser::parse_until_crlf(rem);
-        if parsed.is_err() {
-            return Err(ParserError::CRLFNotFound);
+        // $-1 is the RESP2 null bulk string
+        if length < 0 {
+            return Ok((RESPOutput::Null, rem));
+        }
+        // Lengths are checked before waiting for the data, which the read
+        // buffer would otherwise grow to hold
+        if length > PROTO_MAX_BULK_LEN as i64 {
+            return Err(ParserError::InvalidInput);
         }
-        let (result, rem) = parsed.unwrap();
-        let res = String::from(String::from_utf8_lossy(result));
 
-        // Validate the length of the string
-        if res.len() as u32 != length {
+        // Read exactly <length> bytes rather than scanning for CRLF, so values
+        // may contain "\r\n" themselves
+        let length = length as usize;
+        if rem.len() < length + 2 {
+            return Err(ParserError::IncompleteInput);
+        }
+        if &rem[length..length + 2] != b"\r\n" {
             return Err(ParserError::InvalidInput);
         }
+        let res = match String::from_utf8(rem[..length].to_vec()) {
+            Ok(s) => RESPOutput::BulkString(s),
+            Err(e) => RESPOutput::BulkBytes(e.into_bytes()),
+        };
 
-        Ok((RESPOutput::BulkString(res), rem))
+        Ok((res, &rem[length + 2..]))
     }
 
     fn parse_simple_string(payload: &[u8]) -> Result<(RESPOutput, &[u8]), ParserError> {
@@ -164,7 +235,10 @@ impl Parser {
             return Err(ParserError::CRLFNotFound);
         }
         let (result, rem) = parsed.unwrap();
-        Ok((RESPOutput::Integer(String::from(String::from_utf8_lossy(result)).parse().unwrap()), rem))
+        match String::from_utf8_lossy(result).parse() {
+            Ok(value) => Ok((RESPOutput::Integer(value), rem)),
+            Err(_) => Err(ParserError::InvalidInput),
+        }
     }
 
     fn parse_double(payload: &[u8]) -> Result<(RESPOutput, &[u8]), ParserError> {
@@ -173,7 +247,10 @@ impl Parser {
             return Err(ParserError::CRLFNotFound);
         }
         let (result, rem) = parsed.unwrap();
-        Ok((RESPOutput::Double(String::from(String::from_utf8_lossy(result)).parse().unwrap()), rem))
+        match String::from_utf8_lossy(result).parse() {
+            Ok(value) => Ok((RESPOutput::Double(value), rem)),
+            Err(_) => Err(ParserError::InvalidInput),
+        }
     }
 
     fn parse_boolean(payload: &[u8]) -> Result<(RESPOutput, &[u8]), ParserError> {
@@ -182,7 +259,11 @@ impl Parser {
             return Err(ParserError::CRLFNotFound);
         }
         let (result, rem) = parsed.unwrap();
-        Ok((RESPOutput::Boolean(String::from(String::from_utf8_lossy(result)).parse().unwrap()), rem))
+        match result {
+            b"t" => Ok((RESPOutput::Boolean(true), rem)),
+            b"f" => Ok((RESPOutput::Boolean(false), rem)),
+            _ => Err(ParserError::InvalidInput),
+        }
     }
 
     // fn parse_null(payload: &[u8]) -> Result<(RESPOutput, &[u8]), ParserError> {
@@ -194,13 +275,62 @@ impl Parser {
     //     Ok((RESPOutput::Null, rem))
     // }
 
-    fn parse_until_crlf(input: &[u8]) -> ParserCRLFResult {
-        for index in 0..input.len() - 1 {
-            if input[index] == b'\r' && input[index + 1] == b'\n' {
-                return Ok((&input[0..index], &input[index + 2..]));
-            }
+    fn parse_until_crlf(input: &[u8]) -> ParserCRLFResult<'_> {
+        match input.windows(2).position(|window| window == b"\r\n") {
+            Some(index) => Ok((&input[0..index], &input[index + 2..])),
+            None => Err(ParserError::CRLFNotFound),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &[u8]) -> Result<RESPOutput, ParserError> {
+        Parser::parse(input).map(|(output, _)| output)
+    }
+
+    #[test]
+    fn parses_binary_bulk_strings_by_length() {
+        match parse(b"$5\r\na\r\n\xffb\r\n") {
+            Ok(RESPOutput::BulkBytes(bytes)) => assert_eq!(bytes, b"a\r\n\xffb"),
+            other => panic!("unexpected {:?}", other),
         }
+        assert!(matches!(parse(b"$-1\r\n"), Ok(RESPOutput::Null)));
+    }
+
+    #[test]
+    fn waits_for_bulk_strings_up_to_the_maximum_length() {
+        let header = format!("${}\r\n", PROTO_MAX_BULK_LEN);
+        assert!(parse(header.as_bytes()).unwrap_err().is_incomplete());
+    }
+
+    #[test]
+    fn rejects_bulk_lengths_over_the_maximum() {
+        let header = format!("${}\r\n", PROTO_MAX_BULK_LEN + 1);
+        assert!(!parse(header.as_bytes()).unwrap_err().is_incomplete());
+        assert!(!parse(b"$4000000000\r\n").unwrap_err().is_incomplete());
+        // Near i64::MAX, where adding the CRLF would overflow
+        assert!(!parse(b"$9223372036854775807\r\n").unwrap_err().is_incomplete());
+        assert!(!parse(b"*1\r\n$9223372036854775806\r\nab").unwrap_err().is_incomplete());
+    }
+
+    #[test]
+    fn rejects_multibulk_lengths_over_the_maximum() {
+        assert!(parse(b"*2147483647\r\n").unwrap_err().is_incomplete());
+        assert!(!parse(b"*2147483648\r\n").unwrap_err().is_incomplete());
+        assert!(!parse(b"*99999999999999999999\r\n").unwrap_err().is_incomplete());
+    }
 
-        Err(ParserError::CRLFNotFound)
+    #[test]
+    fn rejects_lines_that_never_end() {
+        let mut input = b"$".to_vec();
+        input.resize(input.len() + PROTO_INLINE_MAX_SIZE, b'1');
+        assert!(!parse(&input).unwrap_err().is_incomplete());
+        // Long data after a complete header is fine
+        let mut input = format!("${}\r\n", 2 * PROTO_INLINE_MAX_SIZE).into_bytes();
+        input.resize(input.len() + PROTO_INLINE_MAX_SIZE, b'x');
+        assert!(parse(&input).unwrap_err().is_incomplete());
     }
 }
@@ -2,8 +2,9 @@
 //! 
 //! This module implements a parser for Redis RDB (Redis Database Backup) files.
 //! The RDB file format is a binary format used by Redis to store snapshots of its
-//! database. This implementation supports RDB version 11 and handles various Redis
-//! data types and encodings.
+//! database. This implementation supports RDB versions 1 through 12 and handles
+//! various Redis data types and encodings. Types and opcodes are only accepted
+//! from the version that introduced them on.
 //!
 //! # Format Overview
 //! 
@@ -13,58 +14,155 @@
 //! - Key-value pairs with optional expiry times
 //! - Special opcodes for database selection and auxiliary information
 //! - EOF marker
+//! - From version 5 on, a CRC64 checksum of everything before it
+//!
+//! Errors raised while parsing entries carry the file offset, the opcode being
+//! parsed and the last key read, see [`RDBError::Context`].
+//!
+//! The parser is an iterator over the [`RdbEvent`]s of the file, from the
+//! header to the EOF trailer. [`RDBParser::parse_entry`] returns only the
+//! keys, and [`AsyncRDBParser`](super::rdb_async::AsyncRDBParser) reads from a
+//! `tokio::io::AsyncRead`.
 //!
 //! # Example
 //!
 //! ```no_run
 //! use std::fs::File;
-//! use crate::parser::rdb::RDBParser;
+//! use std::io::BufReader;
+//! use redis_starter_rust::parser::rdb::{RDBParser, RdbEvent};
 //!
 //! let file = File::open("dump.rdb").unwrap();
-//! let mut parser = RDBParser::new(file);
-//! 
-//! // Parse the RDB header
-//! parser.parse_header().unwrap();
+//! let parser = RDBParser::new(BufReader::new(file));
 //!
-//! // Iterate through entries
-//! while let Some(entry) = parser.parse_entry().unwrap() {
-//!     println!("Key: {:?}, Value: {:?}", entry.key, entry.value);
+//! for event in parser {
+//!     match event.unwrap() {
+//!         RdbEvent::Header { version } => println!("RDB version {}", version),
+//!         RdbEvent::Entry(entry) => {
+//!             println!("DB {}, Key: {:?}, Value: {:?}", entry.db, entry.key, entry.value)
+//!         }
+//!         _ => {}
+//!     }
 //! }
 //! ```
 
 use std::io::{self, Read};
 use std::time::SystemTime;
 use std::fmt;
+use crate::persistence::crc64::crc64;
+use crate::persistence::lzf;
+use super::encodings;
+use super::stream::{self, Consumer, ConsumerGroup, PendingEntry, Stream, StreamId};
 
 // RDB Version Constants
-/// The RDB version supported by this parser (version 11)
-const RDB_VERSION: u32 = 11;
+/// The RDB version written by the snapshot writer (version 11)
+pub(crate) const RDB_VERSION: u32 = 11;
+/// The oldest RDB version this parser reads
+pub const RDB_MIN_VERSION: u32 = 1;
+/// The newest RDB version this parser reads, as saved by Redis 7.4
+pub const RDB_MAX_VERSION: u32 = 12;
+/// The first RDB version ending with a CRC64 checksum
+const RDB_CHECKSUM_VERSION: u32 = 5;
 
 // RDB Type Constants
 /// Represents a string value type in RDB
-const RDB_TYPE_STRING: u8 = 0;
+pub(crate) const RDB_TYPE_STRING: u8 = 0;
 /// Represents a list value type in RDB
-const RDB_TYPE_LIST: u8 = 1;
+pub(crate) const RDB_TYPE_LIST: u8 = 1;
 /// Represents a set value type in RDB
-const RDB_TYPE_SET: u8 = 2;
-/// Represents a sorted set value type in RDB
+pub(crate) const RDB_TYPE_SET: u8 = 2;
+/// Represents a sorted set value type in RDB, with scores saved as strings
 const RDB_TYPE_ZSET: u8 = 3;
 /// Represents a hash value type in RDB
-const RDB_TYPE_HASH: u8 = 4;
+pub(crate) const RDB_TYPE_HASH: u8 = 4;
+/// Sorted set with scores saved as binary doubles
+pub(crate) const RDB_TYPE_ZSET_2: u8 = 5;
+/// Module value, as saved by Redis 4.0 release candidates, with no framing
+const RDB_TYPE_MODULE_PRE_GA: u8 = 6;
+/// Module value saved as opcode-tagged fields, so it can be skipped
+const RDB_TYPE_MODULE_2: u8 = 7;
+/// Hash saved as a zipmap
+const RDB_TYPE_HASH_ZIPMAP: u8 = 9;
+/// List saved as a ziplist
+const RDB_TYPE_LIST_ZIPLIST: u8 = 10;
+/// Set of integers saved as an intset
+const RDB_TYPE_SET_INTSET: u8 = 11;
+/// Sorted set saved as a ziplist
+const RDB_TYPE_ZSET_ZIPLIST: u8 = 12;
+/// Hash saved as a ziplist
+const RDB_TYPE_HASH_ZIPLIST: u8 = 13;
+/// List saved as a quicklist of ziplists
+const RDB_TYPE_LIST_QUICKLIST: u8 = 14;
+/// Stream saved as listpacks
+const RDB_TYPE_STREAM_LISTPACKS: u8 = 15;
+/// Hash saved as a listpack
+const RDB_TYPE_HASH_LISTPACK: u8 = 16;
+/// Sorted set saved as a listpack
+const RDB_TYPE_ZSET_LISTPACK: u8 = 17;
+/// List saved as a quicklist of listpacks and plain nodes
+const RDB_TYPE_LIST_QUICKLIST_2: u8 = 18;
+/// Stream with its first ID, max deleted ID, entries added and group offsets
+const RDB_TYPE_STREAM_LISTPACKS_2: u8 = 19;
+/// Set saved as a listpack
+const RDB_TYPE_SET_LISTPACK: u8 = 20;
+/// Stream with the active time of consumers
+pub(crate) const RDB_TYPE_STREAM_LISTPACKS_3: u8 = 21;
+/// Hash with field expiries, as saved by Redis 7.4 release candidates
+const RDB_TYPE_HASH_METADATA_PRE_GA: u8 = 22;
+/// Listpack hash with field expiries, as saved by Redis 7.4 release candidates
+const RDB_TYPE_HASH_LISTPACK_EX_PRE_GA: u8 = 23;
+/// Hash with field expiries
+const RDB_TYPE_HASH_METADATA: u8 = 24;
+/// Listpack hash with field expiries
+const RDB_TYPE_HASH_LISTPACK_EX: u8 = 25;
+
+/// Quicklist node holding a single large element as is
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+/// Quicklist node holding a listpack of elements
+const QUICKLIST_NODE_PACKED: u64 = 2;
 
 // RDB Opcode Constants
 /// Marks the end of the RDB file
-const RDB_OPCODE_EOF: u8 = 0xFF;
+pub(crate) const RDB_OPCODE_EOF: u8 = 0xFF;
 /// Indicates a database selection operation
-const RDB_OPCODE_SELECTDB: u8 = 0xFE;
+pub(crate) const RDB_OPCODE_SELECTDB: u8 = 0xFE;
 /// Indicates an expiry time in seconds
 const RDB_OPCODE_EXPIRETIME: u8 = 0xFD;
 /// Indicates an expiry time in milliseconds
-const RDB_OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+pub(crate) const RDB_OPCODE_EXPIRETIME_MS: u8 = 0xFC;
 /// Indicates auxiliary information (metadata)
-const RDB_OPCODE_AUX: u8 = 0xFA;
+pub(crate) const RDB_OPCODE_AUX: u8 = 0xFA;
 /// Indicates database size information
-const RDB_OPCODE_RESIZEDB: u8 = 0xFB;
+pub(crate) const RDB_OPCODE_RESIZEDB: u8 = 0xFB;
+/// Indicates the LFU access frequency of the next key
+const RDB_OPCODE_FREQ: u8 = 0xF8;
+/// Indicates the LRU idle time of the next key, in seconds
+const RDB_OPCODE_IDLE: u8 = 0xF9;
+/// Indicates auxiliary data saved by a module
+const RDB_OPCODE_MODULE_AUX: u8 = 0xF7;
+/// Indicates a function library, as saved by Redis 7.0 release candidates
+const RDB_OPCODE_FUNCTION_PRE_GA: u8 = 0xF6;
+/// Indicates a function library
+const RDB_OPCODE_FUNCTION2: u8 = 0xF5;
+/// Indicates cluster slot size information
+const RDB_OPCODE_SLOT_INFO: u8 = 0xF4;
+
+// Module Value Opcodes
+/// Marks the end of a module value
+const RDB_MODULE_OPCODE_EOF: u64 = 0;
+/// Signed integer saved as a length
+const RDB_MODULE_OPCODE_SINT: u64 = 1;
+/// Unsigned integer saved as a length
+const RDB_MODULE_OPCODE_UINT: u64 = 2;
+/// 4 byte binary float
+const RDB_MODULE_OPCODE_FLOAT: u64 = 3;
+/// 8 byte binary double
+const RDB_MODULE_OPCODE_DOUBLE: u64 = 4;
+/// String
+const RDB_MODULE_OPCODE_STRING: u64 = 5;
+
+/// Characters module type names are made of, indexed by 6-bit values
+const MODULE_NAME_CHARSET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
 
 /// Represents errors that can occur during RDB parsing
 #[derive(Debug)]
@@ -73,7 +171,7 @@ pub enum RDBError {
     IoError(io::Error),
     /// Invalid magic string at the start of file (should be "REDIS")
     InvalidMagicString,
-    /// Unsupported RDB version (only version 11 is supported)
+    /// Unsupported RDB version (versions 1 through 12 are supported)
     UnsupportedVersion,
     /// Invalid length encoding in the RDB file
     InvalidLength,
@@ -81,16 +179,74 @@ pub enum RDBError {
     InvalidEncoding,
     /// Invalid value type encountered
     InvalidType,
+    /// A module value saved without framing, which cannot be skipped
+    /// without the module. Holds the module type name.
+    UnskippableModule(String),
+    /// The CRC64 trailer does not match the contents of the file
+    ChecksumMismatch {
+        /// Checksum stored in the trailer
+        expected: u64,
+        /// Checksum computed over the file
+        actual: u64,
+    },
+    /// An error raised while parsing an entry, with where it happened
+    Context {
+        /// Offset in the file at which the error was detected
+        offset: u64,
+        /// The type or opcode byte of the entry being parsed
+        opcode: Option<u8>,
+        /// The last key read, which may belong to the entry being parsed
+        last_key: Option<Vec<u8>>,
+        /// The underlying error
+        source: Box<RDBError>,
+    },
+}
+
+impl RDBError {
+    /// The underlying error, with any context stripped
+    pub fn root(&self) -> &RDBError {
+        match self {
+            RDBError::Context { source, .. } => source.root(),
+            error => error,
+        }
+    }
 }
 
 impl fmt::Display for RDBError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            RDBError::IoError(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+                write!(f, "Unexpected end of file")
+            },
+            RDBError::IoError(error) => write!(f, "I/O error: {}", error),
+            RDBError::ChecksumMismatch { expected, actual } => {
+                write!(f, "Wrong RDB checksum: expected {:016x}, got {:016x}", expected, actual)
+            },
+            RDBError::Context { offset, opcode, last_key, source } => {
+                write!(f, "{} at offset {}", source, offset)?;
+                if let Some(opcode) = opcode {
+                    write!(f, ", parsing type/opcode 0x{:02x}", opcode)?;
+                }
+                match last_key {
+                    Some(key) => write!(f, ", last key read {:?}", String::from_utf8_lossy(key)),
+                    None => write!(f, ", before any key"),
+                }
+            },
+            _ => write!(f, "{:?}", self),
+        }
     }
 }
 
 // StdError has to be bound as well
-impl std::error::Error for RDBError {}
+impl std::error::Error for RDBError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RDBError::IoError(error) => Some(error),
+            RDBError::Context { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
 
 impl From<io::Error> for RDBError {
     fn from(error: io::Error) -> Self {
@@ -99,21 +255,36 @@ impl From<io::Error> for RDBError {
 }
 
 /// Represents a value stored in Redis
-/// 
-/// Currently only supports String values, but will be extended
-/// to support other Redis data types in the future.
+///
+/// Collections are returned the same way whichever encoding the file used
+/// for them.
 #[derive(Debug)]
 pub enum RDBValue {
     /// String value stored as a byte vector (can be text or binary)
     String(Vec<u8>),
+    /// List elements, head first
+    List(Vec<Vec<u8>>),
+    /// Set members
+    Set(Vec<Vec<u8>>),
+    /// Sorted set members with their scores
+    SortedSet(Vec<(Vec<u8>, f64)>),
+    /// Hash field/value pairs. Fields with an expiry (RDB 12) that already
+    /// passed are left out; the expiry of the other fields is not kept.
+    Hash(Vec<(Vec<u8>, Vec<u8>)>),
+    /// Stream entries, metadata and consumer groups
+    Stream(Box<Stream>),
+    /// Value of a module type, which is skipped. Holds the module type name.
+    Module(String),
 }
 
 /// Represents a key-value entry in the RDB file
 /// 
 /// Each entry consists of:
 /// - A key (as bytes)
-/// - A value (currently only strings)
+/// - A value
 /// - An optional expiry time
+/// - Optional LRU/LFU eviction metadata
+/// - The database it belongs to
 #[derive(Debug)]
 pub struct RDBEntry {
     /// The key as a byte vector
@@ -122,6 +293,57 @@ pub struct RDBEntry {
     pub value: RDBValue,
     /// Optional expiry time for the key
     pub expiry: Option<SystemTime>,
+    /// Seconds since the key was last accessed, saved with an LRU policy
+    pub idle: Option<u64>,
+    /// Logarithmic access frequency counter, saved with an LFU policy
+    pub freq: Option<u8>,
+    /// Database selected by the last SELECTDB opcode before the entry
+    pub db: usize,
+}
+
+/// An event of an RDB file, in file order
+///
+/// The parser returns these from [`RDBParser::next_event`] and as an
+/// iterator. Everything that is not a key is an event of its own, so tools
+/// see the whole file and not only its keys.
+#[derive(Debug)]
+pub enum RdbEvent {
+    /// The file header, with the RDB version
+    Header { version: u32 },
+    /// An auxiliary field, like `redis-ver` or `ctime`
+    Aux { key: Vec<u8>, value: Vec<u8> },
+    /// The keys that follow belong to database `db`
+    SelectDb { db: usize },
+    /// The number of keys, and of keys with an expiry, in the current
+    /// database. Only a hint to size tables.
+    ResizeDb { size: usize, expires: usize },
+    /// The number of keys, and of keys with an expiry, in a cluster slot
+    SlotInfo { slot: usize, size: usize, expires: usize },
+    /// A function library
+    Function { code: Vec<u8> },
+    /// Auxiliary data saved by a module, which is skipped
+    ModuleAux { module: String },
+    /// A key with its value and metadata
+    Entry(RDBEntry),
+    /// The end of the file, with the checksum from the trailer if the
+    /// version has one. The checksum was verified unless it is zero.
+    Eof { checksum: Option<u64> },
+}
+
+/// Reader that keeps the offset and the running CRC64 of the bytes read
+struct TrackingReader<R: Read> {
+    inner: R,
+    offset: u64,
+    checksum: u64,
+}
+
+impl<R: Read> Read for TrackingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.offset += read as u64;
+        self.checksum = crc64(self.checksum, &buf[..read]);
+        Ok(read)
+    }
 }
 
 /// Parser for Redis RDB files
@@ -135,9 +357,37 @@ pub struct RDBEntry {
 /// - Integer encoding
 pub struct RDBParser<R: Read> {
     /// The underlying reader providing the RDB data
-    reader: R,
+    reader: TrackingReader<R>,
     /// The currently selected database number
-    current_db: u8,
+    current_db: usize,
+    /// The RDB version read from the header
+    version: u32,
+    /// Whether a zero checksum trailer, written when checksums are turned
+    /// off, is accepted without verification
+    skip_zero_checksum: bool,
+    /// The type or opcode byte of the entry being parsed
+    opcode: Option<u8>,
+    /// The last key read
+    last_key: Option<Vec<u8>>,
+    /// Code of the function libraries read
+    functions: Vec<Vec<u8>>,
+    /// Metadata read for the next key
+    pending: KeyMetadata,
+    /// Whether the header was parsed
+    header_read: bool,
+    /// Whether the EOF opcode was reached, or parsing failed
+    finished: bool,
+    /// Size of the whole input, when known, to reject lengths that claim
+    /// more bytes than are left
+    input_len: Option<u64>,
+}
+
+/// Expiry and eviction metadata, saved before the key it belongs to
+#[derive(Debug, Default)]
+struct KeyMetadata {
+    expiry: Option<SystemTime>,
+    idle: Option<u64>,
+    freq: Option<u8>,
 }
 
 impl<R: Read> RDBParser<R> {
@@ -151,18 +401,67 @@ impl<R: Read> RDBParser<R> {
     ///
     /// ```no_run
     /// use std::fs::File;
-    /// use crate::parser::rdb::RDBParser;
+    /// use redis_starter_rust::parser::rdb::RDBParser;
     ///
     /// let file = File::open("dump.rdb").unwrap();
     /// let parser = RDBParser::new(file);
     /// ```
     pub fn new(reader: R) -> Self {
         RDBParser {
-            reader,
+            reader: TrackingReader { inner: reader, offset: 0, checksum: 0 },
             current_db: 0,
+            version: RDB_MAX_VERSION,
+            skip_zero_checksum: true,
+            opcode: None,
+            last_key: None,
+            functions: Vec::new(),
+            pending: KeyMetadata::default(),
+            header_read: false,
+            finished: false,
+            input_len: None,
         }
     }
 
+    /// Code of the function libraries read so far. Libraries are not tied
+    /// to a key, so they are collected here instead of returned as entries.
+    pub fn functions(&self) -> &[Vec<u8>] {
+        &self.functions
+    }
+
+    /// Sets whether a checksum trailer of zero skips verification, which is
+    /// the default. Redis writes zero when `rdbchecksum` is off; turn this
+    /// off to require a real checksum.
+    pub fn set_skip_zero_checksum(&mut self, skip: bool) {
+        self.skip_zero_checksum = skip;
+    }
+
+    /// Sets the size of the whole input, such as the file size. Lengths
+    /// that claim more bytes than are left are then rejected as invalid
+    /// right away, instead of failing once the input runs out.
+    pub fn set_input_len(&mut self, len: u64) {
+        self.input_len = Some(len);
+    }
+
+    /// The number of bytes read so far
+    pub fn offset(&self) -> u64 {
+        self.reader.offset
+    }
+
+    /// The RDB version of the file, as read by [`parse_header`]. Until the
+    /// header is parsed, the newest supported version is assumed.
+    ///
+    /// [`parse_header`]: RDBParser::parse_header
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Returns the underlying reader, positioned after the last byte parsed.
+    /// An AOF with an RDB preamble continues with commands after the EOF
+    /// event.
+    pub fn into_inner(self) -> R {
+        self.reader.inner
+    }
+
     /// Parses the RDB file header
     ///
     /// The header consists of:
@@ -171,11 +470,11 @@ impl<R: Read> RDBParser<R> {
     ///
     /// # Returns
     ///
-    /// * `Ok(())` if header is valid
+    /// * `Ok(u32)` with the RDB version if header is valid
     /// * `Err(RDBError::InvalidMagicString)` if magic string is not "REDIS"
     /// * `Err(RDBError::UnsupportedVersion)` if version is not supported
     /// * `Err(RDBError::IoError)` if reading fails
-    pub fn parse_header(&mut self) -> Result<(), RDBError> {
+    pub fn parse_header(&mut self) -> Result<u32, RDBError> {
         let mut magic = [0u8; 5];
         self.reader.read_exact(&mut magic)?;
         
@@ -192,11 +491,13 @@ impl<R: Read> RDBParser<R> {
             .parse::<u32>()
             .map_err(|_| RDBError::InvalidMagicString)?;
 
-        if version_num != RDB_VERSION {
+        if !(RDB_MIN_VERSION..=RDB_MAX_VERSION).contains(&version_num) {
             return Err(RDBError::UnsupportedVersion);
         }
+        self.version = version_num;
+        self.header_read = true;
 
-        Ok(())
+        Ok(version_num)
     }
 
     /// Reads a length-encoded integer from the RDB file
@@ -204,7 +505,7 @@ impl<R: Read> RDBParser<R> {
     /// The encoding format uses the first two bits to determine the format:
     /// - 00: Next 6 bits represent length
     /// - 01: Next 14 bits represent length
-    /// - 10: Next 32 bits represent length
+    /// - 10: Next 32 or 64 bits represent length
     /// - 11: Special format (8, 16, or 32 bit integer)
     ///
     /// # Returns
@@ -213,22 +514,25 @@ impl<R: Read> RDBParser<R> {
     /// * `Err(RDBError::InvalidLength)` - If length encoding is invalid
     /// * `Err(RDBError::IoError)` - If reading fails
     pub fn read_length(&mut self) -> Result<usize, RDBError> {
+        let len = self.read_number()?;
+        self.check_length(len)
+    }
+
+    /// Reads a number in the length encoding, for values that are not
+    /// lengths, such as IDs and timestamps
+    fn read_number(&mut self) -> Result<u64, RDBError> {
         let mut byte = [0u8; 1];
         self.reader.read_exact(&mut byte)?;
         let first = byte[0];
 
         match first >> 6 {
-            0 => Ok((first & 0x3F) as usize),
+            0 => Ok((first & 0x3F) as u64),
             1 => {
                 let mut next = [0u8; 1];
                 self.reader.read_exact(&mut next)?;
-                Ok((((first & 0x3F) as usize) << 8) | (next[0] as usize))
-            },
-            2 => {
-                let mut buf = [0u8; 4];
-                self.reader.read_exact(&mut buf)?;
-                Ok(u32::from_be_bytes(buf) as usize)
+                Ok((((first & 0x3F) as u64) << 8) | (next[0] as u64))
             },
+            2 => self.read_long_number(first),
             3 => {
                 // Special format
                 match first & 0x3F {
@@ -236,19 +540,19 @@ impl<R: Read> RDBParser<R> {
                         // 8-bit integer
                         let mut buf = [0u8; 1];
                         self.reader.read_exact(&mut buf)?;
-                        Ok(buf[0] as usize)
+                        Ok(buf[0] as u64)
                     },
                     1 => {
                         // 16-bit integer
                         let mut buf = [0u8; 2];
                         self.reader.read_exact(&mut buf)?;
-                        Ok(u16::from_be_bytes(buf) as usize)
+                        Ok(u16::from_be_bytes(buf) as u64)
                     },
                     2 => {
                         // 32-bit integer
                         let mut buf = [0u8; 4];
                         self.reader.read_exact(&mut buf)?;
-                        Ok(u32::from_be_bytes(buf) as usize)
+                        Ok(u32::from_be_bytes(buf) as u64)
                     },
                     _ => Err(RDBError::InvalidLength),
                 }
@@ -257,17 +561,54 @@ impl<R: Read> RDBParser<R> {
         }
     }
 
+    /// Reads the 32-bit (0x80) or 64-bit (0x81) big-endian length that
+    /// follows `first`
+    fn read_long_length(&mut self, first: u8) -> Result<usize, RDBError> {
+        let len = self.read_long_number(first)?;
+        self.check_length(len)
+    }
+
+    /// Reads the 32-bit (0x80) or 64-bit (0x81) big-endian number that
+    /// follows `first`
+    fn read_long_number(&mut self, first: u8) -> Result<u64, RDBError> {
+        match first {
+            0x80 => {
+                let mut buf = [0u8; 4];
+                self.reader.read_exact(&mut buf)?;
+                Ok(u32::from_be_bytes(buf) as u64)
+            },
+            0x81 => {
+                let mut buf = [0u8; 8];
+                self.reader.read_exact(&mut buf)?;
+                Ok(u64::from_be_bytes(buf))
+            },
+            _ => Err(RDBError::InvalidLength),
+        }
+    }
+
+    /// Checks that `len` bytes, or elements of at least a byte each, can
+    /// still be read from the input
+    fn check_length(&self, len: u64) -> Result<usize, RDBError> {
+        let left = self.input_len.map(|total| total.saturating_sub(self.reader.offset));
+        if left.is_some_and(|left| len > left) {
+            return Err(RDBError::InvalidLength);
+        }
+        usize::try_from(len).map_err(|_| RDBError::InvalidLength)
+    }
+
     /// Reads a string from the RDB file
     ///
     /// Handles various string encodings:
     /// - Length-prefixed strings (using length encoding)
     /// - Integer-encoded strings (8, 16, or 32 bit)
-    /// - Compressed strings (TODO)
+    /// - LZF-compressed strings
     ///
     /// The first byte determines the encoding:
     /// - 0xC0: 8-bit integer
     /// - 0xC1: 16-bit integer
     /// - 0xC2: 32-bit integer
+    /// - 0xC3: LZF-compressed string, followed by the compressed and
+    ///   uncompressed lengths
     /// - Other: Length-prefixed string
     ///
     /// # Returns
@@ -282,27 +623,34 @@ impl<R: Read> RDBParser<R> {
 
         // Check for special encodings
         match first {
-            // Integer encodings
+            // Integer encodings, stored little-endian
             0xC0 => {
                 // 8-bit integer
                 let mut buf = [0u8; 1];
                 self.reader.read_exact(&mut buf)?;
                 let num = buf[0] as i8;
-                return Ok(num.to_string().into_bytes());
+                Ok(num.to_string().into_bytes())
             },
             0xC1 => {
                 // 16-bit integer
                 let mut buf = [0u8; 2];
                 self.reader.read_exact(&mut buf)?;
-                let num = i16::from_be_bytes(buf);
-                return Ok(num.to_string().into_bytes());
+                let num = i16::from_le_bytes(buf);
+                Ok(num.to_string().into_bytes())
             },
             0xC2 => {
                 // 32-bit integer
                 let mut buf = [0u8; 4];
                 self.reader.read_exact(&mut buf)?;
-                let num = i32::from_be_bytes(buf);
-                return Ok(num.to_string().into_bytes());
+                let num = i32::from_le_bytes(buf);
+                Ok(num.to_string().into_bytes())
+            },
+            0xC3 => {
+                let compressed_len = self.read_length()?;
+                // The uncompressed length may well exceed what is left
+                let len = usize::try_from(self.read_number()?).map_err(|_| RDBError::InvalidLength)?;
+                let compressed = self.read_bytes(compressed_len)?;
+                lzf::decompress(&compressed, len).ok_or(RDBError::InvalidEncoding)
             },
             _ => {
                 // Regular string length encoding
@@ -313,11 +661,7 @@ impl<R: Read> RDBParser<R> {
                         self.reader.read_exact(&mut next)?;
                         (((first & 0x3F) as usize) << 8) | (next[0] as usize)
                     },
-                    2 => {
-                        let mut buf = [0u8; 4];
-                        self.reader.read_exact(&mut buf)?;
-                        u32::from_be_bytes(buf) as usize
-                    },
+                    2 => self.read_long_length(first)?,
                     3 => {
                         // Special format
                         match first & 0x3F {
@@ -345,38 +689,38 @@ impl<R: Read> RDBParser<R> {
                     _ => return Err(RDBError::InvalidLength), // This should never happen
                 };
 
-                let mut buf = vec![0u8; len];
-                self.reader.read_exact(&mut buf)?;
-                Ok(buf)
+                self.read_bytes(len)
             }
         }
     }
 
-    /// Parses the next entry from the RDB file
-    ///
-    /// This method handles:
-    /// - Special opcodes (EOF, SELECT DB, etc.)
-    /// - Key-value pairs with their types
-    /// - Expiry times
-    /// - Auxiliary data
-    ///
-    /// Special opcodes are handled as follows:
-    /// - EOF (0xFF): End of file reached
-    /// - SELECTDB (0xFE): Switch to a different database
-    /// - EXPIRETIME/EXPIRETIME_MS (0xFD/0xFC): Set expiry time for next entry
-    /// - AUX (0xFA): Skip auxiliary information
-    /// - RESIZEDB (0xFB): Skip database size information
+    /// Reads exactly `len` bytes. Lengths come from the input and cannot be
+    /// trusted, so the buffer grows as bytes arrive instead of being sized
+    /// from `len` up front.
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>, RDBError> {
+        let mut buf = Vec::new();
+        (&mut self.reader).take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() < len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(buf)
+    }
+
+    /// Parses the next entry from the RDB file, skipping the other events.
+    /// The header is parsed first if [`parse_header`] was not called.
     ///
     /// # Returns
     ///
     /// * `Ok(Some(RDBEntry))` - Successfully parsed entry
     /// * `Ok(None)` - End of file reached
-    /// * `Err(RDBError)` - Parse error occurred
+    /// * `Err(RDBError::Context)` - Parse error, with where it occurred
+    ///
+    /// [`parse_header`]: RDBParser::parse_header
     ///
     /// # Example
     ///
     /// ```no_run
-    /// use crate::parser::rdb::RDBParser;
+    /// use redis_starter_rust::parser::rdb::RDBParser;
     /// use std::fs::File;
     ///
     /// let file = File::open("dump.rdb").unwrap();
@@ -388,91 +732,593 @@ impl<R: Read> RDBParser<R> {
     /// }
     /// ```
     pub fn parse_entry(&mut self) -> Result<Option<RDBEntry>, RDBError> {
-        let mut opcode = [0u8; 1];
-        match self.reader.read_exact(&mut opcode) {
-            Ok(_) => {},
-            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                // End of file reached
-                return Ok(None);
-            },
-            Err(e) => return Err(RDBError::IoError(e)),
+        while let Some(event) = self.next_event()? {
+            if let RdbEvent::Entry(entry) = event {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Parses the next event from the RDB file: the header, then one event
+    /// per opcode or key, up to the EOF trailer
+    ///
+    /// Opcodes are handled as follows:
+    /// - EOF (0xFF): End of file reached, the checksum trailer is verified
+    /// - SELECTDB (0xFE): Switch to a different database
+    /// - EXPIRETIME/EXPIRETIME_MS (0xFD/0xFC): Set expiry time for next entry
+    /// - AUX (0xFA): Auxiliary information
+    /// - RESIZEDB (0xFB): Database size information
+    /// - IDLE/FREQ (0xF9/0xF8): Set LRU/LFU metadata for next entry
+    /// - MODULE_AUX (0xF7): Module auxiliary data, which is skipped
+    /// - FUNCTION2 (0xF5): A function library, also kept in [`functions`]
+    /// - SLOT_INFO (0xF4): Cluster slot size information
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(RdbEvent))` - Successfully parsed event
+    /// * `Ok(None)` - The EOF event was already returned
+    /// * `Err(RDBError::Context)` - Parse error, with where it occurred
+    ///
+    /// [`functions`]: RDBParser::functions
+    pub fn next_event(&mut self) -> Result<Option<RdbEvent>, RDBError> {
+        if self.finished {
+            return Ok(None);
+        }
+        self.read_event().map(Some).map_err(|error| {
+            // Nothing after a parse error can be trusted
+            self.finished = true;
+            RDBError::Context {
+                offset: self.reader.offset,
+                opcode: self.opcode,
+                last_key: self.last_key.clone(),
+                source: Box::new(error),
+            }
+        })
+    }
+
+    /// Reads opcodes up to the next event. Expiry and LRU/LFU opcodes only
+    /// set metadata for the key that follows them.
+    fn read_event(&mut self) -> Result<RdbEvent, RDBError> {
+        if !self.header_read {
+            let version = self.parse_header()?;
+            return Ok(RdbEvent::Header { version });
+        }
+
+        loop {
+            // A file ending without the EOF opcode is truncated
+            let mut opcode = [0u8; 1];
+            self.reader.read_exact(&mut opcode)?;
+            self.opcode = Some(opcode[0]);
+
+            // Older files cannot contain types and opcodes added after them
+            if self.version < introduced_in(opcode[0]) {
+                return Err(RDBError::InvalidType);
+            }
+
+            let event = match opcode[0] {
+                RDB_OPCODE_EOF => {
+                    let checksum = self.verify_checksum()?;
+                    self.finished = true;
+                    RdbEvent::Eof { checksum }
+                },
+                RDB_OPCODE_SELECTDB => {
+                    let db = self.read_number()? as usize;
+                    self.current_db = db;
+                    RdbEvent::SelectDb { db }
+                },
+                RDB_OPCODE_EXPIRETIME | RDB_OPCODE_EXPIRETIME_MS => {
+                    // Expiry timestamps are little-endian: 4 bytes of seconds for
+                    // EXPIRETIME, 8 bytes of milliseconds for EXPIRETIME_MS
+                    let expiry = if opcode[0] == RDB_OPCODE_EXPIRETIME {
+                        let mut timestamp = [0u8; 4];
+                        self.reader.read_exact(&mut timestamp)?;
+                        SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(u32::from_le_bytes(timestamp) as u64)
+                    } else {
+                        let mut timestamp = [0u8; 8];
+                        self.reader.read_exact(&mut timestamp)?;
+                        SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(u64::from_le_bytes(timestamp))
+                    };
+                    self.pending.expiry = Some(expiry);
+                    continue;
+                },
+                RDB_OPCODE_IDLE => {
+                    self.pending.idle = Some(self.read_number()?);
+                    continue;
+                },
+                RDB_OPCODE_FREQ => {
+                    let mut freq = [0u8; 1];
+                    self.reader.read_exact(&mut freq)?;
+                    self.pending.freq = Some(freq[0]);
+                    continue;
+                },
+                RDB_OPCODE_SLOT_INFO => RdbEvent::SlotInfo {
+                    slot: self.read_number()? as usize,
+                    size: self.read_number()? as usize,
+                    expires: self.read_number()? as usize,
+                },
+                RDB_OPCODE_FUNCTION2 => {
+                    let code = self.read_string()?;
+                    self.functions.push(code.clone());
+                    RdbEvent::Function { code }
+                },
+                RDB_OPCODE_FUNCTION_PRE_GA => {
+                    // Only written by Redis 7.0 release candidates, not supported
+                    return Err(RDBError::InvalidType);
+                },
+                RDB_OPCODE_MODULE_AUX => {
+                    // The module id and when the data is loaded, then the data
+                    let module = module_name(self.read_number()?);
+                    let when_opcode = self.read_number()?;
+                    self.read_number()?;
+                    if when_opcode != RDB_MODULE_OPCODE_UINT {
+                        return Err(RDBError::InvalidEncoding);
+                    }
+                    self.skip_module_value()?;
+                    RdbEvent::ModuleAux { module }
+                },
+                RDB_OPCODE_AUX => RdbEvent::Aux {
+                    key: self.read_string()?,
+                    value: self.read_string()?,
+                },
+                RDB_OPCODE_RESIZEDB => RdbEvent::ResizeDb {
+                    size: self.read_number()? as usize,
+                    expires: self.read_number()? as usize,
+                },
+                value_type => {
+                    let key = self.read_string()?;
+                    self.last_key = Some(key.clone());
+                    let value = self.read_value(value_type)?;
+                    let metadata = std::mem::take(&mut self.pending);
+
+                    RdbEvent::Entry(RDBEntry {
+                        key,
+                        value,
+                        expiry: metadata.expiry,
+                        idle: metadata.idle,
+                        freq: metadata.freq,
+                        db: self.current_db,
+                    })
+                }
+            };
+            return Ok(event);
         }
+    }
 
-        match opcode[0] {
-            RDB_OPCODE_EOF => {
-                Ok(None)
+    /// Checks the CRC64 trailer that follows the EOF opcode against the
+    /// checksum of everything read before it, and returns the trailer
+    fn verify_checksum(&mut self) -> Result<Option<u64>, RDBError> {
+        if self.version < RDB_CHECKSUM_VERSION {
+            return Ok(None);
+        }
+        let actual = self.reader.checksum;
+        let mut trailer = [0u8; 8];
+        self.reader.read_exact(&mut trailer)?;
+        let expected = u64::from_le_bytes(trailer);
+        if expected != actual && !(expected == 0 && self.skip_zero_checksum) {
+            return Err(RDBError::ChecksumMismatch { expected, actual });
+        }
+        Ok(Some(expected))
+    }
+
+    /// Reads a value preceded by its type but without a key, which is the
+    /// layout of DUMP payloads
+    pub fn read_object(&mut self) -> Result<RDBValue, RDBError> {
+        let mut value_type = [0u8; 1];
+        self.reader.read_exact(&mut value_type)?;
+        self.read_value(value_type[0])
+    }
+
+    /// Reads a value of the given RDB type, decoding compact encodings
+    fn read_value(&mut self, value_type: u8) -> Result<RDBValue, RDBError> {
+        let value = match value_type {
+            RDB_TYPE_STRING => RDBValue::String(self.read_string()?),
+            RDB_TYPE_LIST => RDBValue::List(self.read_strings()?),
+            RDB_TYPE_SET => RDBValue::Set(self.read_strings()?),
+            RDB_TYPE_ZSET | RDB_TYPE_ZSET_2 => {
+                let len = self.read_length()?;
+                let mut members = Vec::new();
+                for _ in 0..len {
+                    let member = self.read_string()?;
+                    let score = if value_type == RDB_TYPE_ZSET {
+                        self.read_string_double()?
+                    } else {
+                        self.read_binary_double()?
+                    };
+                    members.push((member, score));
+                }
+                RDBValue::SortedSet(members)
+            },
+            RDB_TYPE_HASH => {
+                let len = self.read_length()?;
+                let mut fields = Vec::new();
+                for _ in 0..len {
+                    fields.push((self.read_string()?, self.read_string()?));
+                }
+                RDBValue::Hash(fields)
             },
-            RDB_OPCODE_SELECTDB => {
-                let db = self.read_length()?;
-                self.current_db = db as u8;
-                self.parse_entry()
+            RDB_TYPE_HASH_ZIPMAP => {
+                let blob = self.read_string()?;
+                RDBValue::Hash(encodings::zipmap(&blob).ok_or(RDBError::InvalidEncoding)?)
             },
-            RDB_OPCODE_EXPIRETIME | RDB_OPCODE_EXPIRETIME_MS => {
-                let mut timestamp = [0u8; 8];
-                self.reader.read_exact(&mut timestamp)?;
-                let expiry = if opcode[0] == RDB_OPCODE_EXPIRETIME {
-                    SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(u64::from_be_bytes(timestamp))
+            RDB_TYPE_LIST_ZIPLIST => RDBValue::List(self.read_blob(encodings::ziplist)?),
+            RDB_TYPE_SET_INTSET => RDBValue::Set(self.read_blob(encodings::intset)?),
+            RDB_TYPE_SET_LISTPACK => RDBValue::Set(self.read_blob(encodings::listpack)?),
+            RDB_TYPE_ZSET_ZIPLIST | RDB_TYPE_ZSET_LISTPACK => {
+                let elements = if value_type == RDB_TYPE_ZSET_ZIPLIST {
+                    self.read_blob(encodings::ziplist)?
                 } else {
-                    SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(u64::from_be_bytes(timestamp))
+                    self.read_blob(encodings::listpack)?
                 };
-                
-                let entry = self.parse_entry()?;
-                match entry {
-                    Some(mut e) => {
-                        e.expiry = Some(expiry);
-                        Ok(Some(e))
-                    },
-                    None => Ok(None),
+                let members = encodings::pairs(elements)
+                    .ok_or(RDBError::InvalidEncoding)?
+                    .into_iter()
+                    .map(|(member, score)| Ok((member, parse_score(&score)?)))
+                    .collect::<Result<_, RDBError>>()?;
+                RDBValue::SortedSet(members)
+            },
+            RDB_TYPE_HASH_ZIPLIST | RDB_TYPE_HASH_LISTPACK => {
+                let elements = if value_type == RDB_TYPE_HASH_ZIPLIST {
+                    self.read_blob(encodings::ziplist)?
+                } else {
+                    self.read_blob(encodings::listpack)?
+                };
+                RDBValue::Hash(encodings::pairs(elements).ok_or(RDBError::InvalidEncoding)?)
+            },
+            RDB_TYPE_LIST_QUICKLIST => {
+                let nodes = self.read_length()?;
+                let mut items = Vec::new();
+                for _ in 0..nodes {
+                    items.extend(self.read_blob(encodings::ziplist)?);
                 }
+                RDBValue::List(items)
             },
-            RDB_OPCODE_AUX => {
-                // Skip auxiliary fields (metadata)
-                let _key = self.read_string()?;
-                let _value = self.read_string()?;
-                // Print AUX fields in readable format for debugging
-                println!("AUX fields: key={:?}, value={:?}", String::from_utf8_lossy(&_key), String::from_utf8_lossy(&_value));
-                // Continue parsing the next entry
-                self.parse_entry()
+            RDB_TYPE_LIST_QUICKLIST_2 => {
+                let nodes = self.read_length()?;
+                let mut items = Vec::new();
+                for _ in 0..nodes {
+                    match self.read_number()? {
+                        QUICKLIST_NODE_PLAIN => items.push(self.read_string()?),
+                        QUICKLIST_NODE_PACKED => items.extend(self.read_blob(encodings::listpack)?),
+                        _ => return Err(RDBError::InvalidEncoding),
+                    }
+                }
+                RDBValue::List(items)
             },
-            RDB_OPCODE_RESIZEDB => {
-                // Skip database size info
-                let _db_size = self.read_length()?;
-                let _expires_size = self.read_length()?;
-                // Continue parsing the next entry
-                self.parse_entry()
+            RDB_TYPE_HASH_METADATA | RDB_TYPE_HASH_METADATA_PRE_GA => {
+                // Field expiries are saved relative to the smallest one, plus
+                // one so that 0 can mean "no expiry". Release candidates saved
+                // them as absolute times instead.
+                let min_expiry = if value_type == RDB_TYPE_HASH_METADATA {
+                    self.read_millis()?
+                } else {
+                    1
+                };
+                let now = unix_millis();
+                let len = self.read_length()?;
+                let mut fields = Vec::new();
+                for _ in 0..len {
+                    let ttl = self.read_number()?;
+                    let field = self.read_string()?;
+                    let value = self.read_string()?;
+                    let expired = ttl != 0 && ttl.saturating_add(min_expiry).saturating_sub(1) <= now;
+                    if !expired {
+                        fields.push((field, value));
+                    }
+                }
+                RDBValue::Hash(fields)
             },
-            value_type => {
-                let key = self.read_string()?;
-                
-                let value = match value_type {
-                    RDB_TYPE_STRING => {
-                        let data = self.read_string()?;
-                        RDBValue::String(data)
-                    },
-                    // For all other types, convert them to string representation
-                    RDB_TYPE_LIST => {
-                        RDBValue::String(Vec::new())
-                    },
-                    RDB_TYPE_SET => {
-                        RDBValue::String(Vec::new())
-                    },
-                    RDB_TYPE_ZSET => {
-                        RDBValue::String(Vec::new())
-                    },
-                    RDB_TYPE_HASH => {
-                        RDBValue::String(Vec::new())
-                    },
-                    _ => {
-                        return Err(RDBError::InvalidType);
+            RDB_TYPE_HASH_LISTPACK_EX | RDB_TYPE_HASH_LISTPACK_EX_PRE_GA => {
+                if value_type == RDB_TYPE_HASH_LISTPACK_EX {
+                    self.read_millis()?;
+                }
+                // Triplets of field, value and absolute expiry, 0 for none
+                let elements = self.read_blob(encodings::listpack)?;
+                let now = unix_millis();
+                let mut fields = Vec::new();
+                let mut iter = elements.into_iter();
+                while let Some(field) = iter.next() {
+                    let (value, expiry) = iter.next().zip(iter.next()).ok_or(RDBError::InvalidEncoding)?;
+                    let expiry = std::str::from_utf8(&expiry)
+                        .ok()
+                        .and_then(|expiry| expiry.parse::<u64>().ok())
+                        .ok_or(RDBError::InvalidEncoding)?;
+                    if expiry == 0 || expiry > now {
+                        fields.push((field, value));
                     }
+                }
+                RDBValue::Hash(fields)
+            },
+            RDB_TYPE_STREAM_LISTPACKS | RDB_TYPE_STREAM_LISTPACKS_2 | RDB_TYPE_STREAM_LISTPACKS_3 => {
+                RDBValue::Stream(Box::new(self.read_stream(value_type)?))
+            },
+            RDB_TYPE_MODULE_2 => {
+                let name = module_name(self.read_number()?);
+                self.skip_module_value()?;
+                RDBValue::Module(name)
+            },
+            RDB_TYPE_MODULE_PRE_GA => {
+                let name = module_name(self.read_number()?);
+                return Err(RDBError::UnskippableModule(name));
+            },
+            _ => return Err(RDBError::InvalidType),
+        };
+        Ok(value)
+    }
+
+    /// Reads a stream: its listpacks keyed by master ID, its metadata, then
+    /// its consumer groups with their PEL and consumers
+    fn read_stream(&mut self, value_type: u8) -> Result<Stream, RDBError> {
+        let nodes = self.read_length()?;
+        let mut entries = Vec::new();
+        for _ in 0..nodes {
+            let master = StreamId::from_be_bytes(&self.read_string()?).ok_or(RDBError::InvalidEncoding)?;
+            let elements = self.read_blob(encodings::listpack)?;
+            entries.extend(stream::node_entries(master, elements).ok_or(RDBError::InvalidEncoding)?);
+        }
+
+        let length = self.read_number()?;
+        let last_id = self.read_stream_id()?;
+        let (first_id, max_deleted_id, entries_added) = if value_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+            (self.read_stream_id()?, self.read_stream_id()?, self.read_number()?)
+        } else {
+            // Not saved before Redis 7.0, derive them as Redis does
+            let first_id = entries.first().map(|entry| entry.id).unwrap_or_default();
+            (first_id, StreamId::default(), length)
+        };
+
+        let mut groups = Vec::new();
+        for _ in 0..self.read_length()? {
+            let name = self.read_string()?;
+            let group_last_id = self.read_stream_id()?;
+            // u64::MAX stands for an unknown count
+            let entries_read = if value_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+                Some(self.read_number()?).filter(|&read| read != u64::MAX)
+            } else {
+                None
+            };
+
+            let mut pending = Vec::new();
+            for _ in 0..self.read_length()? {
+                pending.push(PendingEntry {
+                    id: self.read_raw_stream_id()?,
+                    delivery_time: self.read_millis()?,
+                    delivery_count: self.read_number()?,
+                });
+            }
+
+            let mut consumers = Vec::new();
+            for _ in 0..self.read_length()? {
+                let name = self.read_string()?;
+                let seen_time = self.read_millis()?;
+                let active_time = if value_type >= RDB_TYPE_STREAM_LISTPACKS_3 {
+                    self.read_millis()?
+                } else {
+                    seen_time
                 };
+                let pending = (0..self.read_length()?)
+                    .map(|_| self.read_raw_stream_id())
+                    .collect::<Result<_, _>>()?;
+                consumers.push(Consumer { name, seen_time, active_time, pending });
+            }
+
+            groups.push(ConsumerGroup { name, last_id: group_last_id, entries_read, pending, consumers });
+        }
+
+        Ok(Stream { entries, length, last_id, first_id, max_deleted_id, entries_added, groups })
+    }
 
-                Ok(Some(RDBEntry {
-                    key,
-                    value,
-                    expiry: None,
-                }))
+    /// Reads a stream ID saved as two lengths
+    fn read_stream_id(&mut self) -> Result<StreamId, RDBError> {
+        Ok(StreamId { ms: self.read_number()?, seq: self.read_number()? })
+    }
+
+    /// Reads a stream ID saved as 16 raw big-endian bytes
+    fn read_raw_stream_id(&mut self) -> Result<StreamId, RDBError> {
+        let mut buf = [0u8; 16];
+        self.reader.read_exact(&mut buf)?;
+        Ok(StreamId::from_be_bytes(&buf).unwrap_or_default())
+    }
+
+    /// Skips a module value made of opcode-tagged fields, up to its EOF
+    fn skip_module_value(&mut self) -> Result<(), RDBError> {
+        loop {
+            match self.read_number()? {
+                RDB_MODULE_OPCODE_EOF => return Ok(()),
+                RDB_MODULE_OPCODE_SINT | RDB_MODULE_OPCODE_UINT => {
+                    self.read_number()?;
+                },
+                RDB_MODULE_OPCODE_FLOAT => {
+                    self.reader.read_exact(&mut [0u8; 4])?;
+                },
+                RDB_MODULE_OPCODE_DOUBLE => {
+                    self.reader.read_exact(&mut [0u8; 8])?;
+                },
+                RDB_MODULE_OPCODE_STRING => {
+                    self.read_string()?;
+                },
+                _ => return Err(RDBError::InvalidEncoding),
+            }
+        }
+    }
+
+    /// Reads a length followed by that many strings
+    fn read_strings(&mut self) -> Result<Vec<Vec<u8>>, RDBError> {
+        let len = self.read_length()?;
+        (0..len).map(|_| self.read_string()).collect()
+    }
+
+    /// Reads a string holding a compact encoding and decodes it with `decode`
+    fn read_blob(&mut self, decode: fn(&[u8]) -> Option<Vec<Vec<u8>>>) -> Result<Vec<Vec<u8>>, RDBError> {
+        let blob = self.read_string()?;
+        decode(&blob).ok_or(RDBError::InvalidEncoding)
+    }
+
+    /// Reads a double saved as a length-prefixed string, with the lengths
+    /// 253, 254 and 255 standing for NaN, +inf and -inf
+    fn read_string_double(&mut self) -> Result<f64, RDBError> {
+        let mut len = [0u8; 1];
+        self.reader.read_exact(&mut len)?;
+        match len[0] {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => {
+                let mut buf = vec![0u8; len as usize];
+                self.reader.read_exact(&mut buf)?;
+                parse_score(&buf)
+            }
+        }
+    }
+
+    /// Reads a little-endian binary double
+    fn read_binary_double(&mut self) -> Result<f64, RDBError> {
+        let mut buf = [0u8; 8];
+        self.reader.read_exact(&mut buf)?;
+        Ok(f64::from_le_bytes(buf))
+    }
+
+    /// Reads a little-endian unix time in milliseconds
+    fn read_millis(&mut self) -> Result<u64, RDBError> {
+        let mut buf = [0u8; 8];
+        self.reader.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+}
+
+/// Iterates over the events of the file, see [`RDBParser::next_event`].
+/// The iterator ends after the EOF event or the first error.
+impl<R: Read> Iterator for RDBParser<R> {
+    type Item = Result<RdbEvent, RDBError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_event().transpose()
+    }
+}
+
+/// The RDB version that introduced a value type or opcode
+fn introduced_in(type_or_opcode: u8) -> u32 {
+    match type_or_opcode {
+        // Redis 2.4 added the compact encodings of small collections
+        RDB_TYPE_HASH_ZIPMAP
+        | RDB_TYPE_LIST_ZIPLIST
+        | RDB_TYPE_SET_INTSET
+        | RDB_TYPE_ZSET_ZIPLIST => 2,
+        // Redis 2.6 added millisecond expiries and ziplist hashes
+        RDB_OPCODE_EXPIRETIME_MS | RDB_TYPE_HASH_ZIPLIST => 3,
+        // Redis 3.2 added quicklists and the AUX and RESIZEDB opcodes
+        RDB_TYPE_LIST_QUICKLIST | RDB_OPCODE_AUX | RDB_OPCODE_RESIZEDB => 7,
+        // Redis 4.0 added binary sorted set scores and modules
+        RDB_TYPE_ZSET_2 | RDB_TYPE_MODULE_PRE_GA => 8,
+        // Redis 5.0 added streams, module framing and eviction metadata
+        RDB_TYPE_STREAM_LISTPACKS
+        | RDB_TYPE_MODULE_2
+        | RDB_OPCODE_MODULE_AUX
+        | RDB_OPCODE_IDLE
+        | RDB_OPCODE_FREQ => 9,
+        // Redis 7.0 moved from ziplists to listpacks, saved more stream
+        // metadata and added functions
+        RDB_TYPE_HASH_LISTPACK
+        | RDB_TYPE_ZSET_LISTPACK
+        | RDB_TYPE_LIST_QUICKLIST_2
+        | RDB_TYPE_STREAM_LISTPACKS_2
+        | RDB_OPCODE_FUNCTION_PRE_GA
+        | RDB_OPCODE_FUNCTION2 => 10,
+        // Redis 7.2 added listpack sets and consumer active times
+        RDB_TYPE_SET_LISTPACK | RDB_TYPE_STREAM_LISTPACKS_3 => 11,
+        // Redis 7.4 added hash field expiries
+        RDB_TYPE_HASH_METADATA_PRE_GA
+        | RDB_TYPE_HASH_LISTPACK_EX_PRE_GA
+        | RDB_TYPE_HASH_METADATA
+        | RDB_TYPE_HASH_LISTPACK_EX
+        | RDB_OPCODE_SLOT_INFO => 12,
+        _ => RDB_MIN_VERSION,
+    }
+}
+
+/// Name of a module type from its id: 9 characters of 6 bits each, above
+/// 10 bits of encoding version
+fn module_name(id: u64) -> String {
+    (0..9)
+        .map(|i| MODULE_NAME_CHARSET[((id >> (10 + 6 * (8 - i))) & 63) as usize] as char)
+        .collect()
+}
+
+/// Parses a sorted set score stored as text, as Redis' strtod would
+fn parse_score(bytes: &[u8]) -> Result<f64, RDBError> {
+    let text = std::str::from_utf8(bytes).map_err(|_| RDBError::InvalidEncoding)?;
+    match text {
+        "inf" | "+inf" => Ok(f64::INFINITY),
+        "-inf" => Ok(f64::NEG_INFINITY),
+        _ => text.parse().map_err(|_| RDBError::InvalidEncoding),
+    }
+}
+
+/// Current wall-clock time in unix milliseconds
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An RDB file of `body` after the header, ending with a zero checksum
+    fn rdb_file(body: &[u8]) -> Vec<u8> {
+        let mut file = b"REDIS0011".to_vec();
+        file.extend_from_slice(body);
+        file.push(RDB_OPCODE_EOF);
+        file.extend_from_slice(&[0; 8]);
+        file
+    }
+
+    fn parser(file: &[u8]) -> RDBParser<&[u8]> {
+        let mut parser = RDBParser::new(file);
+        parser.set_input_len(file.len() as u64);
+        parser
+    }
+
+    #[test]
+    fn rejects_lengths_past_the_end_of_the_input() {
+        let mut body = vec![RDB_TYPE_STRING, 0x01, b'k', 0x81];
+        body.extend_from_slice(&(1u64 << 44).to_be_bytes());
+        body.extend_from_slice(b"value");
+        let file = rdb_file(&body);
+
+        match parser(&file).parse_entry() {
+            Err(RDBError::Context { offset, opcode, last_key, source }) => {
+                assert!(matches!(*source, RDBError::InvalidLength));
+                assert_eq!(offset, 9 + 3 + 9);
+                assert_eq!(opcode, Some(RDB_TYPE_STRING));
+                assert_eq!(last_key.as_deref(), Some(&b"k"[..]));
             }
+            other => panic!("expected an invalid length, got {:?}", other),
         }
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn rejects_counts_past_the_end_of_the_input() {
+        let mut body = vec![RDB_TYPE_LIST, 0x01, b'k', 0x80];
+        body.extend_from_slice(&u32::MAX.to_be_bytes());
+        let file = rdb_file(&body);
+        let error = parser(&file).parse_entry().unwrap_err();
+        assert!(matches!(error.root(), RDBError::InvalidLength));
+    }
+
+    #[test]
+    fn reads_numbers_larger_than_the_input() {
+        // An LRU idle time is a number, not a length of what follows
+        let mut body = vec![RDB_OPCODE_IDLE, 0x81];
+        body.extend_from_slice(&(1u64 << 40).to_be_bytes());
+        body.extend_from_slice(&[RDB_TYPE_STRING, 0x01, b'k', 0x01, b'v']);
+        let file = rdb_file(&body);
+
+        let entry = parser(&file).parse_entry().unwrap().unwrap();
+        assert_eq!(entry.key, b"k");
+        assert_eq!(entry.idle, Some(1 << 40));
+        assert!(matches!(entry.value, RDBValue::String(value) if value == b"v"));
+    }
+}
@@ -0,0 +1,82 @@
+//! Async front end for [`RDBParser`]
+//!
+//! Parsing itself stays synchronous: the parser runs on Tokio's blocking
+//! thread pool, reading through a bridge that waits on the `AsyncRead`, and
+//! hands its events back over a bounded channel. Callers get the same events
+//! as from the sync parser without ever blocking the runtime.
+
+use std::io::{self, BufReader, Read};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use super::rdb::{RDBEntry, RDBError, RDBParser, RdbEvent};
+
+/// How many events are parsed ahead of the caller
+const EVENT_BUFFER: usize = 64;
+
+/// Parser for Redis RDB files read from a [`tokio::io::AsyncRead`]
+///
+/// # Example
+///
+/// ```no_run
+/// use redis_starter_rust::parser::rdb_async::AsyncRDBParser;
+///
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// let file = tokio::fs::File::open("dump.rdb").await?;
+/// let mut parser = AsyncRDBParser::new(file);
+/// while let Some(event) = parser.next_event().await? {
+///     println!("{:?}", event);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct AsyncRDBParser {
+    events: mpsc::Receiver<Result<RdbEvent, RDBError>>,
+}
+
+impl AsyncRDBParser {
+    /// Starts parsing `reader` in the background, with the defaults of
+    /// [`RDBParser::new`]. Must be called from within a Tokio runtime.
+    /// Parsing stops when the parser is dropped.
+    pub fn new<R: AsyncRead + Unpin + Send + 'static>(reader: R) -> Self {
+        let handle = Handle::current();
+        let (sender, events) = mpsc::channel(EVENT_BUFFER);
+        tokio::task::spawn_blocking(move || {
+            let reader = BufReader::new(BlockingReader { inner: reader, handle });
+            for event in RDBParser::new(reader) {
+                if sender.blocking_send(event).is_err() {
+                    break;
+                }
+            }
+        });
+        AsyncRDBParser { events }
+    }
+
+    /// Parses the next event, see [`RDBParser::next_event`]
+    pub async fn next_event(&mut self) -> Result<Option<RdbEvent>, RDBError> {
+        self.events.recv().await.transpose()
+    }
+
+    /// Parses the next entry, skipping the other events, see
+    /// [`RDBParser::parse_entry`]
+    pub async fn parse_entry(&mut self) -> Result<Option<RDBEntry>, RDBError> {
+        while let Some(event) = self.next_event().await? {
+            if let RdbEvent::Entry(entry) = event {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Blocking `Read` over an `AsyncRead`, for use off the runtime's workers
+struct BlockingReader<R> {
+    inner: R,
+    handle: Handle,
+}
+
+impl<R: AsyncRead + Unpin> Read for BlockingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.handle.block_on(self.inner.read(buf))
+    }
+}
@@ -0,0 +1,159 @@
+//! Redis Streams as saved in RDB files
+//!
+//! A stream is saved as a radix tree of listpacks, each keyed by a master
+//! entry ID, followed by its metadata and its consumer groups. Entry IDs
+//! inside a listpack are deltas from the master ID, and entries that have
+//! the same fields as the master entry omit them.
+
+use std::fmt;
+
+/// Entry flag: the entry was deleted and is only kept until the listpack is
+/// compacted
+pub(crate) const FLAG_DELETED: i64 = 1;
+/// Entry flag: the entry has the same fields as the master entry
+pub(crate) const FLAG_SAMEFIELDS: i64 = 2;
+
+/// ID of a stream entry, `<ms>-<seq>`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    /// Decodes the 16 byte big-endian form used for radix tree keys and PELs
+    pub fn from_be_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 16 {
+            return None;
+        }
+        let ms = u64::from_be_bytes(bytes[..8].try_into().ok()?);
+        let seq = u64::from_be_bytes(bytes[8..].try_into().ok()?);
+        Some(StreamId { ms, seq })
+    }
+
+    /// Encodes the ID in the 16 byte big-endian form
+    pub fn to_be_bytes(self) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        bytes[..8].copy_from_slice(&self.ms.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.seq.to_be_bytes());
+        bytes
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// A stream entry and its field/value pairs
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamEntry {
+    pub id: StreamId,
+    pub fields: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// An entry delivered to a consumer group but not acknowledged yet
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingEntry {
+    pub id: StreamId,
+    /// Unix time in milliseconds of the last delivery
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+/// A consumer of a consumer group
+#[derive(Debug, Clone, PartialEq)]
+pub struct Consumer {
+    pub name: Vec<u8>,
+    /// Unix time in milliseconds the consumer was last seen
+    pub seen_time: u64,
+    /// Unix time in milliseconds the consumer last read successfully
+    pub active_time: u64,
+    /// IDs of the group's pending entries owned by this consumer
+    pub pending: Vec<StreamId>,
+}
+
+/// A consumer group
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsumerGroup {
+    pub name: Vec<u8>,
+    /// ID of the last entry delivered to the group
+    pub last_id: StreamId,
+    /// Number of entries read by the group, when known
+    pub entries_read: Option<u64>,
+    /// The group's pending entries list (PEL)
+    pub pending: Vec<PendingEntry>,
+    pub consumers: Vec<Consumer>,
+}
+
+/// A stream with its entries, metadata and consumer groups
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stream {
+    /// Entries in ID order, deleted entries left out
+    pub entries: Vec<StreamEntry>,
+    /// Number of entries, as saved
+    pub length: u64,
+    /// ID of the last entry ever added
+    pub last_id: StreamId,
+    /// ID of the first entry
+    pub first_id: StreamId,
+    /// Largest ID ever deleted
+    pub max_deleted_id: StreamId,
+    /// Number of entries ever added
+    pub entries_added: u64,
+    pub groups: Vec<ConsumerGroup>,
+}
+
+/// Decodes the elements of one stream listpack into its entries, leaving
+/// out deleted ones. `master` is the ID the listpack is keyed by.
+///
+/// Layout: the master entry `<count><deleted><num-fields><field>...<0>`,
+/// then for each entry `<flags><ms-diff><seq-diff>` followed by
+/// `<value>...` with the SAMEFIELDS flag or `<num-fields><field><value>...`
+/// without it, and `<lp-count>`.
+pub fn node_entries(master: StreamId, elements: Vec<Vec<u8>>) -> Option<Vec<StreamEntry>> {
+    let mut iter = elements.into_iter();
+    next_int(&mut iter)?;
+    next_int(&mut iter)?;
+    let num_fields = next_int(&mut iter)?;
+    let master_fields = (0..num_fields).map(|_| iter.next()).collect::<Option<Vec<_>>>()?;
+    if next_int(&mut iter)? != 0 {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    while let Some(flags) = iter.next() {
+        let flags = int(&flags)?;
+        let id = StreamId {
+            ms: master.ms.wrapping_add(next_int(&mut iter)? as u64),
+            seq: master.seq.wrapping_add(next_int(&mut iter)? as u64),
+        };
+        let fields = if flags & FLAG_SAMEFIELDS != 0 {
+            master_fields
+                .iter()
+                .map(|field| Some((field.clone(), iter.next()?)))
+                .collect::<Option<Vec<_>>>()?
+        } else {
+            let num_fields = next_int(&mut iter)?;
+            (0..num_fields)
+                .map(|_| Some((iter.next()?, iter.next()?)))
+                .collect::<Option<Vec<_>>>()?
+        };
+        // lp-count, only needed to walk the listpack backwards
+        next_int(&mut iter)?;
+        if flags & FLAG_DELETED == 0 {
+            entries.push(StreamEntry { id, fields });
+        }
+    }
+    Some(entries)
+}
+
+fn next_int(iter: &mut impl Iterator<Item = Vec<u8>>) -> Option<i64> {
+    int(&iter.next()?)
+}
+
+/// Parses an integer element, which listpack decoding returns as text
+fn int(element: &[u8]) -> Option<i64> {
+    std::str::from_utf8(element).ok()?.parse().ok()
+}
@@ -0,0 +1,679 @@
+//! Append-only file (AOF) persistence
+//!
+//! Every command that changes the dataset is appended to the AOF in RESP
+//! form, the way clients send it, so replaying it in order rebuilds the
+//! dataset. Like in Redis 7, the AOF is a directory of files listed by a
+//! [`Manifest`]: a base file holding a snapshot of the dataset, in RDB
+//! format or as commands, followed by incremental files with the commands
+//! logged since. Rewriting the AOF replaces them all with a new base file,
+//! so the AOF does not grow without bound.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use bytes::{Buf, BytesMut};
+use thiserror::Error;
+use tokio::sync::Mutex;
+use crate::command::{Command, Session};
+use crate::config::AppendFsync;
+use crate::error::{RedisError, Result};
+use crate::parser::{Parser, RESPOutput};
+use crate::store::datatype::DataType;
+use crate::store::redis::{DatabaseSnapshot, Store};
+use super::manifest::{AofFileType, AofInfo, Manifest};
+use super::rdb::{self, SaveOptions};
+
+/// How often `appendfsync everysec` syncs the file
+const FSYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where the append-only files are kept and how they are synced
+#[derive(Debug, Clone)]
+pub struct AofOptions {
+    /// Directory of the files, `appenddirname` in `dir`
+    pub dir: PathBuf,
+    /// Base name of the files and of their manifest (`appendfilename`)
+    pub filename: String,
+    pub fsync: AppendFsync,
+}
+
+/// State of the AOF, reported by INFO
+#[derive(Debug, Clone)]
+pub struct AofStatus {
+    /// Whether the last write to the file succeeded
+    pub last_write_ok: bool,
+    /// When the running rewrite started
+    pub current_rewrite_start: Option<Instant>,
+    /// Whether the last rewrite succeeded
+    pub last_rewrite_ok: bool,
+    /// How long the last rewrite took
+    pub last_rewrite_time: Option<Duration>,
+    /// Size in bytes of all the files
+    pub current_size: u64,
+    /// Size in bytes of all the files after the last rewrite, or on startup
+    pub base_size: u64,
+}
+
+/// An append-only file that writes are logged to
+pub struct Aof {
+    options: AofOptions,
+    /// Shared with background rewrites
+    state: Arc<Mutex<AofState>>,
+}
+
+struct AofState {
+    manifest: Manifest,
+    /// The last incremental file, which writes are appended to. Shared with
+    /// background fsyncs.
+    file: Arc<File>,
+    /// Size of the incremental file, up to the last complete write
+    size: u64,
+    /// Database the logged commands apply to, `None` until a SELECT is
+    /// logged to the incremental file
+    db: Option<usize>,
+    /// Commands not in the file yet, because writing them failed
+    pending: Vec<u8>,
+    /// Why the last write failed, cleared once a retry succeeds
+    write_error: Option<String>,
+    /// Whether anything was written since the last fsync
+    unsynced: bool,
+    last_fsync: Instant,
+    status: AofStatus,
+}
+
+impl Aof {
+    /// Opens the append-only files to log writes to, appending to the last
+    /// incremental file of the manifest.
+    ///
+    /// A new AOF starts with a base file holding the keys already in
+    /// `store`, so that the AOF alone holds the whole dataset. History files
+    /// left by an interrupted rewrite are deleted.
+    pub async fn open(store: &Store, options: AofOptions) -> io::Result<Self> {
+        let dir = options.dir.as_path();
+        fs::create_dir_all(dir)?;
+        let mut manifest = Manifest::load(dir, &options.filename)?.unwrap_or_default();
+        let mut changed = false;
+
+        if manifest.base.is_none() && manifest.incrs.is_empty() {
+            let snapshot = store.snapshot().await;
+            let (rdb, compression) = base_format(store, &snapshot).await;
+            let base = manifest.next_base(&options.filename, rdb);
+            write_base(&snapshot, dir, &base, rdb, compression)?;
+            println!("Creating AOF base file {} on server start", base.name);
+            manifest.base = Some(base);
+            changed = true;
+        }
+        let incr = match manifest.incrs.last() {
+            Some(incr) => incr.clone(),
+            None => {
+                let incr = manifest.next_incr(&options.filename);
+                println!("Creating AOF incr file {} on server start", incr.name);
+                changed = true;
+                incr
+            }
+        };
+        for history in manifest.history.drain(..) {
+            let _ = fs::remove_file(dir.join(&history.name));
+            changed = true;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(dir.join(&incr.name))?;
+        if changed {
+            manifest.save(dir, &options.filename)?;
+        }
+        let size = file.metadata()?.len();
+        let current_size = manifest.base.iter()
+            .chain(&manifest.incrs)
+            .map(|info| fs::metadata(dir.join(&info.name)).map_or(0, |metadata| metadata.len()))
+            .sum();
+
+        Ok(Aof {
+            options,
+            state: Arc::new(Mutex::new(AofState {
+                manifest,
+                file: Arc::new(file),
+                size,
+                db: None,
+                pending: Vec::new(),
+                write_error: None,
+                unsynced: false,
+                last_fsync: Instant::now(),
+                status: AofStatus {
+                    last_write_ok: true,
+                    current_rewrite_start: None,
+                    last_rewrite_ok: true,
+                    last_rewrite_time: None,
+                    current_size,
+                    base_size: current_size,
+                },
+            })),
+        })
+    }
+
+    pub async fn status(&self) -> AofStatus {
+        let state = self.state.lock().await;
+        AofStatus { last_write_ok: state.write_error.is_none(), ..state.status.clone() }
+    }
+
+    /// Executes `command` and logs it if it changed the dataset.
+    ///
+    /// Writes are executed one at a time while the file is locked, so they
+    /// are logged in the order they were applied. While the file cannot be
+    /// written, writes are refused instead of being applied without being
+    /// logged.
+    pub async fn execute(&self, command: &Command, store: &Store, session: &mut Session) -> Result<Vec<u8>> {
+        let args = match command.aof_args() {
+            Some(args) => args,
+            None => return command.execute(store, session).await,
+        };
+
+        let mut state = self.state.lock().await;
+        if state.write_error.is_some() {
+            self.write_pending(&mut state);
+            if let Some(error) = &state.write_error {
+                return Err(RedisError::AofWrite(error.clone()));
+            }
+        }
+
+        let db = session.db;
+        let (reply, wrote) = Store::track_writes(command.execute(store, session)).await;
+        let reply = reply?;
+        if wrote {
+            if state.db != Some(db) {
+                state.pending.extend(encode(vec!["SELECT".into(), db.to_string().into()]));
+                state.db = Some(db);
+            }
+            state.pending.extend(encode(args));
+            self.write_pending(&mut state);
+        }
+        Ok(reply)
+    }
+
+    /// Writes the pending commands to the file, and syncs it right away
+    /// under `appendfsync always`.
+    ///
+    /// A failed write is undone so the file never ends in a partial command,
+    /// and is retried with the next write or by the cron.
+    fn write_pending(&self, state: &mut AofState) {
+        if state.pending.is_empty() {
+            return;
+        }
+
+        let fsync = self.options.fsync;
+        let mut result = (&*state.file).write_all(&state.pending);
+        if result.is_ok() && fsync == AppendFsync::Always {
+            result = state.file.sync_data();
+        }
+        match result {
+            Ok(()) => {
+                let written = state.pending.len() as u64;
+                state.size += written;
+                state.status.current_size += written;
+                state.pending.clear();
+                state.unsynced = fsync == AppendFsync::EverySec;
+                if state.write_error.take().is_some() {
+                    println!("AOF write error looks solved, can write again.");
+                }
+            }
+            Err(e) => {
+                // Clients were promised every write is on disk before they
+                // get a reply, which can no longer be kept
+                if fsync == AppendFsync::Always {
+                    eprintln!("Can't recover from AOF write error when the AOF fsync policy is 'always': {}. Exiting...", e);
+                    std::process::exit(1);
+                }
+                if state.write_error.is_none() {
+                    eprintln!("Error writing to the AOF file: {}", e);
+                }
+                let _ = state.file.set_len(state.size);
+                state.write_error = Some(e.to_string());
+            }
+        }
+    }
+
+    /// Periodic work, run by the server cron: retries failed writes and,
+    /// under `appendfsync everysec`, syncs the file in the background once a
+    /// second
+    pub async fn tick(&self) {
+        let mut state = self.state.lock().await;
+        if state.write_error.is_some() {
+            self.write_pending(&mut state);
+        }
+        if !state.unsynced || state.last_fsync.elapsed() < FSYNC_INTERVAL {
+            return;
+        }
+
+        state.unsynced = false;
+        state.last_fsync = Instant::now();
+        sync_in_background(Arc::clone(&state.file));
+    }
+
+    /// Writes what is pending and syncs the file, before shutting down
+    pub async fn fsync(&self) -> io::Result<()> {
+        let mut state = self.state.lock().await;
+        self.write_pending(&mut state);
+        if let Some(error) = &state.write_error {
+            return Err(io::Error::other(error.clone()));
+        }
+        state.unsynced = false;
+        state.last_fsync = Instant::now();
+        state.file.sync_all()
+    }
+
+    /// Starts rewriting the AOF in the background and returns right away
+    /// (`BGREWRITEAOF`).
+    ///
+    /// Writes switch to a new incremental file, and the dataset as it was
+    /// at the switch is written to a new base file, the way Redis forks.
+    /// Once the base file is complete, the manifest is updated to list only
+    /// the new base and incremental files, and the old files are deleted.
+    /// Until then, the manifest lists the old files followed by the new
+    /// incremental file, so a crash during the rewrite loses nothing.
+    pub async fn bgrewrite(&self, store: &Store) -> Result<()> {
+        let (dir, filename) = (self.options.dir.as_path(), self.options.filename.as_str());
+        let mut state = self.state.lock().await;
+        if state.status.current_rewrite_start.is_some() {
+            return Err(RedisError::Message("Background append only file rewriting already in progress".to_string()));
+        }
+        if let Some(error) = &state.write_error {
+            return Err(RedisError::AofWrite(error.clone()));
+        }
+
+        let mut manifest = state.manifest.clone();
+        let incr = manifest.next_incr(filename);
+        let file = OpenOptions::new().create(true).append(true).open(dir.join(&incr.name))?;
+        manifest.save(dir, filename)?;
+        if state.unsynced {
+            sync_in_background(Arc::clone(&state.file));
+            state.unsynced = false;
+        }
+        state.manifest = manifest;
+        state.file = Arc::new(file);
+        state.size = 0;
+        state.db = None;
+        state.status.current_rewrite_start = Some(Instant::now());
+
+        let snapshot = store.snapshot().await;
+        let (rdb, compression) = base_format(store, &snapshot).await;
+        let base = state.manifest.next_base(filename, rdb);
+        drop(state);
+        println!("Background append only file rewriting started");
+
+        let state = Arc::clone(&self.state);
+        let options = self.options.clone();
+        tokio::spawn(async move {
+            let base_dir = options.dir.clone();
+            let base_info = base.clone();
+            let result = tokio::task::spawn_blocking(move || write_base(&snapshot, &base_dir, &base_info, rdb, compression))
+                .await
+                .unwrap_or_else(|e| Err(io::Error::other(e)));
+
+            let mut state = state.lock().await;
+            let result = result.and_then(|size| install_base(&mut state, &options, base, size));
+            let start = state.status.current_rewrite_start.take();
+            state.status.last_rewrite_time = start.map(|start| start.elapsed());
+            state.status.last_rewrite_ok = result.is_ok();
+            match result {
+                Ok(()) => println!("Background AOF rewrite finished successfully"),
+                Err(e) => eprintln!("Background AOF rewrite failed: {}", e),
+            }
+        });
+        Ok(())
+    }
+
+    /// Starts a rewrite once the AOF has grown by `percentage` since the
+    /// last one and is at least `min_size` bytes, the check Redis' serverCron
+    /// runs for `auto-aof-rewrite-percentage` and `auto-aof-rewrite-min-size`
+    pub async fn rewrite_if_due(&self, store: &Store, percentage: u64, min_size: u64) {
+        if percentage == 0 {
+            return;
+        }
+        let status = self.status().await;
+        if status.current_rewrite_start.is_some() || status.current_size < min_size {
+            return;
+        }
+
+        let growth = (status.current_size.saturating_mul(100) / status.base_size.max(1)).saturating_sub(100);
+        if growth >= percentage {
+            println!("Starting automatic rewriting of AOF on {}% growth", growth);
+            if let Err(e) = self.bgrewrite(store).await {
+                eprintln!("Background AOF rewrite failed to start: {}", e);
+            }
+        }
+    }
+}
+
+/// Moves an existing single-file AOF, as written before the AOF became a
+/// directory, into `options.dir` as the base file of a new manifest. This
+/// is how Redis 7 upgrades an AOF written by older versions.
+pub fn upgrade(legacy: &Path, options: &AofOptions) -> io::Result<Manifest> {
+    fs::create_dir_all(&options.dir)?;
+    let base = AofInfo {
+        name: options.filename.clone(),
+        seq: 1,
+        file_type: AofFileType::Base,
+    };
+    fs::rename(legacy, options.dir.join(&base.name))?;
+    let manifest = Manifest { base: Some(base), ..Manifest::default() };
+    manifest.save(&options.dir, &options.filename)?;
+    Ok(manifest)
+}
+
+/// Picks the format of a new base file from the live configuration:
+/// whether to write RDB (`aof-use-rdb-preamble`), and whether to compress it.
+///
+/// Only strings can be written as commands, since those are the only writes
+/// this server replays, so datasets holding other types are always written
+/// as RDB.
+async fn base_format(store: &Store, snapshot: &[DatabaseSnapshot]) -> (bool, bool) {
+    let preamble = store.get_config("aof-use-rdb-preamble").await.is_none_or(|value| value != "no");
+    let strings_only = snapshot.iter()
+        .flatten()
+        .all(|(_, value, _)| matches!(value, DataType::String(_)));
+    let compression = SaveOptions::from_config(store).await.compression;
+    (preamble || !strings_only, compression)
+}
+
+/// Writes `snapshot` to the base file `base` in `dir`, through a temporary
+/// file that is renamed once complete and synced. Returns the file's size.
+fn write_base(snapshot: &[DatabaseSnapshot], dir: &Path, base: &AofInfo, rdb: bool, compression: bool) -> io::Result<u64> {
+    let temp = dir.join(format!("temp-rewriteaof-bg-{}.aof", std::process::id()));
+    let path = dir.join(&base.name);
+    let result = if rdb {
+        rdb::write_rdb(snapshot, &temp, compression, true)
+    } else {
+        write_commands(snapshot, &temp)
+    };
+    let result = result
+        .and_then(|_| fs::rename(&temp, &path))
+        .and_then(|_| fs::metadata(&path))
+        .map(|metadata| metadata.len());
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Writes `snapshot` as the commands that recreate it: for each database a
+/// SELECT, then a SET for each key and a PEXPIREAT for each expiry
+fn write_commands(snapshot: &[DatabaseSnapshot], path: &Path) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    for (db, entries) in snapshot.iter().enumerate() {
+        if entries.is_empty() {
+            continue;
+        }
+        out.write_all(&encode(vec!["SELECT".into(), db.to_string().into()]))?;
+        for (key, value, expiry) in entries {
+            // `base_format` only picks this format for datasets of strings
+            let DataType::String(value) = value else { continue };
+            out.write_all(&encode(vec!["SET".into(), key.clone(), value.clone()]))?;
+            if let Some(at) = expiry {
+                out.write_all(&encode(vec!["PEXPIREAT".into(), key.clone(), at.to_string().into()]))?;
+            }
+        }
+    }
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()
+}
+
+/// Makes the base file written by a rewrite the AOF's base: the manifest
+/// then lists it and the incremental file started with the rewrite, and
+/// the files it replaces are deleted
+fn install_base(state: &mut AofState, options: &AofOptions, base: AofInfo, base_size: u64) -> io::Result<()> {
+    let dir = options.dir.as_path();
+    let mut manifest = state.manifest.clone();
+    let incr = manifest.incrs.pop();
+    let replaced = manifest.base.replace(base.clone())
+        .into_iter()
+        .chain(manifest.incrs.drain(..))
+        .collect::<Vec<_>>();
+    manifest.incrs.extend(incr);
+    if let Err(e) = manifest.save(dir, &options.filename) {
+        let _ = fs::remove_file(dir.join(&base.name));
+        return Err(e);
+    }
+
+    state.manifest = manifest;
+    for info in replaced {
+        let _ = fs::remove_file(dir.join(&info.name));
+    }
+    state.status.current_size = base_size + state.size;
+    state.status.base_size = state.status.current_size;
+    Ok(())
+}
+
+/// Syncs `file` on a blocking thread, so writers do not wait for the disk
+fn sync_in_background(file: Arc<File>) {
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = file.sync_data() {
+            eprintln!("Error syncing the AOF file: {}", e);
+        }
+    });
+}
+
+/// Encodes a logged command as a RESP array of bulk strings, which is also
+/// how writes are streamed to replicas
+pub fn encode(args: Vec<Vec<u8>>) -> Vec<u8> {
+    RESPOutput::Array(args.into_iter().map(RESPOutput::BulkBytes).collect()).encode()
+}
+
+/// Outcome of replaying an append-only file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Replay {
+    /// Number of commands replayed
+    pub commands: usize,
+    /// Set when the file ends in the middle of a command, to the offset
+    /// where that command starts. This is what a crash during a write leaves
+    /// behind, and truncating the file there drops the partial command.
+    pub truncated_at: Option<u64>,
+}
+
+/// Why the commands of an append-only file could not all be read
+#[derive(Error, Debug)]
+pub enum AofReadError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    /// The file ends in the middle of the command starting at `offset`
+    #[error("Unexpected end of file in the command at offset {offset}")]
+    Truncated { offset: u64 },
+
+    /// The bytes at `offset` are not a RESP value
+    #[error("Bad file format at offset {offset}: {reason}")]
+    Corrupt { offset: u64, reason: String },
+}
+
+/// Reads the commands of an append-only file one at a time, with the same
+/// RESP [`Parser`] connections use
+pub struct CommandReader<R: Read> {
+    reader: R,
+    buffer: BytesMut,
+    chunk: Vec<u8>,
+    /// Offset in the file of the next command
+    offset: u64,
+}
+
+impl<R: Read> CommandReader<R> {
+    /// Reads commands from `reader`, which starts at `offset` in the file,
+    /// after any RDB preamble
+    pub fn new(reader: R, offset: u64) -> Self {
+        CommandReader {
+            reader,
+            buffer: BytesMut::with_capacity(64 * 1024),
+            chunk: vec![0u8; 64 * 1024],
+            offset,
+        }
+    }
+
+    /// Offset in the file of the next command, which is also where the last
+    /// command read ends
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the next command with its offset, or `None` at the end of
+    /// the file
+    pub fn next_command(&mut self) -> std::result::Result<Option<(u64, RESPOutput)>, AofReadError> {
+        loop {
+            match Parser::parse(&self.buffer) {
+                Ok((output, rest)) => {
+                    let consumed = self.buffer.len() - rest.len();
+                    self.buffer.advance(consumed);
+                    let offset = self.offset;
+                    self.offset += consumed as u64;
+                    return Ok(Some((offset, output)));
+                }
+                Err(e) if e.is_incomplete() => {}
+                Err(e) => return Err(AofReadError::Corrupt { offset: self.offset, reason: e.to_string() }),
+            }
+
+            let size = self.reader.read(&mut self.chunk)?;
+            if size == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err(AofReadError::Truncated { offset: self.offset });
+            }
+            self.buffer.extend_from_slice(&self.chunk[..size]);
+        }
+    }
+}
+
+/// Replays the commands read from `reader` against `store`, the way
+/// clients' commands are executed. `offset` is where `reader` starts in the
+/// file, after any RDB preamble.
+///
+/// The log only holds commands that succeeded, so a command that cannot be
+/// parsed or fails means the file is corrupted, and is reported with its
+/// offset. A partial command at the end is not an error, see
+/// [`Replay::truncated_at`].
+pub async fn replay<R: Read>(store: &Store, reader: R, offset: u64) -> Result<Replay> {
+    let mut commands = CommandReader::new(reader, offset);
+    let mut session = Session::default();
+    let mut count = 0;
+
+    loop {
+        let (offset, output) = match commands.next_command() {
+            Ok(Some(command)) => command,
+            Ok(None) => break,
+            Err(AofReadError::Truncated { offset }) => {
+                return Ok(Replay { commands: count, truncated_at: Some(offset) });
+            }
+            Err(AofReadError::Corrupt { offset, reason }) => return Err(bad_format(offset, &reason)),
+            Err(AofReadError::Io(e)) => return Err(e.into()),
+        };
+
+        let result = match Command::from_resp(output) {
+            Ok(command) => command.execute(store, &mut session).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            return Err(bad_format(offset, &e.to_string()));
+        }
+        count += 1;
+    }
+    Ok(Replay { commands: count, truncated_at: None })
+}
+
+fn bad_format(offset: u64, reason: &str) -> RedisError {
+    RedisError::Message(format!("Bad file format reading the append only file at offset {}: {}", offset, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(words: &[&str]) -> Vec<u8> {
+        encode(words.iter().map(|word| word.as_bytes().to_vec()).collect())
+    }
+
+    async fn replay_bytes(store: &Store, bytes: &[u8]) -> Result<Replay> {
+        replay(store, io::Cursor::new(bytes), 0).await
+    }
+
+    #[test]
+    fn encodes_commands_as_resp_arrays() {
+        assert_eq!(command(&["SET", "k", "v"]), b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n");
+    }
+
+    #[tokio::test]
+    async fn replays_every_command() {
+        let store = Store::new(2).await.unwrap();
+        let mut log = command(&["SET", "a", "1"]);
+        log.extend(command(&["SELECT", "1"]));
+        log.extend(command(&["SET", "b", "2"]));
+
+        let replay = replay_bytes(&store, &log).await.unwrap();
+        assert_eq!(replay, Replay { commands: 3, truncated_at: None });
+        assert!(store.db(0).get(b"a").await.unwrap().is_some());
+        assert!(store.db(1).get(b"b").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn stops_at_a_truncated_last_command() {
+        let complete = [command(&["SET", "a", "1"]), command(&["SET", "b", "2"])].concat();
+        let last = command(&["SET", "c", "3"]);
+        for cut in 1..last.len() {
+            let store = Store::new(1).await.unwrap();
+            let log = [&complete[..], &last[..cut]].concat();
+            let replay = replay_bytes(&store, &log).await.unwrap();
+            assert_eq!(replay, Replay { commands: 2, truncated_at: Some(complete.len() as u64) }, "cut at {}", cut);
+            assert!(store.db(0).get(b"b").await.unwrap().is_some());
+            assert!(store.db(0).get(b"c").await.unwrap().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_corruption_in_the_middle_with_its_offset() {
+        let first = command(&["SET", "a", "1"]);
+        let offset = first.len();
+        let corruptions: [&[u8]; 3] = [
+            b"!garbage\r\n",
+            b"*2\r\n$3\r\nSET\r\n$x\r\n",
+            &command(&["NOSUCHCOMMAND", "a"]),
+        ];
+        for corruption in corruptions {
+            let store = Store::new(1).await.unwrap();
+            let log = [&first[..], corruption, &command(&["SET", "b", "2"])].concat();
+            let error = replay_bytes(&store, &log).await.unwrap_err().to_string();
+            let expected = format!("Bad file format reading the append only file at offset {}:", offset);
+            assert!(error.starts_with(&expected), "{}", error);
+            assert!(store.db(0).get(b"b").await.unwrap().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_commands_after_a_preamble_offset() {
+        let store = Store::new(1).await.unwrap();
+        let log = [command(&["SET", "a", "1"]), b"*1\r\n".to_vec()].concat();
+        let replay = replay(&store, io::Cursor::new(&log), 100).await.unwrap();
+        assert_eq!(replay.truncated_at, Some(100 + log.len() as u64 - 4));
+    }
+
+    #[tokio::test]
+    async fn logs_relative_expiries_as_a_single_pxat() {
+        let dir = std::env::temp_dir().join(format!("aof-pxat-test-{}", std::process::id()));
+        let options = AofOptions { dir: dir.clone(), filename: "appendonly.aof".to_string(), fsync: AppendFsync::Always };
+        let store = Store::new(1).await.unwrap();
+        store.set_aof(Aof::open(&store, options).await.unwrap());
+
+        let words = RESPOutput::Array(["SET", "k", "v", "EX", "10"].iter()
+            .map(|word| RESPOutput::BulkString(word.to_string()))
+            .collect());
+        let set = Command::from_resp(words).unwrap();
+        store.replication().execute(&set, &store, &mut Session::default()).await.unwrap();
+        let logged = fs::read(dir.join("appendonly.aof.1.incr.aof"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut reader = CommandReader::new(io::Cursor::new(logged.unwrap()), 0);
+        let mut commands = Vec::new();
+        while let Some((_, command)) = reader.next_command().unwrap() {
+            commands.push(command.encode());
+        }
+        let at = store.db(0).expiry(b"k").await.unwrap().flatten().unwrap();
+        assert_eq!(commands, [
+            command(&["SELECT", "0"]),
+            command(&["SET", "k", "v", "PXAT", &at.to_string()]),
+        ]);
+    }
+}
@@ -0,0 +1,182 @@
+//! Listpack encoding, as used for stream nodes in RDB files
+//!
+//! A listpack is `<total-bytes:u32><num-elements:u16><entry>...<0xFF>`, where
+//! each entry is `<encoding><data><backlen>` and `backlen` is the size of the
+//! encoding and data, so the list can be walked from either end. This is
+//! the inverse of [`encodings::listpack`](crate::parser::encodings::listpack).
+
+/// Builds a listpack one element at a time
+#[derive(Debug, Default)]
+pub struct Listpack {
+    entries: Vec<u8>,
+    len: usize,
+}
+
+impl Listpack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a string, stored as an integer when it is the canonical form
+    /// of one, as Redis does
+    pub fn push(&mut self, element: &[u8]) {
+        let integer = std::str::from_utf8(element)
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok().filter(|value| value.to_string() == s));
+        if let Some(value) = integer {
+            return self.push_int(value);
+        }
+
+        let len = element.len();
+        let mut entry = if len < 1 << 6 {
+            vec![0x80 | len as u8]
+        } else if len < 1 << 12 {
+            vec![0xE0 | (len >> 8) as u8, len as u8]
+        } else {
+            let mut entry = vec![0xF0];
+            entry.extend_from_slice(&(len as u32).to_le_bytes());
+            entry
+        };
+        entry.extend_from_slice(element);
+        self.push_entry(entry);
+    }
+
+    /// Appends an integer using the smallest encoding that holds it
+    pub fn push_int(&mut self, value: i64) {
+        let entry = if (0..128).contains(&value) {
+            vec![value as u8]
+        } else if (-4096..4096).contains(&value) {
+            let value = value as u16 & 0x1FFF;
+            vec![0xC0 | (value >> 8) as u8, value as u8]
+        } else if let Ok(value) = i16::try_from(value) {
+            let [a, b] = value.to_le_bytes();
+            vec![0xF1, a, b]
+        } else if (-(1 << 23)..1 << 23).contains(&value) {
+            let [a, b, c, ..] = value.to_le_bytes();
+            vec![0xF2, a, b, c]
+        } else if let Ok(value) = i32::try_from(value) {
+            let mut entry = vec![0xF3];
+            entry.extend_from_slice(&value.to_le_bytes());
+            entry
+        } else {
+            let mut entry = vec![0xF4];
+            entry.extend_from_slice(&value.to_le_bytes());
+            entry
+        };
+        self.push_entry(entry);
+    }
+
+    /// Returns the encoded listpack
+    pub fn finish(self) -> Vec<u8> {
+        let total = 4 + 2 + self.entries.len() + 1;
+        // The element count saturates, and is then recounted by readers
+        let count = u16::try_from(self.len).unwrap_or(u16::MAX);
+        let mut out = Vec::with_capacity(total);
+        out.extend_from_slice(&(total as u32).to_le_bytes());
+        out.extend_from_slice(&count.to_le_bytes());
+        out.extend_from_slice(&self.entries);
+        out.push(0xFF);
+        out
+    }
+
+    /// Appends an encoded entry followed by its back length
+    fn push_entry(&mut self, entry: Vec<u8>) {
+        let backlen = backlen(entry.len());
+        self.entries.extend_from_slice(&entry);
+        self.entries.extend_from_slice(&backlen);
+        self.len += 1;
+    }
+}
+
+/// Encodes the size of an entry in 7-bit groups, most significant first,
+/// with the high bit set on all groups but the first
+fn backlen(len: usize) -> Vec<u8> {
+    let groups = match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    };
+    (0..groups)
+        .rev()
+        .map(|group| {
+            let bits = ((len >> (7 * group)) & 127) as u8;
+            if group == groups - 1 { bits } else { bits | 128 }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::encodings;
+
+    fn entries(elements: &[&[u8]]) -> Vec<u8> {
+        let mut listpack = Listpack::new();
+        for element in elements {
+            listpack.push(element);
+        }
+        listpack.finish()
+    }
+
+    #[test]
+    fn encodes_like_redis() {
+        // `RPUSH list a 1 -1` in Redis 7
+        assert_eq!(entries(&[b"a", b"1", b"-1"]), b"\x0f\x00\x00\x00\x03\x00\x81a\x02\x01\x01\xdf\xff\x02\xff");
+        assert_eq!(Listpack::new().finish(), b"\x07\x00\x00\x00\x00\x00\xff");
+    }
+
+    #[test]
+    fn picks_the_smallest_integer_encoding() {
+        let cases: [(i64, &[u8]); 8] = [
+            (127, b"\x7f\x01"),
+            (200, b"\xc0\xc8\x02"),
+            (-4096, b"\xd0\x00\x02"),
+            (4096, b"\xf1\x00\x10\x03"),
+            (-32769, b"\xf2\xff\x7f\xff\x04"),
+            (100_000, b"\xf2\xa0\x86\x01\x04"),
+            (1 << 30, b"\xf3\x00\x00\x00\x40\x05"),
+            (3_000_000_000, b"\xf4\x00\x5e\xd0\xb2\x00\x00\x00\x00\x09"),
+        ];
+        for (value, entry) in cases {
+            let mut listpack = Listpack::new();
+            listpack.push_int(value);
+            assert_eq!(&listpack.finish()[6..], [entry, b"\xff"].concat(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn keeps_non_canonical_integers_as_strings() {
+        let encoded = entries(&[b"01", b"+1", b"1 "]);
+        assert_eq!(encoded[6], 0x82);
+        assert_eq!(encodings::listpack(&encoded), Some(vec![b"01".to_vec(), b"+1".to_vec(), b"1 ".to_vec()]));
+    }
+
+    #[test]
+    fn encodes_string_lengths_and_back_lengths() {
+        let medium = vec![b'x'; 64];
+        let encoded = entries(&[&medium]);
+        assert_eq!(encoded[6..8], [0xE0, 64]);
+        // The back length of a 66-byte entry fits in one byte
+        assert_eq!(encoded[encoded.len() - 2], 66);
+
+        let long = vec![b'x'; 4096];
+        let encoded = entries(&[&long]);
+        assert_eq!(encoded[6..11], [0xF0, 0x00, 0x10, 0x00, 0x00]);
+        // 4101 bytes take two 7-bit groups, most significant first
+        assert_eq!(encoded[encoded.len() - 3..encoded.len() - 1], [0x20, 0x85]);
+    }
+
+    #[test]
+    fn round_trips_through_the_decoder() {
+        let big = vec![b'y'; 20_000];
+        let elements: Vec<&[u8]> = vec![
+            b"", b"0", b"-1", b"127", b"128", b"4095", b"-4096", b"32767", b"-32768", b"8388607",
+            b"-8388608", b"2147483647", b"-2147483648", b"9223372036854775807", b"-9223372036854775808",
+            b"\xff\x00binary", &big,
+        ];
+        let expected: Vec<Vec<u8>> = elements.iter().map(|element| element.to_vec()).collect();
+        assert_eq!(encodings::listpack(&entries(&elements)), Some(expected));
+    }
+}
@@ -0,0 +1,259 @@
+//! LZF compression, as used for strings in RDB files
+//!
+//! This implements the format of liblzf, which Redis bundles. Compressed data
+//! is a sequence of chunks, each starting with a control byte:
+//!
+//! - `000LLLLL`: a run of `L + 1` literal bytes follows
+//! - `LLLooooo oooooooo`: a back reference of `L + 2` bytes starting
+//!   `o + 1` bytes before the current output position
+//! - `111ooooo LLLLLLLL oooooooo`: the same with a length of `L + 9`
+
+/// log2 of the number of hash table slots the compressor uses
+const HLOG: u32 = 14;
+/// Longest run of literals a single control byte can describe
+const MAX_LIT: usize = 1 << 5;
+/// Farthest a back reference can point
+const MAX_OFF: usize = 1 << 13;
+/// Longest match a back reference can describe
+const MAX_REF: usize = (1 << 8) + (1 << 3);
+
+/// Compresses `input`. Returns `None` when the result would not be at least
+/// 4 bytes shorter than the input, the point below which Redis stores
+/// strings as they are.
+pub fn compress(input: &[u8]) -> Option<Vec<u8>> {
+    let len = input.len();
+    if len <= 4 {
+        return None;
+    }
+    let limit = len - 4;
+
+    let mut out = Vec::with_capacity(limit);
+    // Positions plus one of the last occurrence of each 3-byte hash, 0 if none
+    let mut table = vec![0usize; 1 << HLOG];
+    let mut literals = 0;
+    let mut i = 0;
+
+    while i + 2 < len {
+        let slot = hash(&input[i..i + 3]);
+        let candidate = table[slot];
+        table[slot] = i + 1;
+
+        if candidate > 0 {
+            let reference = candidate - 1;
+            let offset = i - reference - 1;
+            if offset < MAX_OFF && input[reference..reference + 3] == input[i..i + 3] {
+                let max = (len - i).min(MAX_REF);
+                let mut matched = 3;
+                while matched < max && input[reference + matched] == input[i + matched] {
+                    matched += 1;
+                }
+
+                flush_literals(&mut out, &input[i - literals..i]);
+                literals = 0;
+                let encoded = matched - 2;
+                if encoded < 7 {
+                    out.push(((encoded << 5) | (offset >> 8)) as u8);
+                } else {
+                    out.push(((7 << 5) | (offset >> 8)) as u8);
+                    out.push((encoded - 7) as u8);
+                }
+                out.push(offset as u8);
+                if out.len() > limit {
+                    return None;
+                }
+
+                i += matched;
+                continue;
+            }
+        }
+
+        literals += 1;
+        i += 1;
+    }
+
+    flush_literals(&mut out, &input[i - literals..]);
+    if out.len() > limit {
+        return None;
+    }
+    Some(out)
+}
+
+/// Decompresses `input`, which must expand to exactly `len` bytes. Returns
+/// `None` if the data is corrupt.
+pub fn decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    // `len` is read from the same untrusted source as `input`, so the output
+    // only reserves what the input could plausibly expand to and grows from
+    // there
+    let mut out = Vec::with_capacity(len.min(input.len().saturating_mul(2)));
+    let mut i = 0;
+
+    while i < input.len() {
+        let control = input[i] as usize;
+        i += 1;
+
+        if control < MAX_LIT {
+            let run = control + 1;
+            let literal = input.get(i..i + run)?;
+            if out.len() + run > len {
+                return None;
+            }
+            out.extend_from_slice(literal);
+            i += run;
+        } else {
+            let mut matched = control >> 5;
+            if matched == 7 {
+                matched += *input.get(i)? as usize;
+                i += 1;
+            }
+            matched += 2;
+            let offset = ((control & 0x1f) << 8) + *input.get(i)? as usize + 1;
+            i += 1;
+
+            if offset > out.len() || out.len() + matched > len {
+                return None;
+            }
+            // The reference may overlap the bytes being written, so copy
+            // one byte at a time
+            let start = out.len() - offset;
+            for k in 0..matched {
+                out.push(out[start + k]);
+            }
+        }
+    }
+
+    if out.len() != len {
+        return None;
+    }
+    Some(out)
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let v = ((bytes[0] as u32) << 16) | ((bytes[1] as u32) << 8) | bytes[2] as u32;
+    (v.wrapping_mul(2654435761) >> (32 - HLOG)) as usize
+}
+
+/// Writes `literals` as runs of at most 32 bytes, each behind its control byte
+fn flush_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for run in literals.chunks(MAX_LIT) {
+        out.push((run.len() - 1) as u8);
+        out.extend_from_slice(run);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic bytes that do not compress
+    fn noise(len: usize, mut seed: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect()
+    }
+
+    /// The back references in `compressed`, as (length, distance) pairs
+    fn references(compressed: &[u8]) -> Vec<(usize, usize)> {
+        let mut refs = Vec::new();
+        let mut i = 0;
+        while i < compressed.len() {
+            let control = compressed[i] as usize;
+            if control < MAX_LIT {
+                i += control + 2;
+                continue;
+            }
+            let mut len = control >> 5;
+            i += 1;
+            if len == 7 {
+                len += compressed[i] as usize;
+                i += 1;
+            }
+            refs.push((len + 2, ((control & 0x1f) << 8) + compressed[i] as usize + 1));
+            i += 1;
+        }
+        refs
+    }
+
+    fn round_trip(input: &[u8]) -> Vec<u8> {
+        let compressed = compress(input).expect("input should compress");
+        assert_eq!(decompress(&compressed, input.len()).as_deref(), Some(input));
+        compressed
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip(b"hello hello hello hello hello");
+        round_trip(&vec![0; 100_000]);
+        round_trip(&b"0123456789".repeat(1000));
+        let mut mixed = noise(5000, 1);
+        mixed.extend_from_slice(&mixed.clone());
+        round_trip(&mixed);
+    }
+
+    #[test]
+    fn decompresses_redis_output() {
+        // `SET key aaa...a` with 61 a's saves `c3 05 3d 00 61 e0 33 00` in
+        // the RDB file: the LZF marker, the compressed and original lengths,
+        // then one literal and a back reference of 60 bytes
+        let compressed = [0x00, 0x61, 0xe0, 0x33, 0x00];
+        assert_eq!(decompress(&compressed, 61), Some(vec![b'a'; 61]));
+        assert_eq!(compress(&[b'a'; 61]), Some(compressed.to_vec()));
+    }
+
+    #[test]
+    fn splits_long_matches() {
+        let compressed = round_trip(&[b'a'; 300]);
+        assert_eq!(references(&compressed), [(MAX_REF, 1), (35, 264)]);
+    }
+
+    #[test]
+    fn references_reach_back_8k() {
+        let block = noise(64, 2);
+        for (distance, reachable) in [(MAX_OFF, true), (MAX_OFF + 1, false)] {
+            let mut input = block.clone();
+            input.resize(distance, 0);
+            input.extend_from_slice(&block);
+            let compressed = round_trip(&input);
+            let far = references(&compressed).iter().any(|&(_, d)| d == distance);
+            assert_eq!(far, reachable, "distance {}", distance);
+        }
+    }
+
+    #[test]
+    fn splits_literal_runs() {
+        let mut input: Vec<u8> = (1..=40).collect();
+        input.extend_from_slice(&[0; 100]);
+        let compressed = round_trip(&input);
+        assert_eq!(compressed[0] as usize, MAX_LIT - 1);
+        assert_eq!(compressed[1 + MAX_LIT], 40 - MAX_LIT as u8);
+    }
+
+    #[test]
+    fn only_keeps_output_4_bytes_shorter() {
+        assert_eq!(compress(b"aaaa"), None);
+        // A literal and a back reference, 4 bytes either way
+        assert_eq!(compress(&[b'a'; 7]), None);
+        assert_eq!(compress(&[b'a'; 8]).map(|out| out.len()), Some(4));
+        assert_eq!(compress(&noise(1000, 3)), None);
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        // Literal run past the end of the input
+        assert_eq!(decompress(&[0x04, b'a', b'b'], 5), None);
+        // Back reference before the start of the output
+        assert_eq!(decompress(&[0x20, 0x00], 3), None);
+        assert_eq!(decompress(&[0x00, b'a', 0x20, 0x01], 4), None);
+        // Back reference missing its offset or length byte
+        assert_eq!(decompress(&[0x00, b'a', 0x20], 4), None);
+        assert_eq!(decompress(&[0x00, b'a', 0xe0], 12), None);
+        // Output longer or shorter than announced
+        assert_eq!(decompress(&[0x00, 0x61, 0xe0, 0x33, 0x00], 60), None);
+        assert_eq!(decompress(&[0x00, 0x61, 0xe0, 0x33, 0x00], 62), None);
+        assert_eq!(decompress(&[], 0), Some(Vec::new()));
+    }
+}
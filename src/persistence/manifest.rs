@@ -0,0 +1,384 @@
+//! The manifest of a multi-part append-only file, in the format of Redis 7
+//!
+//! Since Redis 7 the AOF is a directory (`appenddirname`) holding a base
+//! file, a snapshot of the dataset written by the last rewrite, and
+//! incremental files with the commands logged since, in order. The manifest
+//! lists them, one per line:
+//!
+//! ```text
+//! file appendonly.aof.1.base.rdb seq 1 type b
+//! file appendonly.aof.1.incr.aof seq 1 type i
+//! ```
+//!
+//! Files of type `h` are history, left over from a rewrite and waiting to
+//! be deleted. File names that contain spaces or quotes are quoted.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// The role of a file listed in the manifest
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AofFileType {
+    /// Snapshot of the dataset written by the last rewrite
+    Base,
+    /// Commands logged after the base was written
+    Incr,
+    /// Obsolete file, to be deleted
+    History,
+}
+
+impl AofFileType {
+    fn code(self) -> char {
+        match self {
+            AofFileType::Base => 'b',
+            AofFileType::Incr => 'i',
+            AofFileType::History => 'h',
+        }
+    }
+
+    fn from_code(code: &str) -> Option<Self> {
+        match code {
+            "b" => Some(AofFileType::Base),
+            "i" => Some(AofFileType::Incr),
+            "h" => Some(AofFileType::History),
+            _ => None,
+        }
+    }
+}
+
+/// A file listed in the manifest
+#[derive(Debug, Clone, PartialEq)]
+pub struct AofInfo {
+    pub name: String,
+    pub seq: u64,
+    pub file_type: AofFileType,
+}
+
+impl fmt::Display for AofInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "file {} seq {} type {}", quote(&self.name), self.seq, self.file_type.code())
+    }
+}
+
+/// The files making up an append-only file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Manifest {
+    pub base: Option<AofInfo>,
+    /// Incremental files, in the order they are replayed
+    pub incrs: Vec<AofInfo>,
+    pub history: Vec<AofInfo>,
+}
+
+impl Manifest {
+    /// Parses the manifest, rejecting it the way Redis does: every line
+    /// must name a file, its sequence number and its type, there can be one
+    /// base file at most, and incremental files must be in sequence order
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut manifest = Manifest::default();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.starts_with('#') {
+                continue;
+            }
+
+            let args = split_args(line)
+                .filter(|args| args.len() >= 6 && args.len() % 2 == 0)
+                .ok_or_else(|| invalid("Invalid AOF manifest file format"))?;
+            let (mut name, mut seq, mut file_type) = (None, None, None);
+            for pair in args.chunks(2) {
+                match pair[0].as_str() {
+                    "file" => name = Some(pair[1].clone()),
+                    "seq" => seq = pair[1].parse::<u64>().ok(),
+                    "type" => file_type = AofFileType::from_code(&pair[1]),
+                    // Newer versions may add keys
+                    _ => {}
+                }
+            }
+            let info = match (name, seq, file_type) {
+                (Some(name), Some(seq), Some(file_type)) => AofInfo { name, seq, file_type },
+                _ => return Err(invalid("Invalid AOF manifest file format")),
+            };
+
+            match info.file_type {
+                AofFileType::Base if manifest.base.is_some() => {
+                    return Err(invalid("Found duplicate base file information"));
+                }
+                AofFileType::Base => manifest.base = Some(info),
+                AofFileType::Incr => {
+                    if manifest.incrs.last().is_some_and(|last| info.seq <= last.seq) {
+                        return Err(invalid("Found a non-monotonic sequence number"));
+                    }
+                    manifest.incrs.push(info);
+                }
+                AofFileType::History => manifest.history.push(info),
+            }
+        }
+
+        if manifest.base.is_none() && manifest.incrs.is_empty() {
+            return Err(invalid("Found an empty AOF manifest"));
+        }
+        Ok(manifest)
+    }
+
+    /// Reads the manifest of the AOF named `filename` in `dir`, `None` if
+    /// there is none
+    pub fn load(dir: &Path, filename: &str) -> io::Result<Option<Self>> {
+        match fs::read_to_string(dir.join(manifest_name(filename))) {
+            Ok(text) => Self::parse(&text).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes the manifest through a temporary file, which replaces the old
+    /// manifest only once it is synced to disk
+    pub fn save(&self, dir: &Path, filename: &str) -> io::Result<()> {
+        let name = manifest_name(filename);
+        let temp = dir.join(format!("temp-{}", name));
+        let result = File::create(&temp)
+            .and_then(|mut file| {
+                file.write_all(self.to_string().as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&temp, dir.join(&name)));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        result
+    }
+
+    /// Adds the next incremental file of the AOF named `filename`, and
+    /// returns it
+    pub fn next_incr(&mut self, filename: &str) -> AofInfo {
+        let seq = self.incrs.last().map_or(1, |last| last.seq + 1);
+        let info = AofInfo {
+            name: format!("{}.{}.incr.aof", filename, seq),
+            seq,
+            file_type: AofFileType::Incr,
+        };
+        self.incrs.push(info.clone());
+        info
+    }
+
+    /// The base file the next rewrite writes, in RDB format or as commands
+    pub fn next_base(&self, filename: &str, rdb: bool) -> AofInfo {
+        let seq = self.base.as_ref().map_or(1, |base| base.seq + 1);
+        AofInfo {
+            name: format!("{}.{}.base.{}", filename, seq, if rdb { "rdb" } else { "aof" }),
+            seq,
+            file_type: AofFileType::Base,
+        }
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for info in self.base.iter().chain(&self.history).chain(&self.incrs) {
+            writeln!(f, "{}", info)?;
+        }
+        Ok(())
+    }
+}
+
+/// Name of the manifest of the AOF named `filename`
+pub fn manifest_name(filename: &str) -> String {
+    format!("{}.manifest", filename)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Quotes a file name that would not read back as a single argument,
+/// escaping it the way Redis' `sdscatrepr` does
+fn quote(name: &str) -> String {
+    let plain = !name.is_empty()
+        && name.bytes().all(|b| b.is_ascii_graphic() && b != b'"' && b != b'\'' && b != b'\\');
+    if plain {
+        return name.to_string();
+    }
+
+    let mut quoted = String::from("\"");
+    for b in name.bytes() {
+        match b {
+            b'\\' => quoted.push_str("\\\\"),
+            b'"' => quoted.push_str("\\\""),
+            b'\n' => quoted.push_str("\\n"),
+            b'\r' => quoted.push_str("\\r"),
+            b'\t' => quoted.push_str("\\t"),
+            0x07 => quoted.push_str("\\a"),
+            0x08 => quoted.push_str("\\b"),
+            b if b.is_ascii_graphic() || b == b' ' => quoted.push(b as char),
+            b => quoted.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Splits a line into arguments, honouring quotes like Redis'
+/// `sdssplitargs`. Returns `None` for unbalanced quotes.
+fn split_args(line: &str) -> Option<Vec<String>> {
+    let mut args = Vec::new();
+    let mut bytes = line.bytes().peekable();
+    loop {
+        while bytes.next_if(|b| b.is_ascii_whitespace()).is_some() {}
+        let first = match bytes.peek() {
+            Some(&b) => b,
+            None => return Some(args),
+        };
+
+        let mut arg = Vec::new();
+        match first {
+            b'"' => {
+                bytes.next();
+                loop {
+                    match bytes.next()? {
+                        b'"' => break,
+                        b'\\' => match bytes.next()? {
+                            b'n' => arg.push(b'\n'),
+                            b'r' => arg.push(b'\r'),
+                            b't' => arg.push(b'\t'),
+                            b'a' => arg.push(0x07),
+                            b'b' => arg.push(0x08),
+                            b'x' => {
+                                let hex = [bytes.next()?, bytes.next()?];
+                                let hex = std::str::from_utf8(&hex).ok()?;
+                                arg.push(u8::from_str_radix(hex, 16).ok()?);
+                            }
+                            b => arg.push(b),
+                        },
+                        b => arg.push(b),
+                    }
+                }
+            }
+            b'\'' => {
+                bytes.next();
+                loop {
+                    match bytes.next()? {
+                        b'\'' => break,
+                        b'\\' if bytes.peek() == Some(&b'\'') => arg.push(bytes.next()?),
+                        b => arg.push(b),
+                    }
+                }
+            }
+            _ => {
+                while let Some(b) = bytes.next_if(|b| !b.is_ascii_whitespace()) {
+                    arg.push(b);
+                }
+            }
+        }
+        // A closing quote must end the argument
+        if bytes.peek().is_some_and(|b| !b.is_ascii_whitespace()) {
+            return None;
+        }
+        args.push(String::from_utf8(arg).ok()?);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A manifest written by Redis 7.0 after two rewrites, the first base
+    /// and incremental files waiting to be deleted
+    const REDIS_MANIFEST: &str = "\
+file appendonly.aof.2.base.rdb seq 2 type b
+file appendonly.aof.1.incr.aof seq 1 type h
+file appendonly.aof.2.incr.aof seq 2 type i
+file appendonly.aof.3.incr.aof seq 3 type i
+";
+
+    fn info(name: &str, seq: u64, file_type: AofFileType) -> AofInfo {
+        AofInfo { name: name.to_string(), seq, file_type }
+    }
+
+    fn error(text: &str) -> String {
+        Manifest::parse(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_a_redis_manifest() {
+        let manifest = Manifest::parse(REDIS_MANIFEST).unwrap();
+        assert_eq!(manifest.base, Some(info("appendonly.aof.2.base.rdb", 2, AofFileType::Base)));
+        assert_eq!(manifest.history, [info("appendonly.aof.1.incr.aof", 1, AofFileType::History)]);
+        assert_eq!(manifest.incrs, [
+            info("appendonly.aof.2.incr.aof", 2, AofFileType::Incr),
+            info("appendonly.aof.3.incr.aof", 3, AofFileType::Incr),
+        ]);
+        assert_eq!(manifest.to_string(), REDIS_MANIFEST);
+    }
+
+    #[test]
+    fn skips_comments_and_unknown_keys() {
+        let manifest = Manifest::parse("# written by a newer version\nfile a.aof seq 1 type i size 10\n").unwrap();
+        assert_eq!(manifest.incrs, [info("a.aof", 1, AofFileType::Incr)]);
+    }
+
+    #[test]
+    fn quotes_names_that_need_it() {
+        assert_eq!(quote("appendonly.aof.1.incr.aof"), "appendonly.aof.1.incr.aof");
+        assert_eq!(quote("append only.aof"), "\"append only.aof\"");
+        assert_eq!(quote("a\"b"), "\"a\\\"b\"");
+        assert_eq!(quote("it's"), "\"it's\"");
+        assert_eq!(quote("back\\slash"), "\"back\\\\slash\"");
+        assert_eq!(quote("tab\tnew\nline"), "\"tab\\tnew\\nline\"");
+        assert_eq!(quote("é"), "\"\\xc3\\xa9\"");
+        assert_eq!(quote(""), "\"\"");
+    }
+
+    #[test]
+    fn reads_quoted_names_back() {
+        for name in ["append only.aof", "a\"b", "it's", "back\\slash", "tab\tnew\nline", "é", ""] {
+            assert_eq!(split_args(&quote(name)), Some(vec![name.to_string()]), "{:?}", name);
+        }
+        assert_eq!(split_args("'it\\'s' \"\\x41\""), Some(vec!["it's".to_string(), "A".to_string()]));
+
+        let mut manifest = Manifest::default();
+        manifest.incrs.push(info("my \"append\" only.aof", 1, AofFileType::Incr));
+        assert_eq!(manifest.to_string(), "file \"my \\\"append\\\" only.aof\" seq 1 type i\n");
+        assert_eq!(Manifest::parse(&manifest.to_string()).unwrap(), manifest);
+    }
+
+    #[test]
+    fn rejects_malformed_manifests() {
+        let format = "Invalid AOF manifest file format";
+        assert_eq!(error("file a.aof seq 1"), format);
+        assert_eq!(error("file a.aof seq 1 type"), format);
+        assert_eq!(error("file a.aof seq x type i"), format);
+        assert_eq!(error("file a.aof seq 1 type z"), format);
+        assert_eq!(error("name a.aof seq 1 type i"), format);
+        assert_eq!(error("file \"a.aof seq 1 type i"), format);
+        assert_eq!(error("file \"a\"b seq 1 type i"), format);
+        assert_eq!(error("file a.aof seq 1 type i\n\nfile b.aof seq 2 type i"), format);
+        assert_eq!(error("file a.rdb seq 1 type b\nfile b.rdb seq 2 type b"), "Found duplicate base file information");
+        assert_eq!(error("file a.aof seq 2 type i\nfile b.aof seq 2 type i"), "Found a non-monotonic sequence number");
+        assert_eq!(error("# nothing\n"), "Found an empty AOF manifest");
+        assert_eq!(error("file a.aof seq 1 type h"), "Found an empty AOF manifest");
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let dir = std::env::temp_dir().join(format!("manifest-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut manifest = Manifest::parse(REDIS_MANIFEST).unwrap();
+        manifest.next_incr("appendonly.aof");
+
+        let result = manifest.save(&dir, "appendonly.aof")
+            .and_then(|_| Manifest::load(&dir, "appendonly.aof"));
+        let missing = Manifest::load(&dir, "other.aof");
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(result.unwrap(), Some(manifest));
+        assert_eq!(missing.unwrap(), None);
+    }
+
+    #[test]
+    fn names_the_next_files() {
+        let mut manifest = Manifest::parse(REDIS_MANIFEST).unwrap();
+        assert_eq!(manifest.next_incr("appendonly.aof"), info("appendonly.aof.4.incr.aof", 4, AofFileType::Incr));
+        assert_eq!(manifest.next_base("appendonly.aof", true), info("appendonly.aof.3.base.rdb", 3, AofFileType::Base));
+        assert_eq!(Manifest::default().next_base("appendonly.aof", false), info("appendonly.aof.1.base.aof", 1, AofFileType::Base));
+    }
+}
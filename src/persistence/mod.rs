@@ -0,0 +1,9 @@
+//! Writing the dataset to disk so it survives a restart
+
+pub mod aof;
+pub mod crc64;
+pub mod dump;
+pub mod listpack;
+pub mod lzf;
+pub mod manifest;
+pub mod rdb;
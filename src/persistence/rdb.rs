@@ -0,0 +1,564 @@
+//! RDB snapshot writer
+//!
+//! Serializes the contents of the [`Store`] in the RDB version 11 format,
+//! which [`RDBParser`](crate::parser::rdb::RDBParser) and `redis-server` can
+//! both load. A snapshot is always written to a temporary file first and then
+//! renamed over the target, so a crash mid-save never leaves a truncated file
+//! behind.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::config::SavePoint;
+use crate::error::{RedisError, Result};
+use crate::parser::rdb::{
+    RDB_OPCODE_AUX, RDB_OPCODE_EOF, RDB_OPCODE_EXPIRETIME_MS, RDB_OPCODE_RESIZEDB,
+    RDB_OPCODE_SELECTDB, RDB_TYPE_HASH, RDB_TYPE_LIST, RDB_TYPE_SET, RDB_TYPE_STREAM_LISTPACKS_3,
+    RDB_TYPE_STRING, RDB_TYPE_ZSET_2, RDB_VERSION,
+};
+use crate::parser::stream::{Stream, StreamEntry, StreamId, FLAG_SAMEFIELDS};
+use crate::store::datatype::DataType;
+use crate::store::redis::{unix_millis, DatabaseSnapshot, Store};
+use super::crc64::crc64;
+use super::listpack::Listpack;
+use super::lzf;
+
+/// Version reported in the `redis-ver` AUX field. Loaders use it for
+/// diagnostics only, so any version that knows RDB 11 will do.
+const REDIS_VERSION: &str = "7.2.0";
+
+/// Most entries saved in one stream listpack, Redis' default
+/// `stream-node-max-entries`
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+/// Writes RDB data to any `Write`, keeping the running checksum of
+/// everything written
+pub struct RDBWriter<W: Write> {
+    writer: W,
+    checksum: u64,
+    /// Whether long strings are LZF-compressed
+    compression: bool,
+}
+
+impl<W: Write> RDBWriter<W> {
+    pub fn new(writer: W) -> Self {
+        RDBWriter { writer, checksum: 0, compression: false }
+    }
+
+    /// Enables LZF compression of strings longer than 20 bytes, as Redis does
+    /// with `rdbcompression yes`
+    pub fn with_compression(mut self, compression: bool) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Writes the "REDIS" magic string followed by the version number
+    pub fn write_header(&mut self) -> io::Result<()> {
+        self.write_raw(format!("REDIS{:04}", RDB_VERSION).as_bytes())
+    }
+
+    /// Writes an AUX field, the key/value metadata at the start of the file
+    pub fn write_aux(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.write_raw(&[RDB_OPCODE_AUX])?;
+        self.write_string(key.as_bytes())?;
+        self.write_string(value.as_bytes())
+    }
+
+    /// Starts the section of database `db`
+    pub fn write_select_db(&mut self, db: usize) -> io::Result<()> {
+        self.write_raw(&[RDB_OPCODE_SELECTDB])?;
+        self.write_length(db as u64)
+    }
+
+    /// Writes the number of keys and keys with an expiry in the current
+    /// database, which loaders use to size their tables up front
+    pub fn write_resize_db(&mut self, size: usize, expires: usize) -> io::Result<()> {
+        self.write_raw(&[RDB_OPCODE_RESIZEDB])?;
+        self.write_length(size as u64)?;
+        self.write_length(expires as u64)
+    }
+
+    /// Writes a key with its value, preceded by its expiry (in unix
+    /// milliseconds) if it has one
+    pub fn write_entry(&mut self, key: &[u8], value: &DataType, expiry: Option<i64>) -> io::Result<()> {
+        if let Some(at) = expiry {
+            self.write_raw(&[RDB_OPCODE_EXPIRETIME_MS])?;
+            self.write_raw(&at.to_le_bytes())?;
+        }
+        self.write_raw(&[value_type(value)])?;
+        self.write_string(key)?;
+        self.write_value(value)
+    }
+
+    /// Writes a value preceded by its type but without a key, which is the
+    /// layout of DUMP payloads
+    pub fn write_object(&mut self, value: &DataType) -> io::Result<()> {
+        self.write_raw(&[value_type(value)])?;
+        self.write_value(value)
+    }
+
+    /// Writes the encoding of a value, as found after its type and key
+    fn write_value(&mut self, value: &DataType) -> io::Result<()> {
+        match value {
+            DataType::String(s) => self.write_string(s),
+            DataType::List(list) => {
+                self.write_length(list.len() as u64)?;
+                list.iter().try_for_each(|item| self.write_string(item))
+            }
+            DataType::Set(set) => {
+                self.write_length(set.len() as u64)?;
+                set.iter().try_for_each(|member| self.write_string(member))
+            }
+            DataType::SortedSet(zset) => {
+                self.write_length(zset.len() as u64)?;
+                zset.iter().try_for_each(|(member, score)| {
+                    self.write_string(member)?;
+                    self.write_raw(&score.to_le_bytes())
+                })
+            }
+            DataType::Hash(hash) => {
+                self.write_length(hash.len() as u64)?;
+                hash.iter().try_for_each(|(field, value)| {
+                    self.write_string(field)?;
+                    self.write_string(value)
+                })
+            }
+            DataType::Stream(stream) => self.write_stream(stream),
+        }
+    }
+
+    /// Writes a stream: its entries in listpacks keyed by their first ID,
+    /// its metadata, then its consumer groups with their PEL and consumers
+    fn write_stream(&mut self, stream: &Stream) -> io::Result<()> {
+        let nodes = stream.entries.chunks(STREAM_NODE_MAX_ENTRIES);
+        self.write_length(nodes.len() as u64)?;
+        for node in nodes {
+            let master = node[0].id;
+            self.write_string(&master.to_be_bytes())?;
+            self.write_string(&stream_node(master, node))?;
+        }
+
+        self.write_length(stream.length)?;
+        self.write_stream_id(stream.last_id)?;
+        self.write_stream_id(stream.first_id)?;
+        self.write_stream_id(stream.max_deleted_id)?;
+        self.write_length(stream.entries_added)?;
+
+        self.write_length(stream.groups.len() as u64)?;
+        for group in &stream.groups {
+            self.write_string(&group.name)?;
+            self.write_stream_id(group.last_id)?;
+            // u64::MAX stands for an unknown count
+            self.write_length(group.entries_read.unwrap_or(u64::MAX))?;
+
+            self.write_length(group.pending.len() as u64)?;
+            for pending in &group.pending {
+                self.write_raw(&pending.id.to_be_bytes())?;
+                self.write_raw(&pending.delivery_time.to_le_bytes())?;
+                self.write_length(pending.delivery_count)?;
+            }
+
+            self.write_length(group.consumers.len() as u64)?;
+            for consumer in &group.consumers {
+                self.write_string(&consumer.name)?;
+                self.write_raw(&consumer.seen_time.to_le_bytes())?;
+                self.write_raw(&consumer.active_time.to_le_bytes())?;
+                self.write_length(consumer.pending.len() as u64)?;
+                for id in &consumer.pending {
+                    self.write_raw(&id.to_be_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a stream ID as two lengths
+    fn write_stream_id(&mut self, id: StreamId) -> io::Result<()> {
+        self.write_length(id.ms)?;
+        self.write_length(id.seq)
+    }
+
+    /// Writes a length using the smallest of the 6, 14, 32 and 64 bit encodings
+    pub fn write_length(&mut self, len: u64) -> io::Result<()> {
+        if len < 1 << 6 {
+            self.write_raw(&[len as u8])
+        } else if len < 1 << 14 {
+            self.write_raw(&[0x40 | (len >> 8) as u8, len as u8])
+        } else if len <= u32::MAX as u64 {
+            self.write_raw(&[0x80])?;
+            self.write_raw(&(len as u32).to_be_bytes())
+        } else {
+            self.write_raw(&[0x81])?;
+            self.write_raw(&len.to_be_bytes())
+        }
+    }
+
+    /// Writes a string, using the compact integer encoding when it is the
+    /// canonical form of a number that fits in 32 bits, as Redis does.
+    /// With compression enabled, strings over 20 bytes are LZF-compressed
+    /// when that saves space.
+    pub fn write_string(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if let Some(value) = integer_encodable(bytes) {
+            return if let Ok(value) = i8::try_from(value) {
+                self.write_raw(&[0xC0, value as u8])
+            } else if let Ok(value) = i16::try_from(value) {
+                self.write_raw(&[0xC1])?;
+                self.write_raw(&value.to_le_bytes())
+            } else {
+                self.write_raw(&[0xC2])?;
+                self.write_raw(&value.to_le_bytes())
+            };
+        }
+
+        if self.compression && bytes.len() > 20 {
+            if let Some(compressed) = lzf::compress(bytes) {
+                self.write_raw(&[0xC3])?;
+                self.write_length(compressed.len() as u64)?;
+                self.write_length(bytes.len() as u64)?;
+                return self.write_raw(&compressed);
+            }
+        }
+
+        self.write_length(bytes.len() as u64)?;
+        self.write_raw(bytes)
+    }
+
+    /// Writes the EOF marker and the checksum, and returns the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_raw(&[RDB_OPCODE_EOF])?;
+        let checksum = self.checksum;
+        self.writer.write_all(&checksum.to_le_bytes())?;
+        Ok(self.writer)
+    }
+
+    /// Returns the inner writer, without writing the EOF marker
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write_raw(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.checksum = crc64(self.checksum, bytes);
+        self.writer.write_all(bytes)
+    }
+}
+
+/// The RDB type a value is saved as
+fn value_type(value: &DataType) -> u8 {
+    match value {
+        DataType::String(_) => RDB_TYPE_STRING,
+        DataType::List(_) => RDB_TYPE_LIST,
+        DataType::Set(_) => RDB_TYPE_SET,
+        DataType::SortedSet(_) => RDB_TYPE_ZSET_2,
+        DataType::Hash(_) => RDB_TYPE_HASH,
+        DataType::Stream(_) => RDB_TYPE_STREAM_LISTPACKS_3,
+    }
+}
+
+/// Returns the value of `bytes` if it is a number in canonical form (no
+/// sign or leading zeros that would be lost) that fits in 32 bits
+fn integer_encodable(bytes: &[u8]) -> Option<i32> {
+    if bytes.is_empty() || bytes.len() > 11 {
+        return None;
+    }
+    let s = std::str::from_utf8(bytes).ok()?;
+    let value = s.parse::<i32>().ok()?;
+    if value.to_string() != s {
+        return None;
+    }
+    Some(value)
+}
+
+/// Encodes stream entries as the listpack of a stream node keyed by
+/// `master`. The first entry's fields become the master fields, which the
+/// entries having the same fields then omit.
+fn stream_node(master: StreamId, entries: &[StreamEntry]) -> Vec<u8> {
+    let master_fields = &entries[0].fields;
+    let mut listpack = Listpack::new();
+    listpack.push_int(entries.len() as i64);
+    listpack.push_int(0);
+    listpack.push_int(master_fields.len() as i64);
+    for (field, _) in master_fields {
+        listpack.push(field);
+    }
+    listpack.push_int(0);
+
+    for entry in entries {
+        let same_fields = entry.fields.len() == master_fields.len()
+            && entry.fields.iter().zip(master_fields).all(|((field, _), (master, _))| field == master);
+        let num_fields = entry.fields.len() as i64;
+        listpack.push_int(if same_fields { FLAG_SAMEFIELDS } else { 0 });
+        listpack.push_int(entry.id.ms.wrapping_sub(master.ms) as i64);
+        listpack.push_int(entry.id.seq.wrapping_sub(master.seq) as i64);
+        if same_fields {
+            for (_, value) in &entry.fields {
+                listpack.push(value);
+            }
+            // lp-count: flags, IDs and values
+            listpack.push_int(3 + num_fields);
+        } else {
+            listpack.push_int(num_fields);
+            for (field, value) in &entry.fields {
+                listpack.push(field);
+                listpack.push(value);
+            }
+            listpack.push_int(4 + 2 * num_fields);
+        }
+    }
+    listpack.finish()
+}
+
+/// Writes `snapshot` to `path` as a complete RDB file. `aof_base` marks it
+/// as the preamble of an append-only file.
+pub fn write_rdb(snapshot: &[DatabaseSnapshot], path: &Path, compression: bool, aof_base: bool) -> io::Result<()> {
+    let file = File::create(path)?;
+    let file = write_snapshot(BufWriter::new(file), snapshot, compression, aof_base)?
+        .into_inner()
+        .map_err(|e| e.into_error())?;
+    file.sync_all()
+}
+
+/// Writes a whole RDB file holding `snapshot` to `writer`, which is
+/// returned once the checksum is written
+pub fn write_snapshot<W: Write>(writer: W, snapshot: &[DatabaseSnapshot], compression: bool, aof_base: bool) -> io::Result<W> {
+    let mut rdb = RDBWriter::new(writer).with_compression(compression);
+
+    rdb.write_header()?;
+    rdb.write_aux("redis-ver", REDIS_VERSION)?;
+    rdb.write_aux("redis-bits", &(usize::BITS).to_string())?;
+    rdb.write_aux("ctime", &(unix_millis() / 1000).to_string())?;
+    rdb.write_aux("used-mem", "0")?;
+    rdb.write_aux("aof-base", if aof_base { "1" } else { "0" })?;
+
+    for (db, entries) in snapshot.iter().enumerate() {
+        if entries.is_empty() {
+            continue;
+        }
+        let expires = entries.iter().filter(|(_, _, expiry)| expiry.is_some()).count();
+        rdb.write_select_db(db)?;
+        rdb.write_resize_db(entries.len(), expires)?;
+        for (key, value, expiry) in entries {
+            rdb.write_entry(key, value, *expiry)?;
+        }
+    }
+    rdb.finish()
+}
+
+/// Where and how snapshots are written
+#[derive(Debug, Clone)]
+pub struct SaveOptions {
+    pub dir: PathBuf,
+    pub filename: String,
+    /// LZF-compress long strings (`rdbcompression`)
+    pub compression: bool,
+}
+
+impl SaveOptions {
+    /// Reads the options from the live configuration, so CONFIG SET applies
+    /// to the next save
+    pub async fn from_config(store: &Store) -> Self {
+        let dir = store.get_config("dir").await.unwrap_or_else(|| ".".to_string());
+        let filename = store.get_config("dbfilename").await.unwrap_or_else(|| "dump.rdb".to_string());
+        let compression = store.get_config("rdbcompression").await.is_none_or(|value| value != "no");
+        SaveOptions { dir: PathBuf::from(dir), filename, compression }
+    }
+}
+
+/// Writes `snapshot` to `dir/filename` through a temporary file in `dir`,
+/// which replaces the old file only once it is complete and synced to disk
+pub fn save_snapshot(snapshot: &[DatabaseSnapshot], options: &SaveOptions) -> io::Result<()> {
+    let dir = options.dir.as_path();
+    fs::create_dir_all(dir)?;
+    let temp = dir.join(format!("temp-{}.rdb", std::process::id()));
+
+    let result = write_rdb(snapshot, &temp, options.compression, false)
+        .and_then(|_| fs::rename(&temp, dir.join(&options.filename)));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Seconds to wait before retrying an automatic save that failed
+const BGSAVE_RETRY_DELAY: i64 = 5;
+
+/// Outcome of past saves, reported by LASTSAVE and INFO
+#[derive(Debug, Clone)]
+pub struct SaveStatus {
+    /// Unix time in seconds of the last successful save, or of startup
+    pub last_save: i64,
+    /// Value of `Store::changes` when the last successful save took its
+    /// snapshot. Changes after that are not on disk yet.
+    pub changes_saved: u64,
+    /// Whether the last save succeeded
+    pub last_save_ok: bool,
+    /// Unix time in seconds of the last background save attempt
+    pub last_bgsave_try: i64,
+    /// How long the last background save took
+    pub last_bgsave_time: Option<Duration>,
+    /// When the running save started
+    pub current_save_start: Option<Instant>,
+    /// Successful saves since startup
+    pub saves: u64,
+}
+
+/// State of RDB saving, shared between the store and background saves
+#[derive(Debug)]
+pub struct SaveState {
+    in_progress: AtomicBool,
+    status: Mutex<SaveStatus>,
+}
+
+impl Default for SaveState {
+    fn default() -> Self {
+        SaveState {
+            in_progress: AtomicBool::new(false),
+            status: Mutex::new(SaveStatus {
+                last_save: unix_millis() / 1000,
+                changes_saved: 0,
+                last_save_ok: true,
+                last_bgsave_try: 0,
+                last_bgsave_time: None,
+                current_save_start: None,
+                saves: 0,
+            }),
+        }
+    }
+}
+
+impl SaveState {
+    /// Whether a save is currently running
+    pub fn in_progress(&self) -> bool {
+        self.in_progress.load(Ordering::SeqCst)
+    }
+
+    pub fn status(&self) -> SaveStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Records that the dataset after `changes` changes is the one on disk,
+    /// because it was just loaded from there. Loaded keys count as changes
+    /// as they are inserted, but there is nothing new to save.
+    pub fn mark_loaded(&self, changes: u64) {
+        self.status.lock().unwrap().changes_saved = changes;
+    }
+
+    /// Claims the right to save. Returns false if a save is already running.
+    fn begin(&self, background: bool) -> bool {
+        if self.in_progress
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return false;
+        }
+
+        let mut status = self.status.lock().unwrap();
+        status.current_save_start = Some(Instant::now());
+        if background {
+            status.last_bgsave_try = unix_millis() / 1000;
+        }
+        true
+    }
+
+    /// Records the outcome of the running save, which snapshotted the
+    /// dataset after `changes` changes
+    fn end(&self, background: bool, changes: u64, result: &io::Result<()>) {
+        let mut status = self.status.lock().unwrap();
+        let elapsed = status.current_save_start.take().map(|start| start.elapsed());
+        if background {
+            status.last_bgsave_time = elapsed;
+        }
+        status.last_save_ok = result.is_ok();
+        if result.is_ok() {
+            status.last_save = unix_millis() / 1000;
+            status.changes_saved = changes;
+            status.saves += 1;
+        }
+        drop(status);
+        self.in_progress.store(false, Ordering::SeqCst);
+    }
+}
+
+fn already_in_progress() -> RedisError {
+    RedisError::Message("Background save already in progress".to_string())
+}
+
+/// Saves the dataset and returns once it is on disk (`SAVE`)
+pub async fn save(store: &Store) -> Result<()> {
+    let state = Arc::clone(store.save_state());
+    if !state.begin(false) {
+        return Err(already_in_progress());
+    }
+
+    let changes = store.changes();
+    let snapshot = store.snapshot().await;
+    let options = SaveOptions::from_config(store).await;
+    let result = tokio::task::spawn_blocking(move || save_snapshot(&snapshot, &options))
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)));
+    state.end(false, changes, &result);
+
+    match result {
+        Ok(()) => {
+            println!("DB saved on disk");
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("Failed saving the DB: {}", e);
+            Err(RedisError::Message(format!("Failed saving the DB: {}", e)))
+        }
+    }
+}
+
+/// Starts saving the dataset in the background and returns right away
+/// (`BGSAVE`).
+///
+/// The keyspace is copied up front, the way Redis forks, so the snapshot is
+/// consistent while clients keep writing during the save.
+pub async fn bgsave(store: &Store) -> Result<()> {
+    let state = Arc::clone(store.save_state());
+    if !state.begin(true) {
+        return Err(already_in_progress());
+    }
+
+    let changes = store.changes();
+    let snapshot = store.snapshot().await;
+    let options = SaveOptions::from_config(store).await;
+    println!("Background saving started");
+    tokio::task::spawn_blocking(move || {
+        let result = save_snapshot(&snapshot, &options);
+        match &result {
+            Ok(()) => println!("Background saving terminated with success"),
+            Err(e) => eprintln!("Background saving error: {}", e),
+        }
+        state.end(true, changes, &result);
+    });
+    Ok(())
+}
+
+/// Starts a background save if any of the save `points` is satisfied, the
+/// check Redis' serverCron runs. After a failed save, the next attempt waits
+/// a few seconds so a full disk is not hammered.
+pub async fn bgsave_if_due(store: &Store, points: &[SavePoint]) {
+    let state = store.save_state();
+    if state.in_progress() {
+        return;
+    }
+
+    let status = state.status();
+    let dirty = store.dirty();
+    let now = unix_millis() / 1000;
+    let elapsed = now - status.last_save;
+    let can_retry = status.last_save_ok || now - status.last_bgsave_try > BGSAVE_RETRY_DELAY;
+    let due = points.iter()
+        .find(|point| dirty >= point.changes && elapsed > point.seconds as i64);
+
+    if let (Some(point), true) = (due, can_retry) {
+        println!("{} changes in {} seconds. Saving...", point.changes, point.seconds);
+        if let Err(e) = bgsave(store).await {
+            eprintln!("Background saving error: {}", e);
+        }
+    }
+}
@@ -0,0 +1,373 @@
+//! Replication state of this server
+//!
+//! The history of the dataset is named by a replication ID, 40 random hex
+//! digits, which replicas use to tell whether they can continue from where
+//! they stopped. The secondary ID is the history the current one continues,
+//! all zeros when there is none.
+//!
+//! As a master, this server streams its writes to replicas. A replica
+//! connects like a client, announces itself with `REPLCONF`, and asks for
+//! the stream with `PSYNC`. It first gets an RDB snapshot of the dataset,
+//! then every write applied since, in the canonical form the AOF logs them
+//! in. The replication offset counts the bytes of that stream, and replicas
+//! report the offset they processed with `REPLCONF ACK`. No backlog of the
+//! stream is kept, so every PSYNC is answered with a full resync.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use crate::command::{Command, Replconf, Session};
+use crate::error::{RedisError, Result};
+use crate::parser::Parser;
+use crate::persistence::aof;
+use crate::persistence::rdb::{self, SaveOptions};
+use crate::store::redis::Store;
+
+/// Length of a replication ID
+const ID_LEN: usize = 40;
+
+/// The replication IDs of this server, as INFO replication reports them
+#[derive(Debug, Clone)]
+pub struct ReplicationIds {
+    pub id: String,
+    pub id2: String,
+    /// Offset up to which `id2` is valid, -1 when there is none
+    pub second_offset: i64,
+}
+
+/// Where a replica is in its synchronization
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplicaState {
+    /// Waiting for its snapshot to be serialized
+    WaitBgsave,
+    /// Receiving its snapshot
+    SendBulk,
+    /// Receiving the stream of writes
+    Online,
+}
+
+impl ReplicaState {
+    /// The state as INFO replication reports it
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReplicaState::WaitBgsave => "wait_bgsave",
+            ReplicaState::SendBulk => "send_bulk",
+            ReplicaState::Online => "online",
+        }
+    }
+}
+
+/// A connected replica, as INFO replication and ROLE report it
+#[derive(Debug, Clone)]
+pub struct ReplicaInfo {
+    pub ip: String,
+    /// Port the replica accepts clients on, 0 when it did not announce it
+    pub port: u16,
+    pub state: ReplicaState,
+    /// Offset the replica acknowledged last
+    pub offset: u64,
+    /// Time since the replica acknowledged an offset
+    pub lag: Duration,
+}
+
+/// The stream of writes as replicas see it
+#[derive(Debug, Clone)]
+pub struct ReplicationStatus {
+    /// Bytes streamed so far, `master_repl_offset`
+    pub offset: u64,
+    pub replicas: Vec<ReplicaInfo>,
+}
+
+pub struct Replication {
+    ids: Mutex<ReplicationIds>,
+    /// Held while a write is applied and streamed, so replicas apply writes
+    /// in the order they were applied here
+    stream: tokio::sync::Mutex<ReplicationStream>,
+    /// Number of replicas attached, which writes check to skip the stream
+    /// when there is nobody to stream to
+    attached: AtomicUsize,
+    /// Held shared by writes that skip the stream, and exclusively while a
+    /// replica attaches, so no write falls between its snapshot and its
+    /// stream
+    attaching: tokio::sync::RwLock<()>,
+}
+
+#[derive(Default)]
+struct ReplicationStream {
+    offset: u64,
+    /// Database the streamed commands apply to, `None` until a SELECT is
+    /// streamed. Reset when a replica attaches, as it starts from none.
+    db: Option<usize>,
+    replicas: Vec<Replica>,
+    /// Identifies the next replica to attach
+    next_id: u64,
+    last_ping: Option<Instant>,
+}
+
+struct Replica {
+    id: u64,
+    info: ReplicaInfo,
+    last_ack: Instant,
+    /// The link of the replica, which forwards the stream to its socket
+    sender: mpsc::UnboundedSender<Bytes>,
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Replication {
+            ids: Mutex::new(new_history()),
+            stream: tokio::sync::Mutex::new(ReplicationStream::default()),
+            attached: AtomicUsize::new(0),
+            attaching: tokio::sync::RwLock::new(()),
+        }
+    }
+}
+
+impl Replication {
+    pub fn ids(&self) -> ReplicationIds {
+        self.ids.lock().unwrap().clone()
+    }
+
+    /// Starts a new history under a new random ID and forgets the previous
+    /// one, like Redis' `changeReplicationId` and `clearReplicationId2`
+    pub fn change_id(&self) {
+        *self.ids.lock().unwrap() = new_history();
+    }
+
+    pub async fn status(&self) -> ReplicationStatus {
+        let stream = self.stream.lock().await;
+        let replicas = stream.replicas.iter()
+            .map(|replica| ReplicaInfo { lag: replica.last_ack.elapsed(), ..replica.info.clone() })
+            .collect();
+        ReplicationStatus { offset: stream.offset, replicas }
+    }
+
+    /// Executes a client's command. Writes are logged to the AOF, if there
+    /// is one, and streamed to replicas when they change the dataset. Keys
+    /// the command found expired are deleted on replicas first.
+    ///
+    /// Without an AOF or replicas, writes run side by side and skip the
+    /// stream entirely.
+    pub async fn execute(&self, command: &Command, store: &Store, session: &mut Session) -> Result<Vec<u8>> {
+        let args = match command.aof_args() {
+            Some(args) => args,
+            None => {
+                let reply = command.execute(store, session).await;
+                if store.has_expired() {
+                    let mut stream = self.stream.lock().await;
+                    stream.feed_expired(store).await;
+                }
+                return reply;
+            }
+        };
+
+        if store.aof().is_none() {
+            let _attaching = self.attaching.read().await;
+            if self.attached.load(Ordering::Acquire) == 0 {
+                let reply = command.execute(store, session).await;
+                // Nobody needs the DELs, but the keys must not pile up
+                if store.has_expired() {
+                    store.take_expired().await;
+                }
+                return reply;
+            }
+        }
+
+        let mut stream = self.stream.lock().await;
+        let db = session.db;
+        let (reply, wrote) = Store::track_writes(async {
+            match store.aof() {
+                Some(aof) => aof.execute(command, store, session).await,
+                None => command.execute(store, session).await,
+            }
+        }).await;
+        stream.feed_expired(store).await;
+        let reply = reply?;
+        if wrote {
+            stream.feed(Some(db), args);
+        }
+        Ok(reply)
+    }
+
+    /// Runs the replication part of the cron: deletes the keys the active
+    /// expire cycle removed on replicas, pings replicas every `ping_period`,
+    /// so they can tell the link is alive, and drops the ones that did not
+    /// acknowledge anything for `timeout`
+    pub async fn cron(&self, store: &Store, ping_period: Duration, timeout: Duration) {
+        let mut stream = self.stream.lock().await;
+        stream.feed_expired(store).await;
+        stream.replicas.retain(|replica| {
+            let timed_out = replica.info.state == ReplicaState::Online && replica.last_ack.elapsed() > timeout;
+            if timed_out {
+                eprintln!("Disconnecting timedout replica: {}:{}", replica.info.ip, replica.info.port);
+            }
+            !timed_out
+        });
+        let due = stream.last_ping.is_none_or(|last_ping| last_ping.elapsed() >= ping_period);
+        if !stream.replicas.is_empty() && due {
+            stream.feed(None, vec![b"PING".to_vec()]);
+            stream.last_ping = Some(Instant::now());
+        }
+    }
+
+    /// Serves a replica that sent PSYNC on `socket`, until the link closes.
+    /// `buffer` holds what the replica sent after PSYNC.
+    ///
+    /// The snapshot is taken while writes are held off, so the stream picks
+    /// up exactly where it ends. Writes applied while the snapshot is sent
+    /// are queued for the replica.
+    pub async fn serve_replica(&self, mut socket: TcpStream, buffer: BytesMut, store: &Store, session: &Session) -> Result<()> {
+        let ip = match &session.announced_ip {
+            Some(ip) => ip.clone(),
+            None => socket.peer_addr()?.ip().to_string(),
+        };
+        let name = format!("{}:{}", ip, session.listening_port);
+        println!("Replica {} asks for synchronization", name);
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (id, offset, snapshot) = {
+            let _attaching = self.attaching.write().await;
+            let mut stream = self.stream.lock().await;
+            let snapshot = store.snapshot().await;
+            let id = stream.next_id;
+            stream.next_id += 1;
+            stream.db = None;
+            stream.replicas.push(Replica {
+                id,
+                info: ReplicaInfo { ip, port: session.listening_port, state: ReplicaState::WaitBgsave, offset: 0, lag: Duration::ZERO },
+                last_ack: Instant::now(),
+                sender,
+            });
+            self.attached.store(stream.replicas.len(), Ordering::Release);
+            (id, stream.offset, snapshot)
+        };
+
+        let result = async {
+            println!("Starting full resync with replica {}", name);
+            let reply = format!("+FULLRESYNC {} {}\r\n", self.ids().id, offset);
+            socket.write_all(reply.as_bytes()).await?;
+
+            let compression = SaveOptions::from_config(store).await.compression;
+            let payload = tokio::task::spawn_blocking(move || rdb::write_snapshot(Vec::new(), &snapshot, compression, false))
+                .await
+                .map_err(io::Error::other)??;
+            self.set_state(id, ReplicaState::SendBulk).await;
+            // Unlike a bulk string, the payload is not followed by CRLF
+            socket.write_all(format!("${}\r\n", payload.len()).as_bytes()).await?;
+            socket.write_all(&payload).await?;
+            self.set_state(id, ReplicaState::Online).await;
+            println!("Synchronization with replica {} succeeded", name);
+
+            self.forward(&mut socket, buffer, receiver, id).await
+        }.await;
+
+        let mut stream = self.stream.lock().await;
+        stream.replicas.retain(|replica| replica.id != id);
+        self.attached.store(stream.replicas.len(), Ordering::Release);
+        drop(stream);
+        println!("Connection with replica {} lost.", name);
+        result
+    }
+
+    /// Forwards the stream to replica `id` and records the offsets it
+    /// acknowledges, until either side closes the link
+    async fn forward(&self, socket: &mut TcpStream, mut buffer: BytesMut, mut receiver: mpsc::UnboundedReceiver<Bytes>, id: u64) -> Result<()> {
+        loop {
+            // Replicas only send REPLCONF ACK, which gets no reply
+            loop {
+                let (output, consumed) = match Parser::parse(&buffer) {
+                    Ok((output, rest)) => (output, buffer.len() - rest.len()),
+                    Err(e) if e.is_incomplete() => break,
+                    Err(e) => return Err(RedisError::Parser(e)),
+                };
+                buffer.advance(consumed);
+                if let Ok(Command::Replconf(Replconf::Ack(offset))) = Command::from_resp(output) {
+                    self.ack(id, offset).await;
+                }
+            }
+
+            tokio::select! {
+                data = receiver.recv() => match data {
+                    Some(data) => socket.write_all(&data).await?,
+                    // The cron dropped the replica
+                    None => return Ok(()),
+                },
+                size = socket.read_buf(&mut buffer) => {
+                    if size? == 0 {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    async fn set_state(&self, id: u64, state: ReplicaState) {
+        let mut stream = self.stream.lock().await;
+        if let Some(replica) = stream.replicas.iter_mut().find(|replica| replica.id == id) {
+            replica.info.state = state;
+            replica.last_ack = Instant::now();
+        }
+    }
+
+    async fn ack(&self, id: u64, offset: u64) {
+        let mut stream = self.stream.lock().await;
+        if let Some(replica) = stream.replicas.iter_mut().find(|replica| replica.id == id) {
+            replica.info.offset = offset;
+            replica.last_ack = Instant::now();
+        }
+    }
+}
+
+impl ReplicationStream {
+    /// Streams a command to every replica, preceded by a SELECT when it
+    /// applies to another database than the previous one. Nothing is
+    /// streamed, and the offset does not move, without replicas.
+    fn feed(&mut self, db: Option<usize>, args: Vec<Vec<u8>>) {
+        if self.replicas.is_empty() {
+            return;
+        }
+
+        let mut data = Vec::new();
+        if let Some(db) = db {
+            if self.db != Some(db) {
+                data.extend(aof::encode(vec!["SELECT".into(), db.to_string().into()]));
+                self.db = Some(db);
+            }
+        }
+        data.extend(aof::encode(args));
+        self.offset += data.len() as u64;
+        let data = Bytes::from(data);
+        self.replicas.retain(|replica| replica.sender.send(data.clone()).is_ok());
+    }
+
+    /// Streams a DEL for every key removed because its expiry passed, as
+    /// replicas never expire keys themselves
+    async fn feed_expired(&mut self, store: &Store) {
+        for (db, keys) in store.take_expired().await {
+            for key in keys {
+                self.feed(Some(db), vec![b"DEL".to_vec(), key]);
+            }
+        }
+    }
+}
+
+fn new_history() -> ReplicationIds {
+    ReplicationIds { id: random_id(), id2: "0".repeat(ID_LEN), second_offset: -1 }
+}
+
+/// A new random replication ID
+fn random_id() -> String {
+    let mut id = String::with_capacity(ID_LEN + 16);
+    while id.len() < ID_LEN {
+        id.push_str(&format!("{:016x}", RandomState::new().build_hasher().finish()));
+    }
+    id.truncate(ID_LEN);
+    id
+}
@@ -1,11 +1,17 @@
 use tokio::net::TcpListener;
 use tokio::signal;
 use std::sync::Arc;
-use crate::{handle_connection, store::redis::Store, store::datatype::DataType};
-use crate::config::AppConfig;
-use crate::parser::RDBParser;
-use std::fs::File;
-use std::io;
+use crate::{handle_connection, store::datatype::DataType};
+use crate::store::redis::{unix_millis, Expiry, SetOptions, Store};
+use crate::config::{format_save_points, parse_save_points, AppConfig, SavePoint, ShutdownMode};
+use crate::persistence::aof::{self, Aof, AofOptions};
+use crate::persistence::manifest::Manifest;
+use crate::persistence::rdb;
+use crate::parser::{RDBError, RDBParser, RdbEvent};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
 pub struct Server {
     listener: TcpListener,
@@ -17,7 +23,7 @@ impl Server {
     pub async fn new(config: AppConfig) -> Result<Self, Box<dyn std::error::Error>> {
         let server = Self {
             listener: TcpListener::bind(format!("{}:{}", config.server.address, config.server.port)).await?,
-            store: Arc::new(Store::new().await?),
+            store: Arc::new(Store::new(config.databases).await?),
             config,
         };
 
@@ -26,15 +32,36 @@ impl Server {
 
     async fn init_config(&self) -> Result<(), Box<dyn std::error::Error>> {
         // Initialize config
-        self.store.set("dir", DataType::String(self.config.dir.clone())).await?;
-        self.store.set("dbfilename", DataType::String(self.config.dbfilename.clone())).await?;
+        self.store.set_config("dir", self.config.dir.clone()).await;
+        self.store.set_config("dbfilename", self.config.dbfilename.clone()).await;
+        self.store.set_config("hz", self.hz().to_string()).await;
+        self.store.set_config("databases", self.store.databases().to_string()).await;
+        self.store.set_config("save", format_save_points(&self.config.save)).await;
+        let rdbcompression = if self.config.rdbcompression { "yes" } else { "no" };
+        self.store.set_config("rdbcompression", rdbcompression.to_string()).await;
+        let appendonly = if self.config.appendonly { "yes" } else { "no" };
+        self.store.set_config("appendonly", appendonly.to_string()).await;
+        self.store.set_config("appendfsync", self.config.appendfsync.as_str().to_string()).await;
+        self.store.set_config("appendfilename", self.config.appendfilename.clone()).await;
+        self.store.set_config("appenddirname", self.config.appenddirname.clone()).await;
+        let preamble = if self.config.aof_use_rdb_preamble { "yes" } else { "no" };
+        self.store.set_config("aof-use-rdb-preamble", preamble.to_string()).await;
+        self.store.set_config("auto-aof-rewrite-percentage", self.config.auto_aof_rewrite_percentage.to_string()).await;
+        self.store.set_config("auto-aof-rewrite-min-size", self.config.auto_aof_rewrite_min_size.to_string()).await;
+        let load_truncated = if self.config.aof_load_truncated { "yes" } else { "no" };
+        self.store.set_config("aof-load-truncated", load_truncated.to_string()).await;
+        self.store.set_config("enable-debug-command", self.config.enable_debug_command.as_str().to_string()).await;
+        self.store.set_config("repl-ping-replica-period", self.config.repl_ping_replica_period.to_string()).await;
+        self.store.set_config("repl-timeout", self.config.repl_timeout.to_string()).await;
 
         Ok(())
     }
 
-    async fn init_db(&self) -> Result<(), Box<dyn std::error::Error>> {
+    /// Loads the RDB file at `path` into `store`. DEBUG RELOAD loads the
+    /// file it just saved through here too.
+    pub async fn init_db(store: &Store, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
         // Try to open the RDB file, if it doesn't exist, that's fine
-        let rdb_file = match File::open(self.config.dbfilename.clone()) {
+        let rdb_file = match File::open(path) {
             Ok(file) => file,
             Err(e) if e.kind() == io::ErrorKind::NotFound => {
                 println!("No RDB file found, starting with empty database");
@@ -43,39 +70,286 @@ impl Server {
             Err(e) => return Err(Box::new(e)),
         };
 
-        println!("Loading RDB file: {}", self.config.dbfilename);
-        let mut rdb_parser = RDBParser::new(rdb_file);
-        
-        // Parse the RDB header
-        rdb_parser.parse_header()?;
+        println!("Loading RDB file: {}", path.display());
+        let size = rdb_file.metadata()?.len();
+        let mut rdb_parser = RDBParser::new(BufReader::new(rdb_file));
+        rdb_parser.set_input_len(size);
+        Self::load_rdb(store, &mut rdb_parser, path).await
+    }
+
+    /// The RDB file loaded on startup
+    fn rdb_path(&self) -> PathBuf {
+        Path::new(&self.config.dir).join(&self.config.dbfilename)
+    }
+
+    /// Loads the keys of the RDB file read by `rdb_parser` into the store,
+    /// stopping after its EOF opcode
+    async fn load_rdb<R: Read>(store: &Store, rdb_parser: &mut RDBParser<R>, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let report = |e: &RDBError| eprintln!("Error loading {}: {}", path.display(), e);
 
         // Parse and load entries
+        let now = unix_millis();
         let mut entry_count = 0;
-        while let Some(entry) = rdb_parser.parse_entry()? {
-            entry_count += 1;
-            let key = String::from_utf8_lossy(&entry.key).to_string();
-            
-            match entry.value {
-                crate::parser::rdb::RDBValue::String(data) => {
-                    let value = String::from_utf8_lossy(&data).to_string();
-                    self.store.set(&key, DataType::String(value)).await?;
-                    
-                    // If there's an expiry, set it
-                    if let Some(_expiry) = entry.expiry {
-                        // TODO: Implement expiry handling
-                    }
+        let mut expired_count = 0;
+        let mut empty_count = 0;
+        let mut module_count = 0;
+        let mut function_count = 0;
+        for event in rdb_parser.by_ref() {
+            let entry = match event.inspect_err(report)? {
+                RdbEvent::Header { version } => {
+                    println!("RDB format version: {}", version);
+                    continue;
+                }
+                RdbEvent::Aux { key, value } => {
+                    println!("AUX fields: key={:?}, value={:?}", String::from_utf8_lossy(&key), String::from_utf8_lossy(&value));
+                    continue;
+                }
+                RdbEvent::Function { .. } => {
+                    function_count += 1;
+                    continue;
+                }
+                RdbEvent::Entry(entry) => entry,
+                _ => continue,
+            };
+            if entry.db >= store.databases() {
+                return Err(format!(
+                    "RDB file has keys in DB {}, but only {} databases are configured",
+                    entry.db,
+                    store.databases(),
+                ).into());
+            }
+
+            // Keys carry their absolute deadline over from the file, and keys
+            // whose deadline already passed are not loaded at all
+            let expiry = entry.expiry.map(|at| {
+                at.duration_since(SystemTime::UNIX_EPOCH)
+                    .map(|since_epoch| since_epoch.as_millis() as i64)
+                    .unwrap_or(0)
+            });
+            if expiry.is_some_and(|at| at <= now) {
+                expired_count += 1;
+                continue;
+            }
+
+            let value = match DataType::try_from(entry.value) {
+                Ok(value) => value,
+                Err(module) => {
+                    println!("Skipping key {:?} of module type {}", String::from_utf8_lossy(&entry.key), module);
+                    module_count += 1;
+                    continue;
                 }
+            };
+            // Collections can end up empty, e.g. once expired hash fields are
+            // dropped, and Redis never keeps empty collections
+            if value.is_empty_collection() {
+                empty_count += 1;
+                continue;
             }
+            entry_count += 1;
+
+            let options = SetOptions { expiry: expiry.map(Expiry::At), ..SetOptions::default() };
+            store.db(entry.db).set_with(&entry.key, value, &options).await?;
         }
 
+        if expired_count > 0 {
+            println!("Skipped {} keys that had already expired", expired_count);
+        }
+        if empty_count > 0 {
+            println!("Skipped {} empty keys", empty_count);
+        }
+        if module_count > 0 {
+            println!("Skipped {} keys of module types", module_count);
+        }
+        if function_count > 0 {
+            println!("Skipped {} function libraries, functions are not supported", function_count);
+        }
         println!("RDB file loaded successfully, loaded {} entries", entry_count);
         Ok(())
     }
 
+    /// Loads the dataset from the append-only files listed by the manifest,
+    /// or from the RDB file if there is no AOF yet, then starts logging
+    /// writes to the AOF. A single-file AOF from before the manifest existed
+    /// is moved into the AOF directory first.
+    async fn init_aof(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let options = AofOptions {
+            dir: Path::new(&self.config.dir).join(&self.config.appenddirname),
+            filename: self.config.appendfilename.clone(),
+            fsync: self.config.appendfsync,
+        };
+        let legacy = Path::new(&self.config.dir).join(&self.config.appendfilename);
+        let manifest = match Manifest::load(&options.dir, &options.filename)? {
+            Some(manifest) => Some(manifest),
+            None if legacy.is_file() => {
+                println!("Upgrading {} to a manifest in {}", legacy.display(), options.dir.display());
+                Some(aof::upgrade(&legacy, &options)?)
+            }
+            None => None,
+        };
+
+        match manifest {
+            Some(manifest) => {
+                let files = manifest.base.iter().chain(&manifest.incrs).collect::<Vec<_>>();
+                for (index, info) in files.iter().enumerate() {
+                    let path = options.dir.join(&info.name);
+                    let file = File::open(&path)
+                        .map_err(|e| format!("Can't open the append-only file {}: {}", path.display(), e))?;
+                    Self::load_aof(&self.store, file, &path, index + 1 == files.len(), self.config.aof_load_truncated).await?;
+                }
+            }
+            None => {
+                println!("No AOF found, it will be created from the current dataset");
+                Self::init_db(&self.store, &self.rdb_path()).await?;
+            }
+        }
+
+        let aof = Aof::open(&self.store, options).await?;
+        self.store.set_aof(aof);
+        Ok(())
+    }
+
+    /// Replays one append-only file, starting with its RDB preamble if it
+    /// has one. Base files written in RDB format are all preamble.
+    ///
+    /// A crash while appending can leave the last command of the last file
+    /// cut short. With `load_truncated`, that command is dropped and the
+    /// file truncated before it, as Redis does, so writes are appended after
+    /// the last complete command.
+    async fn load_aof(
+        store: &Store,
+        file: File,
+        path: &Path,
+        last: bool,
+        load_truncated: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        println!("Loading AOF file: {}", path.display());
+        let size = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut offset = 0;
+        if reader.fill_buf()?.starts_with(b"REDIS") {
+            println!("Reading RDB preamble from AOF file...");
+            let mut rdb_parser = RDBParser::new(reader);
+            rdb_parser.set_input_len(size);
+            Self::load_rdb(store, &mut rdb_parser, path).await?;
+            offset = rdb_parser.offset();
+            reader = rdb_parser.into_inner();
+        }
+
+        let replay = aof::replay(store, reader, offset)
+            .await
+            .inspect_err(|e| eprintln!("Error loading {}: {}", path.display(), e))?;
+        if let Some(offset) = replay.truncated_at {
+            eprintln!("!!! Warning: short read while loading the AOF file {}!!!", path.display());
+            if !last {
+                return Err(format!("The truncated file {} is not the last file of the AOF", path.display()).into());
+            }
+            if !load_truncated {
+                return Err(format!(
+                    "Unrecoverable error reading the append only file {}: the command at offset {} is incomplete",
+                    path.display(),
+                    offset,
+                ).into());
+            }
+            OpenOptions::new()
+                .write(true)
+                .open(path)
+                .and_then(|file| file.set_len(offset))
+                .map_err(|e| format!("Error truncating the AOF file {}: {}", path.display(), e))?;
+            println!(
+                "AOF {} loaded anyway because aof_load_truncated is enabled, truncated at offset {}",
+                path.display(),
+                offset,
+            );
+        }
+        println!("AOF file loaded successfully, replayed {} commands", replay.commands);
+        Ok(())
+    }
+
+    /// The configured `hz`, clamped to the range Redis accepts
+    fn hz(&self) -> u32 {
+        clamp_hz(self.config.hz)
+    }
+
+    /// Spawns the periodic background task, the equivalent of Redis'
+    /// serverCron. It runs `hz` times per second, drives the active expire
+    /// cycle, starts automatic saves, and syncs and rewrites the AOF.
+    ///
+    /// Its settings are read again on every tick, so CONFIG SET applies
+    /// them right away.
+    fn spawn_cron(&self) {
+        let store = Arc::clone(&self.store);
+        let mut config = CronConfig {
+            hz: self.hz(),
+            save_points: self.config.save.clone(),
+            rewrite_percentage: self.config.auto_aof_rewrite_percentage,
+            rewrite_min_size: self.config.auto_aof_rewrite_min_size,
+            ping_period: self.config.repl_ping_replica_period,
+            repl_timeout: self.config.repl_timeout,
+        };
+
+        tokio::spawn(async move {
+            let mut hz = config.hz;
+            let mut interval = cron_interval(hz);
+            loop {
+                interval.tick().await;
+                config.refresh(&store).await;
+                if config.hz != hz {
+                    hz = config.hz;
+                    interval = cron_interval(hz);
+                }
+
+                store.active_expire_cycle(hz).await;
+                rdb::bgsave_if_due(&store, &config.save_points).await;
+                if let Some(aof) = store.aof() {
+                    aof.tick().await;
+                    aof.rewrite_if_due(&store, config.rewrite_percentage, config.rewrite_min_size).await;
+                }
+                let ping_period = Duration::from_secs(config.ping_period);
+                let repl_timeout = Duration::from_secs(config.repl_timeout);
+                store.replication().cron(&store, ping_period, repl_timeout).await;
+            }
+        });
+    }
+
+    /// Saves the dataset before shutting down, unless `shutdown_on_sigint`
+    /// says not to. Like Redis, refuses to shut down if the save fails, so
+    /// changes are not silently lost. Returns whether to shut down.
+    async fn save_on_shutdown(&self) -> bool {
+        let save = match self.config.shutdown_on_sigint {
+            ShutdownMode::Default => self.store.get_config("save").await
+                .and_then(|save| parse_save_points(&save))
+                .is_some_and(|points| !points.is_empty()),
+            ShutdownMode::Save => true,
+            ShutdownMode::NoSave => false,
+        };
+        if !save {
+            return true;
+        }
+
+        // A background save may be writing an older snapshot, let it finish
+        while self.store.save_state().in_progress() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        println!("Saving the final RDB snapshot before exiting.");
+        match rdb::save(&self.store).await {
+            Ok(()) => true,
+            Err(_) => {
+                eprintln!("Error trying to save the DB, can't exit.");
+                false
+            }
+        }
+    }
+
     pub async fn start(&self) -> Result<(), Box<dyn std::error::Error>> {
         // Initialize the database
-        Self::init_config(&self).await?;
-        Self::init_db(&self).await?;
+        Self::init_config(self).await?;
+        if self.config.appendonly {
+            Self::init_aof(self).await?;
+        } else {
+            Self::init_db(&self.store, &self.rdb_path()).await?;
+        }
+        self.store.save_state().mark_loaded(self.store.changes());
+        self.spawn_cron();
 
         loop {
             tokio::select! {
@@ -96,11 +370,159 @@ impl Server {
                 }
                 _ = signal::ctrl_c() => {
                     println!("Ctrl+C pressed, shutting down...");
-                    break;
+                    if let Some(aof) = self.store.aof() {
+                        if let Err(e) = aof.fsync().await {
+                            eprintln!("Error syncing the AOF file: {}", e);
+                        }
+                    }
+                    if self.save_on_shutdown().await {
+                        break;
+                    }
                 }
             }
         }
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+/// Clamps `hz` to the range Redis accepts
+fn clamp_hz(hz: u32) -> u32 {
+    hz.clamp(1, 500)
+}
+
+/// Ticks `hz` times per second
+fn cron_interval(hz: u32) -> tokio::time::Interval {
+    tokio::time::interval(Duration::from_micros(1_000_000 / hz as u64))
+}
+
+/// Settings of the cron, kept in sync with the live configuration
+struct CronConfig {
+    hz: u32,
+    save_points: Vec<SavePoint>,
+    rewrite_percentage: u64,
+    rewrite_min_size: u64,
+    /// Seconds between PINGs to replicas
+    ping_period: u64,
+    /// Seconds before a silent replica is dropped
+    repl_timeout: u64,
+}
+
+impl CronConfig {
+    /// Reads the settings from the configuration of `store`. Values that do
+    /// not parse leave the current setting in place.
+    async fn refresh(&mut self, store: &Store) {
+        if let Some(hz) = Self::read::<u32>(store, "hz").await {
+            self.hz = clamp_hz(hz);
+        }
+        if let Some(points) = store.get_config("save").await.and_then(|save| parse_save_points(&save)) {
+            self.save_points = points;
+        }
+        if let Some(percentage) = Self::read(store, "auto-aof-rewrite-percentage").await {
+            self.rewrite_percentage = percentage;
+        }
+        if let Some(min_size) = Self::read(store, "auto-aof-rewrite-min-size").await {
+            self.rewrite_min_size = min_size;
+        }
+        if let Some(period) = Self::read(store, "repl-ping-replica-period").await {
+            self.ping_period = period;
+        }
+        if let Some(timeout) = Self::read(store, "repl-timeout").await {
+            self.repl_timeout = timeout;
+        }
+    }
+
+    async fn read<T: std::str::FromStr>(store: &Store, name: &str) -> Option<T> {
+        store.get_config(name).await?.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const COMPLETE: &[u8] = b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n";
+    const PARTIAL: &[u8] = b"*3\r\n$3\r\nSET\r\n$1\r\nb";
+
+    /// Writes `contents` to a fresh file and loads it as an AOF file
+    async fn load(name: &str, contents: &[u8], last: bool, load_truncated: bool) -> (Store, Result<(), String>, Vec<u8>) {
+        let dir = std::env::temp_dir().join(format!("aof-load-test-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("appendonly.aof.1.incr.aof");
+        fs::write(&path, contents).unwrap();
+
+        let store = Store::new(1).await.unwrap();
+        let file = File::open(&path).unwrap();
+        let result = Server::load_aof(&store, file, &path, last, load_truncated).await.map_err(|e| e.to_string());
+        let after = fs::read(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        (store, result, after)
+    }
+
+    #[tokio::test]
+    async fn loads_a_truncated_aof_when_allowed() {
+        let (store, result, after) = load("yes", &[COMPLETE, PARTIAL].concat(), true, true).await;
+        result.unwrap();
+        assert_eq!(after, COMPLETE);
+        assert!(store.db(0).get(b"a").await.unwrap().is_some());
+        assert!(store.db(0).get(b"b").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn refuses_a_truncated_aof_otherwise() {
+        let contents = [COMPLETE, PARTIAL].concat();
+        let (_, result, after) = load("no", &contents, true, false).await;
+        let error = result.unwrap_err();
+        assert!(error.contains(&format!("the command at offset {} is incomplete", COMPLETE.len())), "{}", error);
+        assert_eq!(after, contents);
+
+        // Only the last file can have been cut short by a crash
+        let (_, result, after) = load("not-last", &contents, false, true).await;
+        assert!(result.unwrap_err().contains("is not the last file of the AOF"));
+        assert_eq!(after, contents);
+    }
+
+    #[tokio::test]
+    async fn refuses_a_corrupted_aof_either_way() {
+        let contents = [COMPLETE, b"?\r\n", COMPLETE].concat();
+        for load_truncated in [true, false] {
+            let (_, result, after) = load("corrupt", &contents, true, load_truncated).await;
+            let error = result.unwrap_err();
+            assert!(error.contains(&format!("at offset {}", COMPLETE.len())), "{}", error);
+            assert_eq!(after, contents);
+        }
+    }
+
+    #[tokio::test]
+    async fn cron_follows_config_set() {
+        let store = Store::new(1).await.unwrap();
+        let mut config = CronConfig {
+            hz: 10,
+            save_points: vec![SavePoint { seconds: 3600, changes: 1 }],
+            rewrite_percentage: 100,
+            rewrite_min_size: 64,
+            ping_period: 10,
+            repl_timeout: 60,
+        };
+
+        store.set_config("hz", "1000".to_string()).await;
+        store.set_config("save", "60 5 10 100".to_string()).await;
+        store.set_config("repl-timeout", "5".to_string()).await;
+        config.refresh(&store).await;
+        assert_eq!(config.hz, 500);
+        assert_eq!(config.save_points, [SavePoint { seconds: 60, changes: 5 }, SavePoint { seconds: 10, changes: 100 }]);
+        assert_eq!(config.repl_timeout, 5);
+        assert_eq!(config.rewrite_percentage, 100);
+
+        // Values that do not parse keep the current setting
+        store.set_config("hz", "fast".to_string()).await;
+        store.set_config("save", "60".to_string()).await;
+        config.refresh(&store).await;
+        assert_eq!(config.hz, 500);
+        assert_eq!(config.save_points.len(), 2);
+
+        store.set_config("save", "".to_string()).await;
+        config.refresh(&store).await;
+        assert!(config.save_points.is_empty());
+    }
+}
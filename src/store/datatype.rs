@@ -1,42 +1,156 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use crate::parser::RDBValue;
+use crate::parser::stream::Stream;
+
 #[derive(Debug, Clone)]
 pub enum DataType {
-    String(String),
+    /// Strings are binary safe, like in Redis
+    String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Set(HashSet<Vec<u8>>),
+    /// Members of a sorted set, with their scores
+    SortedSet(HashMap<Vec<u8>, f64>),
+    Hash(HashMap<Vec<u8>, Vec<u8>>),
+    /// Streams are only loaded and saved, kept as read from the RDB file
+    Stream(Box<Stream>),
+}
+
+impl DataType {
+    /// Name of the type as reported by the TYPE command
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            DataType::String(_) => "string",
+            DataType::List(_) => "list",
+            DataType::Set(_) => "set",
+            DataType::SortedSet(_) => "zset",
+            DataType::Hash(_) => "hash",
+            DataType::Stream(_) => "stream",
+        }
+    }
+
+    /// The encoding Redis would keep the value in, as DEBUG OBJECT reports
+    /// it. Values are not stored compactly here, so this applies Redis'
+    /// default size limits for compact encodings to the value.
+    pub fn encoding(&self) -> &'static str {
+        // Elements of compact collections are at most 64 bytes long, and
+        // lists are compact up to 8KB in total
+        let small = |s: &Vec<u8>| s.len() <= 64;
+        match self {
+            DataType::String(s) => {
+                if s.len() <= 20 && is_integer(s) {
+                    "int"
+                } else if s.len() <= 44 {
+                    "embstr"
+                } else {
+                    "raw"
+                }
+            }
+            DataType::List(list) if list.iter().map(|item| item.len()).sum::<usize>() <= 8192 => "listpack",
+            DataType::List(_) => "quicklist",
+            DataType::Set(set) if set.len() <= 512 && set.iter().all(|member| is_integer(member)) => "intset",
+            DataType::Set(set) if set.len() <= 128 && set.iter().all(small) => "listpack",
+            DataType::Set(_) => "hashtable",
+            DataType::SortedSet(zset) if zset.len() <= 128 && zset.keys().all(small) => "listpack",
+            DataType::SortedSet(_) => "skiplist",
+            DataType::Hash(hash) if hash.len() <= 128 && hash.iter().all(|(field, value)| small(field) && small(value)) => "listpack",
+            DataType::Hash(_) => "hashtable",
+            DataType::Stream(_) => "stream",
+        }
+    }
+
+    /// Whether the value is a collection with no elements. Redis never keeps
+    /// empty collections around, it deletes the key instead. Streams are the
+    /// exception, they keep their metadata and groups when emptied.
+    pub fn is_empty_collection(&self) -> bool {
+        match self {
+            DataType::String(_) | DataType::Stream(_) => false,
+            DataType::List(list) => list.is_empty(),
+            DataType::Set(set) => set.is_empty(),
+            DataType::SortedSet(zset) => zset.is_empty(),
+            DataType::Hash(hash) => hash.is_empty(),
+        }
+    }
+}
+
+impl From<Vec<u8>> for DataType {
+    fn from(bytes: Vec<u8>) -> Self {
+        DataType::String(bytes)
+    }
 }
 
 impl From<String> for DataType {
     fn from(s: String) -> Self {
-        DataType::String(s)
+        DataType::String(s.into_bytes())
     }
 }
 
 impl From<&str> for DataType {
     fn from(s: &str) -> Self {
-        DataType::String(s.to_string())
+        DataType::String(s.as_bytes().to_vec())
     }
 }
 
 impl From<i64> for DataType {
     fn from(i: i64) -> Self {
-        DataType::String(i.to_string())
+        DataType::String(i.to_string().into_bytes())
     }
 }
 
 impl From<f64> for DataType {
     fn from(f: f64) -> Self {
-        DataType::String(f.to_string())
+        DataType::String(f.to_string().into_bytes())
     }
 }
 
 impl From<bool> for DataType {
     fn from(b: bool) -> Self {
-        DataType::String(b.to_string())
+        DataType::String(b.to_string().into_bytes())
+    }
+}
+
+/// Converts a value loaded from an RDB file, keeping every element as the
+/// bytes it was saved as. Module values cannot be stored, the module type
+/// name is returned instead.
+impl TryFrom<RDBValue> for DataType {
+    type Error = String;
+
+    fn try_from(value: RDBValue) -> Result<Self, String> {
+        Ok(match value {
+            RDBValue::String(data) => DataType::String(data),
+            RDBValue::List(items) => DataType::List(items.into_iter().collect()),
+            RDBValue::Set(members) => DataType::Set(members.into_iter().collect()),
+            RDBValue::SortedSet(members) => DataType::SortedSet(members.into_iter().collect()),
+            RDBValue::Hash(fields) => DataType::Hash(fields.into_iter().collect()),
+            RDBValue::Stream(stream) => DataType::Stream(stream),
+            RDBValue::Module(name) => return Err(name),
+        })
     }
 }
 
-impl ToString for DataType {
-    fn to_string(&self) -> String {
+/// Strings display as their contents, collections as a list of their
+/// elements. Bytes that are not valid UTF-8 display lossily.
+impl fmt::Display for DataType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = |bytes: &Vec<u8>| String::from_utf8_lossy(bytes).into_owned();
         match self {
-            DataType::String(s) => s.clone(),
+            DataType::String(s) => write!(f, "{}", text(s)),
+            DataType::List(list) => write!(f, "{:?}", list.iter().map(text).collect::<Vec<_>>()),
+            DataType::Set(set) => write!(f, "{:?}", set.iter().map(text).collect::<Vec<_>>()),
+            DataType::SortedSet(zset) => {
+                write!(f, "{:?}", zset.iter().map(|(member, score)| (text(member), score)).collect::<Vec<_>>())
+            }
+            DataType::Hash(hash) => {
+                write!(f, "{:?}", hash.iter().map(|(field, value)| (text(field), text(value))).collect::<Vec<_>>())
+            }
+            DataType::Stream(stream) => write!(f, "{:?}", stream.entries),
         }
     }
-} 
\ No newline at end of file
+}
+
+/// Whether `bytes` are an integer in its canonical form, the way Redis
+/// decides to store a string or a set member as an integer
+fn is_integer(bytes: &[u8]) -> bool {
+    let integer = std::str::from_utf8(bytes).ok().and_then(|s| s.parse::<i64>().ok());
+    integer.is_some_and(|i| i.to_string().as_bytes() == bytes)
+}
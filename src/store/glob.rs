@@ -0,0 +1,206 @@
+//! Glob-style pattern matching for KEYS and SCAN MATCH
+//!
+//! This follows `stringmatchlen` from Redis' `util.c`, so patterns behave the
+//! same way they do against a real server:
+//!
+//! - `?` matches any single byte
+//! - `*` matches any sequence of bytes, including an empty one
+//! - `[abc]` matches one of the listed bytes, `[^abc]` any byte but those
+//! - `[a-z]` matches a range of bytes, in either order
+//! - `\` escapes the next byte, both inside and outside of brackets
+
+/// Maximum recursion depth for `*`, guarding against pathological patterns
+const MAX_NESTING: usize = 1000;
+
+/// Returns whether `string` matches the glob `pattern`
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let mut skip_longer = false;
+    match_bytes(pattern, string, &mut skip_longer, 0)
+}
+
+fn match_bytes(p: &[u8], s: &[u8], skip_longer: &mut bool, nesting: usize) -> bool {
+    if nesting > MAX_NESTING {
+        return false;
+    }
+
+    let (mut pi, mut si) = (0, 0);
+    while pi < p.len() && si < s.len() {
+        match p[pi] {
+            b'*' => {
+                while pi + 1 < p.len() && p[pi + 1] == b'*' {
+                    pi += 1;
+                }
+                if pi + 1 == p.len() {
+                    return true;
+                }
+                while si < s.len() {
+                    if match_bytes(&p[pi + 1..], &s[si..], skip_longer, nesting + 1) {
+                        return true;
+                    }
+                    // A nested `*` already failed against every suffix, so
+                    // trying longer prefixes here cannot succeed either
+                    if *skip_longer {
+                        return false;
+                    }
+                    si += 1;
+                }
+                *skip_longer = true;
+                return false;
+            }
+            b'?' => si += 1,
+            b'[' => {
+                pi += 1;
+                let negate = pi < p.len() && p[pi] == b'^';
+                if negate {
+                    pi += 1;
+                }
+
+                let mut matched = false;
+                loop {
+                    if pi >= p.len() {
+                        // Unterminated class: stop at the last pattern byte
+                        pi -= 1;
+                        break;
+                    }
+                    if p[pi] == b'\\' && p.len() - pi >= 2 {
+                        pi += 1;
+                        if p[pi] == s[si] {
+                            matched = true;
+                        }
+                    } else if p[pi] == b']' {
+                        break;
+                    } else if p.len() - pi >= 3 && p[pi + 1] == b'-' {
+                        let (start, end) = if p[pi] <= p[pi + 2] {
+                            (p[pi], p[pi + 2])
+                        } else {
+                            (p[pi + 2], p[pi])
+                        };
+                        if s[si] >= start && s[si] <= end {
+                            matched = true;
+                        }
+                        pi += 2;
+                    } else if p[pi] == s[si] {
+                        matched = true;
+                    }
+                    pi += 1;
+                }
+
+                if negate {
+                    matched = !matched;
+                }
+                if !matched {
+                    return false;
+                }
+                si += 1;
+            }
+            _ => {
+                if p[pi] == b'\\' && p.len() - pi >= 2 {
+                    pi += 1;
+                }
+                if p[pi] != s[si] {
+                    return false;
+                }
+                si += 1;
+            }
+        }
+
+        pi += 1;
+        if si == s.len() {
+            while pi < p.len() && p[pi] == b'*' {
+                pi += 1;
+            }
+            break;
+        }
+    }
+
+    pi == p.len() && si == s.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(cases: &[(&[u8], &[u8], bool)]) {
+        for &(pattern, string, expected) in cases {
+            assert_eq!(
+                glob_match(pattern, string),
+                expected,
+                "{:?} against {:?}",
+                String::from_utf8_lossy(pattern),
+                String::from_utf8_lossy(string),
+            );
+        }
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        check(&[
+            (b"", b"", true),
+            (b"", b"a", false),
+            // Like Redis, `*` alone does not match the empty string
+            (b"*", b"", false),
+            (b"*", b"abc", true),
+            (b"**", b"abc", true),
+            (b"a*", b"a", true),
+            (b"*a", b"ba", true),
+            (b"*a", b"ab", false),
+            (b"a*b*c", b"axxbyyc", true),
+            (b"a*b*c", b"axxbyy", false),
+            (b"?", b"", false),
+            (b"h?llo", b"hello", true),
+            (b"h?llo", b"hllo", false),
+            (b"\xff*", b"\xff\x00", true),
+        ]);
+    }
+
+    #[test]
+    fn matches_classes() {
+        check(&[
+            (b"h[ae]llo", b"hallo", true),
+            (b"h[ae]llo", b"hillo", false),
+            (b"h[^e]llo", b"hallo", true),
+            (b"h[^e]llo", b"hello", false),
+            (b"h[a-b]llo", b"hbllo", true),
+            (b"h[b-a]llo", b"hallo", true),
+            (b"[^a-z]", b"A", true),
+            (b"[^a-z]", b"q", false),
+            (b"[^a-z]", b"", false),
+            // An empty class matches nothing
+            (b"[]", b"a", false),
+            (b"[]", b"]", false),
+            (b"a[]", b"a", false),
+            (b"[^]", b"a", true),
+            // An unterminated class ends with the pattern
+            (b"[ab", b"a", true),
+            (b"[ab", b"c", false),
+            (b"x[a-", b"x-", true),
+        ]);
+    }
+
+    #[test]
+    fn matches_escapes() {
+        check(&[
+            (b"\\*", b"*", true),
+            (b"\\*", b"a", false),
+            (b"h\\?llo", b"h?llo", true),
+            (b"h\\?llo", b"hello", false),
+            (b"[\\]]", b"]", true),
+            (b"[\\^a]", b"^", true),
+            (b"[a\\-z]", b"-", true),
+            (b"[a\\-z]", b"b", false),
+            (b"\\[a]", b"[a]", true),
+            // A trailing backslash has nothing to escape and matches itself
+            (b"\\", b"\\", true),
+            (b"a\\", b"a\\", true),
+            (b"a\\", b"a", false),
+            (b"[a\\", b"\\", true),
+        ]);
+    }
+
+    #[test]
+    fn handles_pathological_patterns() {
+        let string = [b'a'; 64];
+        assert!(!glob_match(b"*a*a*a*a*a*a*a*a*a*a*a*a*b", &string));
+        assert!(glob_match(b"*a*a*a*a*a*a*a*a*a*a*a*a*a", &string));
+    }
+}
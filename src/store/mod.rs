@@ -1,2 +1,3 @@
 pub mod redis;
-pub mod datatype;
\ No newline at end of file
+pub mod datatype;
+pub mod glob;
@@ -1,69 +1,1146 @@
-use std::collections::HashMap;
-use std::time::{Instant, Duration};
-use tokio::sync::RwLock;
-use crate::error::Result;
+use std::cell::Cell;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::future::Future;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::time::{Instant, Duration, SystemTime};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use tokio::sync::{RwLock, RwLockWriteGuard};
+use crate::error::{RedisError, Result};
+use crate::persistence::aof::Aof;
+use crate::persistence::rdb::SaveState;
+use crate::replication::Replication;
 use super::datatype::DataType;
+use super::glob::glob_match;
 
+/// Largest string value a client can build with APPEND or SETRANGE (512MB),
+/// matching Redis' default `proto-max-bulk-len`.
+pub const MAX_STRING_LENGTH: usize = 512 * 1024 * 1024;
+
+// Active expire cycle tuning, matching the defaults of Redis' activeExpireCycle
+/// Keys with an expiry sampled per round
+const ACTIVE_EXPIRE_KEYS_PER_LOOP: usize = 20;
+/// Keep running rounds while more than this percentage of sampled keys had expired
+const ACTIVE_EXPIRE_ACCEPTABLE_STALE: usize = 10;
+/// Share of each `1/hz` period a cycle may spend, in percent
+const ACTIVE_EXPIRE_CYCLE_TIME_PERC: u64 = 25;
+
+tokio::task_local! {
+    /// Changes made by the command running on the current task, counted
+    /// while it runs under `Store::track_writes`
+    static TASK_CHANGES: Cell<u64>;
+}
+
+/// Counts `count` changes towards the next automatic save, and towards the
+/// command running on the current task
+fn count_changes(changes: &AtomicU64, count: u64) {
+    changes.fetch_add(count, Ordering::Relaxed);
+    let _ = TASK_CHANGES.try_with(|task| task.set(task.get() + count));
+}
+
+/// How a write should treat the time to live of the key it touches
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Expiry {
+    /// Expire at the given wall-clock time, in unix milliseconds
+    At(i64),
+    /// Keep whatever expiry the key already had (`KEEPTTL`)
+    Keep,
+}
+
+/// Condition a conditional write (`NX`/`XX`) must satisfy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetCondition {
+    /// Only write if the key does not exist
+    NotExists,
+    /// Only write if the key already exists
+    Exists,
+}
+
+/// Conditions the EXPIRE family checks against a key's current expiry.
+/// Keys without an expiry count as having an infinite one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExpireCondition {
+    /// Only set the expiry if the key has none (`NX`)
+    pub nx: bool,
+    /// Only set the expiry if the key already has one (`XX`)
+    pub xx: bool,
+    /// Only set the expiry if it is later than the current one (`GT`)
+    pub gt: bool,
+    /// Only set the expiry if it is earlier than the current one (`LT`)
+    pub lt: bool,
+}
+
+/// Options for `Database::set_with`, mirroring the flags of the SET command
+#[derive(Debug, Clone, Default)]
+pub struct SetOptions {
+    pub condition: Option<SetCondition>,
+    /// Return the previous value of the key
+    pub get: bool,
+    /// `None` clears any existing expiry, like a plain SET
+    pub expiry: Option<Expiry>,
+}
+
+/// Counters describing how keys are being expired, reported by INFO
+#[derive(Debug, Clone, Default)]
+pub struct ExpireStats {
+    /// Keys removed because their expiry passed, on access or by the cycle
+    pub expired_keys: u64,
+    /// Running estimate of the share (0.0 to 1.0) of keys with an expiry
+    /// that are already logically expired
+    pub stale_perc: f64,
+    /// Cycles that stopped because they used up their time budget
+    pub time_cap_reached_count: u64,
+    /// Total time spent in the active expire cycle
+    pub cycle_time: Duration,
+}
+
+#[derive(Clone)]
 pub struct Entry {
     value: DataType,
-    expiry: Option<Instant>,
+    /// Absolute wall-clock deadline in unix milliseconds, the same model RDB
+    /// files use, so expiries survive being saved and loaded
+    expiry: Option<i64>,
 }
 
-pub struct Store {
-    data: RwLock<HashMap<String, Entry>>,
+impl Entry {
+    /// Whether the entry is still visible at unix time `now` (in milliseconds)
+    fn is_live(&self, now: i64) -> bool {
+        self.expiry.is_none_or(|expiry| now <= expiry)
+    }
 }
 
-impl Store {
-    pub async fn new() -> Result<Self> {
-        let data = RwLock::new(HashMap::new());
+/// Current wall-clock time in unix milliseconds
+pub fn unix_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
 
-        let store = Self { 
-            data,
-        };
+/// The keys of a database, plus the bookkeeping needed to expire them
+#[derive(Default)]
+struct Keyspace {
+    entries: HashMap<Arc<[u8]>, Entry>,
+    /// Every key of `entries` with its scan hash, in the order SCAN walks
+    /// them, so a page is a range query starting at the cursor. The keys
+    /// share their allocation with `entries`.
+    order: BTreeSet<(u64, Arc<[u8]>)>,
+    /// Keys that were given an expiry, sampled by the active expire cycle.
+    /// Deleting or persisting a key leaves it here; the cycle drops such
+    /// stale keys when it samples them.
+    volatile: Vec<Vec<u8>>,
+    /// The keys in `volatile`, so each one is tracked only once
+    tracked: HashSet<Vec<u8>>,
+    /// Keys removed because their expiry passed
+    expired: u64,
+    /// Keys removed because their expiry passed that replicas were not sent
+    /// a DEL for yet, drained by `Store::take_expired`
+    unpropagated: Vec<Vec<u8>>,
+    /// Set when any database has unpropagated keys, shared by all of them
+    propagate: Arc<AtomicBool>,
+}
 
-        Ok(store)
+impl Keyspace {
+    /// Returns the entry stored at `key`, removing it first if it has expired.
+    fn live_entry(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        let expired = self.entries.get(key)
+            .is_some_and(|entry| !entry.is_live(unix_millis()));
+
+        if expired {
+            self.expire(key);
+            return None;
+        }
+        self.entries.get_mut(key)
     }
 
-    pub async fn get(&self, key: &str) -> Result<Option<DataType>> {
-        let is_expired = {
-            let data = self.data.read().await;
-            if let Some(entry) = data.get(key) {
-                if let Some(expiry) = entry.expiry {
-                    Instant::now() > expiry
-                } else {
-                    false
+    fn insert(&mut self, key: Vec<u8>, entry: Entry) {
+        let volatile = entry.expiry.is_some();
+        if volatile {
+            self.track(&key);
+        }
+        let key: Arc<[u8]> = key.into();
+        if !self.entries.contains_key(&key) {
+            self.order.insert((Database::scan_hash(&key), Arc::clone(&key)));
+        }
+        self.entries.insert(key, entry);
+    }
+
+    /// Returns the entry stored at `key`, inserting `default()` first if
+    /// there is none
+    fn get_or_insert(&mut self, key: &[u8], default: impl FnOnce() -> Entry) -> &mut Entry {
+        if !self.entries.contains_key(key) {
+            self.insert(key.to_vec(), default());
+        }
+        self.entries.get_mut(key).expect("key was just inserted")
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        let (key, entry) = self.entries.remove_entry(key)?;
+        self.order.remove(&(Database::scan_hash(&key), key));
+        Some(entry)
+    }
+
+    /// Registers `key` with the active expire cycle. Must be called whenever
+    /// a key is given an expiry.
+    fn track(&mut self, key: &[u8]) {
+        if !self.tracked.contains(key) {
+            self.tracked.insert(key.to_vec());
+            self.volatile.push(key.to_vec());
+        }
+    }
+
+    /// Samples up to `count` keys with an expiry and removes those that have
+    /// expired. Returns how many keys were sampled and how many expired.
+    fn expire_sample(&mut self, count: usize) -> (usize, usize) {
+        let now = unix_millis();
+        let (mut sampled, mut expired) = (0, 0);
+
+        // Stale keys don't count as samples, so bound the work they can cause
+        let mut checks = 0;
+        while sampled < count && checks < count * 20 && !self.volatile.is_empty() {
+            checks += 1;
+            let index = random_index(self.volatile.len());
+            match self.entries.get(self.volatile[index].as_slice()).map(|entry| entry.expiry) {
+                Some(Some(deadline)) => {
+                    sampled += 1;
+                    if now > deadline {
+                        let key = self.untrack(index);
+                        self.expire(&key);
+                        expired += 1;
+                    }
+                }
+                // Deleted or persisted since it was tracked
+                _ => {
+                    self.untrack(index);
                 }
-            } else {
-                false
             }
+        }
+        (sampled, expired)
+    }
+
+    /// Removes `key` because its expiry passed, and queues it for a DEL to
+    /// replicas
+    fn expire(&mut self, key: &[u8]) {
+        self.remove(key);
+        self.expired += 1;
+        self.unpropagated.push(key.to_vec());
+        self.propagate.store(true, Ordering::Release);
+    }
+
+    fn untrack(&mut self, index: usize) -> Vec<u8> {
+        let key = self.volatile.swap_remove(index);
+        self.tracked.remove(&key);
+        key
+    }
+}
+
+/// A point-in-time copy of one database: every live key with its value and
+/// its expiry in unix milliseconds
+pub type DatabaseSnapshot = Vec<(Vec<u8>, DataType, Option<i64>)>;
+
+/// One of the numbered logical databases a client picks with SELECT
+pub struct Database {
+    data: RwLock<Keyspace>,
+    /// Changes made to any database, shared by all of them, which drive
+    /// automatic saves
+    changes: Arc<AtomicU64>,
+}
+
+impl Database {
+    fn new(changes: Arc<AtomicU64>, propagate: Arc<AtomicBool>) -> Self {
+        Self { data: RwLock::new(Keyspace { propagate, ..Keyspace::default() }), changes }
+    }
+
+    pub async fn get(&self, key: &[u8]) -> Result<Option<DataType>> {
+        let is_expired = {
+            let data = self.data.read().await;
+            data.entries.get(key).is_some_and(|entry| !entry.is_live(unix_millis()))
         };
 
         if is_expired {
             let mut data = self.data.write().await;
-            data.remove(key);
+            data.live_entry(key);
             return Ok(None);
         }
 
         let data = self.data.read().await;
-        Ok(data.get(key).map(|entry| entry.value.clone()))
+        Ok(data.entries.get(key).map(|entry| entry.value.clone()))
     }
 
-    pub async fn set(&self, key: &str, value: DataType) -> Result<()> {
+    pub async fn set(&self, key: &[u8], value: DataType) -> Result<()> {
         let mut data = self.data.write().await;
-        data.insert(key.to_string(), Entry { value, expiry: None });
+        data.insert(key.to_vec(), Entry { value, expiry: None });
+        self.add_changes(1);
         Ok(())
     }
 
-    pub async fn set_ex(&self, key: &str, value: DataType, expiry: Duration) -> Result<()> {
+    pub async fn set_ex(&self, key: &[u8], value: DataType, expiry: Duration) -> Result<()> {
+        let mut data = self.data.write().await;
+        let expiration = Self::deadline_in(expiry);
+        data.insert(key.to_vec(), Entry { value, expiry: Some(expiration) });
+        self.add_changes(1);
+        Ok(())
+    }
+
+    pub async fn delete(&self, key: &[u8]) -> Result<()> {
+        let mut data = self.data.write().await;
+        if data.remove(key).is_some() {
+            self.add_changes(1);
+        }
+        Ok(())
+    }
+
+    /// Removes every key in `keys` and returns how many existed
+    pub async fn del(&self, keys: &[Vec<u8>]) -> Result<usize> {
+        let mut data = self.data.write().await;
+        let mut removed = 0;
+        for key in keys {
+            if data.live_entry(key).is_some() {
+                data.remove(key);
+                removed += 1;
+            }
+        }
+        self.add_changes(removed);
+        Ok(removed)
+    }
+
+    /// Counts how many of `keys` exist. Keys given more than once are
+    /// counted every time, as in Redis.
+    pub async fn exists(&self, keys: &[Vec<u8>]) -> Result<usize> {
+        let mut data = self.data.write().await;
+        Ok(keys.iter()
+            .filter(|key| data.live_entry(key).is_some())
+            .count())
+    }
+
+    /// Returns the type name of the value at `key`, as reported by TYPE
+    pub async fn key_type(&self, key: &[u8]) -> Result<Option<&'static str>> {
+        let mut data = self.data.write().await;
+        Ok(data.live_entry(key).map(|entry| entry.value.type_name()))
+    }
+
+    /// Moves the value and expiry at `src` to `dst`.
+    ///
+    /// With `nx` set nothing happens if `dst` already exists. Returns whether
+    /// the key was renamed, or an error if `src` does not exist.
+    pub async fn rename(&self, src: &[u8], dst: &[u8], nx: bool) -> Result<bool> {
+        let mut data = self.data.write().await;
+        if data.live_entry(src).is_none() {
+            return Err(RedisError::Message("no such key".to_string()));
+        }
+        if src == dst {
+            return Ok(!nx);
+        }
+        if nx && data.live_entry(dst).is_some() {
+            return Ok(false);
+        }
+
+        if let Some(entry) = data.remove(src) {
+            data.insert(dst.to_vec(), entry);
+        }
+        self.add_changes(1);
+        Ok(true)
+    }
+
+    /// Copies the value and expiry at `src` to `dst`, overwriting `dst` only
+    /// when `replace` is set. Returns whether the copy happened.
+    pub async fn copy(&self, src: &[u8], dst: &[u8], replace: bool) -> Result<bool> {
+        let mut data = self.data.write().await;
+        let entry = match data.live_entry(src) {
+            Some(entry) => entry.clone(),
+            None => return Ok(false),
+        };
+        if !replace && data.live_entry(dst).is_some() {
+            return Ok(false);
+        }
+        data.insert(dst.to_vec(), entry);
+        self.add_changes(1);
+        Ok(true)
+    }
+
+    /// Returns a random key that has not expired, or `None` if there is none
+    pub async fn random_key(&self) -> Result<Option<Vec<u8>>> {
+        let data = self.data.read().await;
+        let now = unix_millis();
+        let live = data.entries.iter()
+            .filter(|(_, entry)| entry.is_live(now))
+            .count();
+        if live == 0 {
+            return Ok(None);
+        }
+
+        let index = random_index(live);
+        Ok(data.entries.iter()
+            .filter(|(_, entry)| entry.is_live(now))
+            .nth(index)
+            .map(|(key, _)| key.to_vec()))
+    }
+
+    /// Writes `value` at `key` honoring the SET flags in `options`.
+    ///
+    /// The condition check and the write happen under a single write lock, so
+    /// `NX` can be used for locking. Returns the previous value (only looked up
+    /// when `options.get` is set) and whether the write was performed.
+    pub async fn set_with(&self, key: &[u8], value: DataType, options: &SetOptions) -> Result<(Option<DataType>, bool)> {
         let mut data = self.data.write().await;
-        let expiration = Instant::now() + expiry;
-        data.insert(key.to_string(), Entry { value, expiry: Some(expiration) });
+        let existing = data.live_entry(key);
+
+        let old = match (&existing, options.get) {
+            (Some(entry), true) => match &entry.value {
+                DataType::String(_) => Some(entry.value.clone()),
+                _ => return Err(RedisError::WrongType),
+            },
+            _ => None,
+        };
+
+        let allowed = match options.condition {
+            Some(SetCondition::NotExists) => existing.is_none(),
+            Some(SetCondition::Exists) => existing.is_some(),
+            None => true,
+        };
+        if !allowed {
+            return Ok((old, false));
+        }
+
+        let expiry = Self::resolve_expiry(options.expiry, existing.and_then(|entry| entry.expiry));
+        data.insert(key.to_vec(), Entry { value, expiry });
+        self.add_changes(1);
+        Ok((old, true))
+    }
+
+    /// Sets every key/value pair in `pairs` under one write lock, so no reader
+    /// observes a partially applied batch. Any previous expiry is cleared.
+    pub async fn mset(&self, pairs: &[(Vec<u8>, DataType)]) -> Result<()> {
+        let mut data = self.data.write().await;
+        for (key, value) in pairs {
+            data.insert(key.clone(), Entry { value: value.clone(), expiry: None });
+        }
+        self.add_changes(pairs.len());
         Ok(())
     }
 
-    pub async fn delete(&self, key: &str) -> Result<()> {
+    /// Like `mset`, but writes nothing at all if any of the keys exists.
+    /// Returns whether the batch was written.
+    pub async fn msetnx(&self, pairs: &[(Vec<u8>, DataType)]) -> Result<bool> {
+        let mut data = self.data.write().await;
+        for (key, _) in pairs {
+            if data.live_entry(key).is_some() {
+                return Ok(false);
+            }
+        }
+        for (key, value) in pairs {
+            data.insert(key.clone(), Entry { value: value.clone(), expiry: None });
+        }
+        self.add_changes(pairs.len());
+        Ok(true)
+    }
+
+    /// Looks up several keys with a single read lock. Expired keys read as
+    /// missing; they are left for the next write access to remove.
+    pub async fn mget(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<DataType>>> {
+        let data = self.data.read().await;
+        let now = unix_millis();
+        Ok(keys.iter()
+            .map(|key| {
+                data.entries.get(key.as_slice())
+                    .filter(|entry| entry.is_live(now))
+                    .map(|entry| entry.value.clone())
+            })
+            .collect())
+    }
+
+    /// Removes `key` and returns the string it held
+    pub async fn getdel(&self, key: &[u8]) -> Result<Option<DataType>> {
+        let mut data = self.data.write().await;
+        match data.live_entry(key) {
+            Some(entry) => match &entry.value {
+                DataType::String(_) => {
+                    self.add_changes(1);
+                    Ok(data.remove(key).map(|entry| entry.value))
+                }
+                _ => Err(RedisError::WrongType),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the string stored at `key` and updates its expiry.
+    ///
+    /// `expiry` follows the same rules as `SetOptions::expiry`: `None` makes
+    /// the key persistent and `Expiry::Keep` leaves it untouched.
+    pub async fn getex(&self, key: &[u8], expiry: Option<Expiry>) -> Result<Option<DataType>> {
+        let mut data = self.data.write().await;
+        let (value, volatile) = match data.live_entry(key) {
+            Some(entry) => match &entry.value {
+                DataType::String(_) => {
+                    entry.expiry = Self::resolve_expiry(expiry, entry.expiry);
+                    (entry.value.clone(), entry.expiry.is_some())
+                }
+                _ => return Err(RedisError::WrongType),
+            },
+            None => return Ok(None),
+        };
+        if volatile {
+            data.track(key);
+        }
+        if expiry != Some(Expiry::Keep) {
+            self.add_changes(1);
+        }
+        Ok(Some(value))
+    }
+
+    /// Appends `value` to the string stored at `key`, creating it if needed.
+    ///
+    /// The existing expiry is kept. Returns the length of the string after
+    /// the append.
+    pub async fn append(&self, key: &[u8], value: &[u8]) -> Result<usize> {
+        let mut data = self.data.write().await;
+        match data.live_entry(key) {
+            Some(entry) => match &mut entry.value {
+                DataType::String(s) => {
+                    if s.len() + value.len() > MAX_STRING_LENGTH {
+                        return Err(Self::too_long());
+                    }
+                    s.extend_from_slice(value);
+                    self.add_changes(1);
+                    Ok(s.len())
+                }
+                _ => Err(RedisError::WrongType),
+            },
+            None => {
+                data.insert(key.to_vec(), Entry { value: DataType::String(value.to_vec()), expiry: None });
+                self.add_changes(1);
+                Ok(value.len())
+            }
+        }
+    }
+
+    /// Overwrites part of the string stored at `key`, starting at byte `offset`.
+    ///
+    /// Missing keys are treated as empty strings and the value is zero-padded
+    /// up to `offset` when it is too short. An empty `value` never creates the
+    /// key. Returns the length of the string after the write.
+    pub async fn setrange(&self, key: &[u8], offset: usize, value: &[u8]) -> Result<usize> {
+        let mut data = self.data.write().await;
+        let current = match data.live_entry(key) {
+            Some(entry) => match &entry.value {
+                DataType::String(s) => Some(s.len()),
+                _ => return Err(RedisError::WrongType),
+            },
+            None => None,
+        };
+
+        if value.is_empty() {
+            return Ok(current.unwrap_or(0));
+        }
+        if offset + value.len() > MAX_STRING_LENGTH {
+            return Err(Self::too_long());
+        }
+
+        let entry = data.get_or_insert(key, || Entry { value: DataType::String(Vec::new()), expiry: None });
+        match &mut entry.value {
+            DataType::String(s) => {
+                if s.len() < offset + value.len() {
+                    s.resize(offset + value.len(), 0);
+                }
+                s[offset..offset + value.len()].copy_from_slice(value);
+                self.add_changes(1);
+                Ok(s.len())
+            }
+            _ => Err(RedisError::WrongType),
+        }
+    }
+
+    /// Sets the expiry of `key` to `at` (unix milliseconds), subject to `condition`.
+    ///
+    /// A time that is not in the future deletes the key right away, as Redis
+    /// does. Returns whether the key exists and the condition was met.
+    pub async fn expire(&self, key: &[u8], at: i64, condition: ExpireCondition) -> Result<bool> {
+        let mut data = self.data.write().await;
+        let entry = match data.live_entry(key) {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+
+        let allowed = match entry.expiry {
+            Some(current) => !condition.nx
+                && (!condition.gt || at > current)
+                && (!condition.lt || at < current),
+            None => !condition.xx && !condition.gt,
+        };
+        if !allowed {
+            return Ok(false);
+        }
+
+        if at <= unix_millis() {
+            data.remove(key);
+        } else {
+            entry.expiry = Some(at);
+            data.track(key);
+        }
+        self.add_changes(1);
+        Ok(true)
+    }
+
+    /// Returns when `key` expires, in unix milliseconds.
+    ///
+    /// The outer `None` means the key does not exist, `Some(None)` that it
+    /// exists but never expires.
+    pub async fn expiry(&self, key: &[u8]) -> Result<Option<Option<i64>>> {
+        let mut data = self.data.write().await;
+        Ok(data.live_entry(key).map(|entry| entry.expiry))
+    }
+
+    /// Removes the expiry of `key`. Returns whether there was one to remove.
+    pub async fn persist(&self, key: &[u8]) -> Result<bool> {
+        let mut data = self.data.write().await;
+        let removed = data.live_entry(key)
+            .and_then(|entry| entry.expiry.take())
+            .is_some();
+        if removed {
+            self.add_changes(1);
+        }
+        Ok(removed)
+    }
+
+    /// Returns the value at `key` with its expiry in unix milliseconds, which
+    /// is what DUMP and MIGRATE serialize
+    pub async fn get_with_expiry(&self, key: &[u8]) -> Result<Option<(DataType, Option<i64>)>> {
+        let mut data = self.data.write().await;
+        Ok(data.live_entry(key).map(|entry| (entry.value.clone(), entry.expiry)))
+    }
+
+    /// Stores a value recreated by RESTORE at `key`, failing with `BusyKey`
+    /// if the key exists and `replace` is not set. A value whose expiry has
+    /// already passed is not stored, but still replaces the existing key.
+    pub async fn restore(&self, key: &[u8], value: DataType, expiry: Option<i64>, replace: bool) -> Result<()> {
         let mut data = self.data.write().await;
-        data.remove(key);
+        let exists = data.live_entry(key).is_some();
+        if exists && !replace {
+            return Err(RedisError::BusyKey);
+        }
+
+        if expiry.is_some_and(|at| at < unix_millis()) {
+            if exists {
+                data.remove(key);
+                self.add_changes(1);
+            }
+            return Ok(());
+        }
+        data.insert(key.to_vec(), Entry { value, expiry });
+        self.add_changes(1);
         Ok(())
     }
+
+    /// Number of keys in the database, including expired keys that were not
+    /// reclaimed yet, as DBSIZE reports it
+    pub async fn size(&self) -> usize {
+        self.data.read().await.entries.len()
+    }
+
+    /// Removes every key. With `lazy` set the old keys are freed on a
+    /// blocking thread, so flushing a large database does not stall the
+    /// caller (`ASYNC`).
+    pub async fn flush(&self, lazy: bool) {
+        let old = {
+            let mut data = self.data.write().await;
+            let expired = data.expired;
+            let unpropagated = std::mem::take(&mut data.unpropagated);
+            let propagate = Arc::clone(&data.propagate);
+            self.add_changes(data.entries.len());
+            std::mem::replace(&mut *data, Keyspace { expired, unpropagated, propagate, ..Keyspace::default() })
+        };
+        if lazy {
+            tokio::task::spawn_blocking(move || drop(old));
+        }
+    }
+
+    /// Returns the number of keys, the number of keys with an expiry and
+    /// their average time to live in milliseconds, as INFO keyspace reports
+    pub async fn keyspace_info(&self) -> (usize, usize, i64) {
+        let data = self.data.read().await;
+        let now = unix_millis();
+        let ttls: Vec<i64> = data.entries.values()
+            .filter_map(|entry| entry.expiry)
+            .map(|at| (at - now).max(0))
+            .collect();
+        let avg_ttl = if ttls.is_empty() {
+            0
+        } else {
+            ttls.iter().sum::<i64>() / ttls.len() as i64
+        };
+        (data.entries.len(), ttls.len(), avg_ttl)
+    }
+
+    /// Returns every key that has not expired and matches the glob `pattern`
+    pub async fn keys(&self, pattern: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let data = self.data.read().await;
+        let now = unix_millis();
+        Ok(data.entries.iter()
+            .filter(|(_, entry)| entry.is_live(now))
+            .filter(|(key, _)| Self::matches(pattern, key))
+            .map(|(key, _)| key.to_vec())
+            .collect())
+    }
+
+    /// Returns the next page of an incremental scan of the keyspace.
+    ///
+    /// Keys are visited in the order of a hash that is fixed for the life of
+    /// the process, and the cursor is the hash to continue from. Unlike the
+    /// iteration order of the `HashMap`, that order does not change when the
+    /// map resizes, so every key present for the whole scan is returned at
+    /// least once. Keys are kept sorted by that hash, so a call only walks
+    /// the keys of its page, roughly `count` of them. `pattern` and
+    /// `type_name` filter those keys afterwards, as in Redis. A returned
+    /// cursor of 0 means the scan is complete.
+    pub async fn scan(
+        &self,
+        cursor: u64,
+        count: usize,
+        pattern: Option<&[u8]>,
+        type_name: Option<&str>,
+    ) -> Result<(u64, Vec<Vec<u8>>)> {
+        let data = self.data.read().await;
+
+        // The page ends after `count` keys, but keys with colliding hashes
+        // are never split across pages. The next cursor is the hash of the
+        // first key left out, which is never 0 as it follows another key.
+        let mut page = Vec::with_capacity(count);
+        let mut next = 0;
+        for (hash, key) in data.order.range((cursor, Arc::from(&[][..]))..) {
+            if page.len() >= count && page.last().is_some_and(|&(last, _)| last != *hash) {
+                next = *hash;
+                break;
+            }
+            page.push((*hash, key));
+        }
+
+        let now = unix_millis();
+        let keys = page.into_iter()
+            .filter_map(|(_, key)| Some((key, data.entries.get(key)?)))
+            .filter(|(_, entry)| entry.is_live(now))
+            .filter(|(key, _)| pattern.is_none_or(|pattern| Self::matches(pattern, key)))
+            .filter(|(_, entry)| type_name.is_none_or(|name| entry.value.type_name().eq_ignore_ascii_case(name)))
+            .map(|(key, _)| key.to_vec())
+            .collect();
+        Ok((next, keys))
+    }
+
+    /// Counts `count` changes towards the next automatic save
+    fn add_changes(&self, count: usize) {
+        count_changes(&self.changes, count as u64);
+    }
+
+    fn scan_hash(key: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// `*` matches every key, including the empty one the glob matcher rejects
+    fn matches(pattern: &[u8], key: &[u8]) -> bool {
+        pattern == b"*" || glob_match(pattern, key)
+    }
+
+    /// Computes the deadline a write leaves on a key whose current deadline is `current`
+    fn resolve_expiry(expiry: Option<Expiry>, current: Option<i64>) -> Option<i64> {
+        match expiry {
+            None => None,
+            Some(Expiry::Keep) => current,
+            Some(Expiry::At(at)) => Some(at),
+        }
+    }
+
+    /// The unix time in milliseconds once `duration` has elapsed, saturating
+    /// absurdly large TTLs instead of overflowing
+    pub fn deadline_in(duration: Duration) -> i64 {
+        let millis = i64::try_from(duration.as_millis()).unwrap_or(i64::MAX);
+        unix_millis().saturating_add(millis)
+    }
+
+    fn too_long() -> RedisError {
+        RedisError::Message("string exceeds maximum allowed size (proto-max-bulk-len)".to_string())
+    }
+}
+
+pub struct Store {
+    dbs: Vec<Database>,
+    /// Server parameters exposed through CONFIG GET/SET, kept apart from the
+    /// keyspace so key commands never see them
+    config: RwLock<HashMap<String, String>>,
+    /// Counters of the active expire cycle
+    expire_stats: Mutex<ExpireStats>,
+    /// Database the next active expire cycle starts from
+    next_expire_db: AtomicUsize,
+    /// Whether the active expire cycle runs, turned off with DEBUG
+    /// SET-ACTIVE-EXPIRE 0 so tests can observe expired keys
+    active_expire: AtomicBool,
+    /// Total number of changes made to the dataset since startup
+    changes: Arc<AtomicU64>,
+    /// Whether any database removed expired keys not yet sent to replicas
+    propagate: Arc<AtomicBool>,
+    /// Progress and outcome of RDB saves, shared with background saves
+    save_state: Arc<SaveState>,
+    /// The append-only file writes are logged to, with `appendonly` on
+    aof: OnceLock<Aof>,
+    replication: Replication,
+}
+
+impl Store {
+    /// Creates a store with `databases` empty databases, at least one
+    pub async fn new(databases: usize) -> Result<Self> {
+        let changes = Arc::new(AtomicU64::new(0));
+        let propagate = Arc::new(AtomicBool::new(false));
+        let dbs = (0..databases.max(1))
+            .map(|_| Database::new(Arc::clone(&changes), Arc::clone(&propagate)))
+            .collect();
+        let config = RwLock::new(HashMap::new());
+
+        let store = Self { 
+            dbs,
+            config,
+            expire_stats: Mutex::new(ExpireStats::default()),
+            next_expire_db: AtomicUsize::new(0),
+            active_expire: AtomicBool::new(true),
+            changes,
+            propagate,
+            save_state: Arc::new(SaveState::default()),
+            aof: OnceLock::new(),
+            replication: Replication::default(),
+        };
+
+        Ok(store)
+    }
+
+    /// Returns database `index`, which must be below `databases()`
+    pub fn db(&self, index: usize) -> &Database {
+        &self.dbs[index]
+    }
+
+    /// Number of databases
+    pub fn databases(&self) -> usize {
+        self.dbs.len()
+    }
+
+    pub fn save_state(&self) -> &Arc<SaveState> {
+        &self.save_state
+    }
+
+    /// Starts logging writes to `aof`. Only the first call has an effect.
+    pub fn set_aof(&self, aof: Aof) {
+        let _ = self.aof.set(aof);
+    }
+
+    /// The append-only file, if writes are being logged
+    pub fn aof(&self) -> Option<&Aof> {
+        self.aof.get()
+    }
+
+    pub fn replication(&self) -> &Replication {
+        &self.replication
+    }
+
+    /// Turns the active expire cycle on or off. Expired keys are still
+    /// removed when accessed.
+    pub fn set_active_expire(&self, enabled: bool) {
+        self.active_expire.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Total number of changes made to the dataset since startup. Every
+    /// key written or removed counts as one change.
+    pub fn changes(&self) -> u64 {
+        self.changes.load(Ordering::Relaxed)
+    }
+
+    /// Runs `future`, which executes a command, and returns its output with
+    /// whether it changed the dataset. Only changes made by the current
+    /// task count, not those of other clients running at the same time.
+    /// Calls can be nested, changes count towards every enclosing call.
+    pub async fn track_writes<F: Future>(future: F) -> (F::Output, bool) {
+        let (output, count) = TASK_CHANGES.scope(Cell::new(0), async {
+            let output = future.await;
+            (output, TASK_CHANGES.with(Cell::get))
+        }).await;
+        let _ = TASK_CHANGES.try_with(|task| task.set(task.get() + count));
+        (output, count > 0)
+    }
+
+    /// Whether keys were removed because their expiry passed since the last
+    /// `take_expired`
+    pub fn has_expired(&self) -> bool {
+        self.propagate.load(Ordering::Acquire)
+    }
+
+    /// Takes the keys removed because their expiry passed since the last
+    /// call, by database, so they can be deleted on replicas as well
+    pub async fn take_expired(&self) -> Vec<(usize, Vec<Vec<u8>>)> {
+        if !self.propagate.swap(false, Ordering::AcqRel) {
+            return Vec::new();
+        }
+        let mut expired = Vec::new();
+        for (index, db) in self.dbs.iter().enumerate() {
+            let mut data = db.data.write().await;
+            if !data.unpropagated.is_empty() {
+                expired.push((index, std::mem::take(&mut data.unpropagated)));
+            }
+        }
+        expired
+    }
+
+    /// Number of changes since the last successful save
+    pub fn dirty(&self) -> u64 {
+        self.changes().saturating_sub(self.save_state.status().changes_saved)
+    }
+
+    /// Copies every live key of every database, indexed by database.
+    ///
+    /// All databases are locked before any is copied, so the snapshot is
+    /// consistent across databases too.
+    pub async fn snapshot(&self) -> Vec<DatabaseSnapshot> {
+        let mut guards = Vec::with_capacity(self.dbs.len());
+        for db in &self.dbs {
+            guards.push(db.data.read().await);
+        }
+
+        let now = unix_millis();
+        guards.iter()
+            .map(|data| {
+                data.entries.iter()
+                    .filter(|(_, entry)| entry.is_live(now))
+                    .map(|(key, entry)| (key.to_vec(), entry.value.clone(), entry.expiry))
+                    .collect()
+            })
+            .collect()
+    }
+
+    pub async fn get_config(&self, name: &str) -> Option<String> {
+        let config = self.config.read().await;
+        config.get(name).cloned()
+    }
+
+    pub async fn set_config(&self, name: &str, value: String) {
+        let mut config = self.config.write().await;
+        config.insert(name.to_string(), value);
+    }
+
+    /// Moves `key` from database `src` to database `dst`, keeping its expiry.
+    ///
+    /// Returns whether the key was moved: nothing happens if it does not
+    /// exist in `src` or already exists in `dst`.
+    pub async fn move_key(&self, key: &[u8], src: usize, dst: usize) -> Result<bool> {
+        let (mut from, mut to) = self.write_pair(src, dst).await;
+        if from.live_entry(key).is_none() || to.live_entry(key).is_some() {
+            return Ok(false);
+        }
+        if let Some(entry) = from.remove(key) {
+            to.insert(key.to_vec(), entry);
+        }
+        count_changes(&self.changes, 1);
+        Ok(true)
+    }
+
+    /// Copies the value and expiry at `src` in database `src_db` to `dst` in
+    /// database `dst_db`, overwriting `dst` only when `replace` is set.
+    /// Returns whether the copy happened.
+    pub async fn copy(&self, src: &[u8], src_db: usize, dst: &[u8], dst_db: usize, replace: bool) -> Result<bool> {
+        if src_db == dst_db {
+            return self.dbs[src_db].copy(src, dst, replace).await;
+        }
+
+        let (mut from, mut to) = self.write_pair(src_db, dst_db).await;
+        let entry = match from.live_entry(src) {
+            Some(entry) => entry.clone(),
+            None => return Ok(false),
+        };
+        if !replace && to.live_entry(dst).is_some() {
+            return Ok(false);
+        }
+        to.insert(dst.to_vec(), entry);
+        count_changes(&self.changes, 1);
+        Ok(true)
+    }
+
+    /// Swaps the contents of databases `a` and `b`. Clients that selected
+    /// one of them see the other's keys right away.
+    pub async fn swap_db(&self, a: usize, b: usize) {
+        if a == b {
+            return;
+        }
+        let (mut first, mut second) = self.write_pair(a, b).await;
+        std::mem::swap(&mut *first, &mut *second);
+        // Unpropagated keys were removed from the database with that number,
+        // they do not move with the data
+        std::mem::swap(&mut first.unpropagated, &mut second.unpropagated);
+        count_changes(&self.changes, 1);
+    }
+
+    /// Removes every key from every database
+    pub async fn flush_all(&self, lazy: bool) {
+        for db in &self.dbs {
+            db.flush(lazy).await;
+        }
+    }
+
+    /// Locks two different databases for writing. The locks are always taken
+    /// in index order, so two callers locking the same pair cannot deadlock.
+    async fn write_pair(&self, a: usize, b: usize) -> (RwLockWriteGuard<'_, Keyspace>, RwLockWriteGuard<'_, Keyspace>) {
+        if a < b {
+            let first = self.dbs[a].data.write().await;
+            let second = self.dbs[b].data.write().await;
+            (first, second)
+        } else {
+            let second = self.dbs[b].data.write().await;
+            let first = self.dbs[a].data.write().await;
+            (first, second)
+        }
+    }
+
+    /// Runs one active expire cycle, modelled on Redis' activeExpireCycle.
+    ///
+    /// Keys with an expiry are sampled in rounds of 20, and expired ones are
+    /// removed. Another round follows while more than 10% of a round's sample
+    /// had expired, so the work adapts to how many keys are waiting to be
+    /// reclaimed. The cycle stops once it has used 25% of the `1/hz` period
+    /// between two cycles. The write lock is released between rounds.
+    ///
+    /// Databases are visited in turn, and a cycle that runs out of time
+    /// leaves the rest for the next one, which picks up where it stopped.
+    pub async fn active_expire_cycle(&self, hz: u32) {
+        if !self.active_expire.load(Ordering::Relaxed) {
+            return;
+        }
+        let start = Instant::now();
+        let time_limit = Duration::from_micros(1_000_000 * ACTIVE_EXPIRE_CYCLE_TIME_PERC / hz.max(1) as u64 / 100);
+        let (mut total_sampled, mut total_expired) = (0, 0);
+        let mut time_cap_reached = false;
+
+        let first = self.next_expire_db.load(Ordering::Relaxed);
+        'dbs: for i in 0..self.dbs.len() {
+            let index = (first + i) % self.dbs.len();
+            self.next_expire_db.store((index + 1) % self.dbs.len(), Ordering::Relaxed);
+
+            loop {
+                let (sampled, expired) = {
+                    let mut data = self.dbs[index].data.write().await;
+                    data.expire_sample(ACTIVE_EXPIRE_KEYS_PER_LOOP)
+                };
+                total_sampled += sampled;
+                total_expired += expired;
+
+                if start.elapsed() > time_limit {
+                    time_cap_reached = true;
+                    break 'dbs;
+                }
+                if sampled == 0 || expired * 100 / sampled <= ACTIVE_EXPIRE_ACCEPTABLE_STALE {
+                    break;
+                }
+            }
+        }
+
+        let mut stats = self.expire_stats.lock().unwrap();
+        let current_perc = if total_sampled > 0 {
+            total_expired as f64 / total_sampled as f64
+        } else {
+            0.0
+        };
+        // Smooth the estimate over cycles, like Redis does
+        stats.stale_perc = current_perc * 0.05 + stats.stale_perc * 0.95;
+        if time_cap_reached {
+            stats.time_cap_reached_count += 1;
+        }
+        stats.cycle_time += start.elapsed();
+    }
+
+    pub async fn expire_stats(&self) -> ExpireStats {
+        let mut expired_keys = 0;
+        for db in &self.dbs {
+            expired_keys += db.data.read().await.expired;
+        }
+        let mut stats = self.expire_stats.lock().unwrap().clone();
+        stats.expired_keys = expired_keys;
+        stats
+    }
+}
+
+/// Picks a random index below `len`, which must not be 0
+fn random_index(len: usize) -> usize {
+    RandomState::new().build_hasher().finish() as usize % len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Scans `db` to the end, checking the cursor returns to 0 and no key
+    /// is returned twice
+    async fn scan_all(db: &Database, count: usize, pattern: Option<&[u8]>) -> HashSet<Vec<u8>> {
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        for _ in 0..10_000 {
+            let (next, keys) = db.scan(cursor, count, pattern, None).await.unwrap();
+            for key in keys {
+                assert!(seen.insert(key.clone()), "{:?} returned twice", key);
+            }
+            if next == 0 {
+                return seen;
+            }
+            assert!(next > cursor, "cursor went from {} back to {}", cursor, next);
+            cursor = next;
+        }
+        panic!("scan did not finish");
+    }
+
+    #[tokio::test]
+    async fn scan_returns_every_key_once() {
+        let store = Store::new(1).await.unwrap();
+        let db = store.db(0);
+        let keys: HashSet<Vec<u8>> = (0..1000).map(|i| format!("key:{}", i).into_bytes()).collect();
+        for key in &keys {
+            db.set(key, DataType::String(b"v".to_vec())).await.unwrap();
+        }
+        db.set(b"", DataType::String(b"v".to_vec())).await.unwrap();
+
+        for count in [1, 7, 10, 5000] {
+            let mut expected = keys.clone();
+            expected.insert(Vec::new());
+            assert_eq!(scan_all(db, count, None).await, expected);
+            assert_eq!(scan_all(db, count, Some(b"*")).await, expected);
+        }
+
+        let matching = scan_all(db, 10, Some(b"key:1?")).await;
+        let expected: HashSet<Vec<u8>> = (10..20).map(|i| format!("key:{}", i).into_bytes()).collect();
+        assert_eq!(matching, expected);
+    }
+
+    #[tokio::test]
+    async fn scan_keeps_its_place_while_keys_change() {
+        let store = Store::new(1).await.unwrap();
+        let db = store.db(0);
+        for i in 0..500 {
+            db.set(format!("key:{}", i).as_bytes(), DataType::String(b"v".to_vec())).await.unwrap();
+        }
+
+        // Keys present for the whole scan are returned exactly once, even as
+        // others are added and removed along the way
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        let mut round = 0;
+        loop {
+            let (next, keys) = db.scan(cursor, 10, None, None).await.unwrap();
+            for key in keys {
+                assert!(seen.insert(key.clone()), "{:?} returned twice", key);
+            }
+            db.set(format!("new:{}", round).as_bytes(), DataType::String(b"v".to_vec())).await.unwrap();
+            db.delete(format!("key:{}", 250 + round).as_bytes()).await.unwrap();
+            round += 1;
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        for i in (0..250).chain(250 + round..500) {
+            assert!(seen.contains(format!("key:{}", i).as_bytes()), "key:{} was missed", i);
+        }
+    }
 }